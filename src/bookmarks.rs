@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+use crate::repository::write_atomic;
+
+/// Named pointers to changesets, stored one per line as `<hex nodeid> <name>`.
+/// The active bookmark, if any, is stored separately and advances with commits.
+#[derive(Debug, Default, PartialEq)]
+pub struct Bookmarks {
+    marks: BTreeMap<String, [u8; 20]>,
+    pub active: Option<String>,
+}
+
+impl Bookmarks {
+    pub fn from_file<P: AsRef<Path>>(path: P, active_path: P) -> Result<Bookmarks> {
+        let mut bookmarks = Bookmarks::default();
+        if path.as_ref().exists() {
+            for line in fs::read_to_string(path)?.lines() {
+                let (hash, name) = line
+                    .split_once(' ')
+                    .ok_or_else(|| anyhow!("Malformed bookmark line: {}", line))?;
+                let mut nodeid = [0u8; 20];
+                hex::decode_to_slice(hash, &mut nodeid)?;
+                bookmarks.marks.insert(name.to_string(), nodeid);
+            }
+        }
+        if active_path.as_ref().exists() {
+            let active = fs::read_to_string(active_path)?.trim().to_string();
+            if bookmarks.marks.contains_key(&active) {
+                bookmarks.active = Some(active);
+            }
+        }
+        Ok(bookmarks)
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P, active_path: P) -> Result<()> {
+        let mut contents = String::new();
+        for (name, nodeid) in &self.marks {
+            contents.push_str(&format!("{} {}\n", hex::encode(nodeid), name));
+        }
+        write_atomic(path, contents)?;
+        match &self.active {
+            Some(active) => write_atomic(active_path, active)?,
+            None if active_path.as_ref().exists() => fs::remove_file(active_path)?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&[u8; 20]> {
+        self.marks.get(name)
+    }
+
+    pub fn set(&mut self, name: &str, nodeid: [u8; 20]) {
+        self.marks.insert(name.to_string(), nodeid);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<[u8; 20]> {
+        if self.active.as_deref() == Some(name) {
+            self.active = None;
+        }
+        self.marks.remove(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &[u8; 20])> {
+        self.marks.iter()
+    }
+
+    /// Names of the bookmarks that point at a changeset.
+    pub fn names_for(&self, nodeid: &[u8; 20]) -> Vec<&str> {
+        self.marks
+            .iter()
+            .filter(|(_name, mark)| *mark == nodeid)
+            .map(|(name, _mark)| name.as_str())
+            .collect()
+    }
+
+    /// Move the active bookmark from `parent` to `child` after a commit.
+    pub fn advance(&mut self, parent: &[u8; 20], child: [u8; 20]) {
+        if let Some(active) = &self.active {
+            if let Some(nodeid) = self.marks.get_mut(active) {
+                if nodeid == parent {
+                    *nodeid = child;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let path = base_dir.path().join("bookmarks");
+        let active_path = base_dir.path().join("bookmarks.current");
+        let mut bookmarks = Bookmarks::from_file(&path, &active_path)?;
+        assert_eq!(bookmarks, Bookmarks::default());
+
+        bookmarks.set("feature", [1u8; 20]);
+        bookmarks.set("other", [2u8; 20]);
+        bookmarks.active = Some("feature".to_string());
+        bookmarks.advance(&[1u8; 20], [3u8; 20]);
+        bookmarks.write_to_file(&path, &active_path)?;
+
+        let mut bookmarks = Bookmarks::from_file(&path, &active_path)?;
+        assert_eq!(bookmarks.get("feature"), Some(&[3u8; 20]));
+        assert_eq!(bookmarks.names_for(&[2u8; 20]), vec!["other"]);
+        assert_eq!(bookmarks.active.as_deref(), Some("feature"));
+        bookmarks.remove("feature");
+        assert_eq!(bookmarks.active, None);
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
+use std::collections::BTreeMap;
 use std::fmt;
 use std::path::PathBuf;
 
-use chrono::{DateTime, Utc};
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
@@ -10,7 +12,203 @@ pub struct Changeset {
     pub committer: String,
     pub message: String,
     pub time: Option<DateTime<Utc>>,
+    /// The committer's offset from UTC in seconds, positive west of UTC as
+    /// in Mercurial.
+    pub timezone: i32,
     pub changed_files: Vec<PathBuf>,
+    pub extras: BTreeMap<String, String>,
+}
+
+// The layout of changesets written after extras but before timezones were
+// recorded. These entries have no version header.
+#[derive(Deserialize)]
+struct ChangesetV1 {
+    manifest_nodeid: [u8; 20],
+    committer: String,
+    message: String,
+    time: Option<DateTime<Utc>>,
+    changed_files: Vec<PathBuf>,
+    extras: BTreeMap<String, String>,
+}
+
+// The layout of changesets written before branches and other extras were
+// recorded.
+#[derive(Deserialize)]
+struct ChangesetV0 {
+    manifest_nodeid: [u8; 20],
+    committer: String,
+    message: String,
+    time: Option<DateTime<Utc>>,
+    changed_files: Vec<PathBuf>,
+}
+
+impl From<ChangesetV0> for ChangesetV1 {
+    fn from(old: ChangesetV0) -> ChangesetV1 {
+        ChangesetV1 {
+            manifest_nodeid: old.manifest_nodeid,
+            committer: old.committer,
+            message: old.message,
+            time: old.time,
+            changed_files: old.changed_files,
+            extras: BTreeMap::new(),
+        }
+    }
+}
+
+// Prefix of versioned changelog entries, followed by a version byte. Old
+// entries start with a manifest hash, which is vanishingly unlikely to
+// begin with these bytes.
+const VERSION_MAGIC: &[u8] = b"\0hg-rs\0";
+const VERSION: u8 = 2;
+
+pub const DEFAULT_BRANCH: &str = "default";
+
+impl Changeset {
+    /// Serialize for the changelog, with a version header.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes = VERSION_MAGIC.to_vec();
+        bytes.push(VERSION);
+        bincode::serialize_into(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    /// Deserialize a changelog entry in the current or an older format.
+    pub fn decode(bytes: &[u8]) -> Result<Changeset> {
+        match bytes.strip_prefix(VERSION_MAGIC) {
+            Some([VERSION, rest @ ..]) => Ok(bincode::deserialize(rest)?),
+            Some(_) => anyhow::bail!("Unsupported changeset version."),
+            None => {
+                let old = match bincode::deserialize::<ChangesetV1>(bytes) {
+                    Ok(old) => old,
+                    Err(_) => bincode::deserialize::<ChangesetV0>(bytes)?.into(),
+                };
+                Ok(Changeset {
+                    manifest_nodeid: old.manifest_nodeid,
+                    committer: old.committer,
+                    message: old.message,
+                    time: old.time,
+                    timezone: 0,
+                    changed_files: old.changed_files,
+                    extras: old.extras,
+                })
+            }
+        }
+    }
+
+    /// Serialize as a Mercurial changelog entry:
+    ///
+    /// ```text
+    /// <manifest hex>
+    /// <user>
+    /// <seconds> <timezone>[ <extras>]
+    /// <changed file>...
+    ///
+    /// <description>
+    /// ```
+    ///
+    /// Extras are `key:value` pairs separated by NUL bytes, sorted by key.
+    pub fn to_text(&self) -> Vec<u8> {
+        let mut date = format!(
+            "{} {}",
+            self.time.map_or(0, |time| time.timestamp()),
+            self.timezone
+        );
+        let extras: Vec<String> = self
+            .extras
+            .iter()
+            .map(|(key, value)| escape_extra(&format!("{}:{}", key, value)))
+            .collect();
+        if !extras.is_empty() {
+            date.push(' ');
+            date.push_str(&extras.join("\0"));
+        }
+        let mut files: Vec<String> = self
+            .changed_files
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        files.sort();
+        // Mercurial strips trailing whitespace from each line and trailing
+        // newlines from the description.
+        let description: Vec<&str> = self.message.lines().map(str::trim_end).collect();
+        let mut lines = vec![
+            hex::encode(self.manifest_nodeid),
+            self.committer.trim().to_string(),
+            date,
+        ];
+        lines.extend(files);
+        lines.push(String::new());
+        lines.push(description.join("\n").trim_matches('\n').to_string());
+        lines.join("\n").into_bytes()
+    }
+
+    /// Parse a Mercurial changelog entry written by `to_text`.
+    pub fn from_text(bytes: &[u8]) -> Result<Changeset> {
+        let text = std::str::from_utf8(bytes).context("Changelog entry is not UTF-8.")?;
+        let mut lines = text.splitn(4, '\n');
+        let mut next = |what: &str| {
+            lines
+                .next()
+                .with_context(|| format!("Changelog entry has no {}.", what))
+        };
+        let (manifest, committer, date) = (next("manifest")?, next("user")?, next("date")?);
+        // Changed files follow, then a blank line and the description.
+        let rest = lines.next().unwrap_or_default();
+        let (files, message) = match rest.strip_prefix('\n') {
+            Some(message) => ("", message),
+            None => rest.split_once("\n\n").unwrap_or((rest, "")),
+        };
+        let mut manifest_nodeid = [0u8; 20];
+        hex::decode_to_slice(manifest, &mut manifest_nodeid)
+            .context("Invalid manifest hash in changelog entry.")?;
+        let mut date = date.splitn(3, ' ');
+        let seconds: f64 = date.next().unwrap_or_default().parse()?;
+        let timezone: i32 = date
+            .next()
+            .context("Changelog entry has no timezone.")?
+            .parse()?;
+        let mut extras = BTreeMap::new();
+        for extra in date.next().unwrap_or_default().split('\0') {
+            if let Some((key, value)) = unescape_extra(extra).split_once(':') {
+                extras.insert(key.to_string(), value.to_string());
+            }
+        }
+        Ok(Changeset {
+            manifest_nodeid,
+            committer: committer.to_string(),
+            message: message.to_string(),
+            time: Some(Utc.timestamp(seconds as i64, 0)),
+            timezone,
+            changed_files: files.lines().map(PathBuf::from).collect(),
+            extras,
+        })
+    }
+
+    /// The commit time in the committer's timezone.
+    pub fn date(&self) -> Option<DateTime<FixedOffset>> {
+        let offset = FixedOffset::west_opt(self.timezone).or_else(|| FixedOffset::east_opt(0))?;
+        self.time.map(|time| time.with_timezone(&offset))
+    }
+
+    pub fn branch(&self) -> &str {
+        self.extras
+            .get("branch")
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_BRANCH)
+    }
+
+    pub fn set_branch(&mut self, branch: &str) {
+        if branch == DEFAULT_BRANCH {
+            self.extras.remove("branch");
+        } else {
+            self.extras.insert("branch".to_string(), branch.to_string());
+        }
+    }
+
+    // Whether this changeset closes its branch.
+    pub fn closes_branch(&self) -> bool {
+        self.extras.contains_key("close")
+    }
 }
 
 impl fmt::Display for Changeset {
@@ -18,7 +216,10 @@ impl fmt::Display for Changeset {
         writeln!(f, "{}", hex::encode(self.manifest_nodeid))?;
         writeln!(f, "{}", self.committer)?;
         if let Some(time) = self.time {
-            writeln!(f, "{}", time)?;
+            writeln!(f, "{} {}", time.timestamp(), self.timezone)?;
+        }
+        for (key, value) in &self.extras {
+            writeln!(f, "{}={}", key, value)?;
         }
         for path in &self.changed_files {
             writeln!(f, "{}", path.display())?;
@@ -26,3 +227,130 @@ impl fmt::Display for Changeset {
         writeln!(f, "\n{}", self.message)
     }
 }
+
+fn escape_extra(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\0', "\\0")
+}
+
+fn unescape_extra(text: &str) -> String {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct OldChangeset {
+        manifest_nodeid: [u8; 20],
+        committer: String,
+        message: String,
+        time: Option<DateTime<Utc>>,
+        changed_files: Vec<PathBuf>,
+        extras: BTreeMap<String, String>,
+    }
+
+    // The layout of changesets written by the first versions of hg-rs.
+    #[derive(Serialize)]
+    struct BaselineChangeset {
+        manifest_nodeid: [u8; 20],
+        committer: String,
+        message: String,
+        time: Option<DateTime<Utc>>,
+        changed_files: Vec<PathBuf>,
+    }
+
+    #[test]
+    fn test_encode() -> Result<()> {
+        let mut changeset = Changeset {
+            manifest_nodeid: [7; 20],
+            committer: "jane".to_string(),
+            message: "Fix it".to_string(),
+            time: Some(Utc.timestamp(1_000_000_000, 0)),
+            timezone: -3600,
+            changed_files: vec!["a".into()],
+            ..Changeset::default()
+        };
+        changeset.set_branch("stable");
+        assert_eq!(Changeset::decode(&changeset.encode()?)?, changeset);
+        assert_eq!(
+            changeset.date().map(|date| date.to_rfc3339()),
+            Some("2001-09-09T02:46:40+01:00".to_string())
+        );
+
+        let old = OldChangeset {
+            manifest_nodeid: changeset.manifest_nodeid,
+            committer: changeset.committer.clone(),
+            message: changeset.message.clone(),
+            time: changeset.time,
+            changed_files: changeset.changed_files.clone(),
+            extras: changeset.extras.clone(),
+        };
+        changeset.timezone = 0;
+        assert_eq!(Changeset::decode(&bincode::serialize(&old)?)?, changeset);
+
+        let baseline = BaselineChangeset {
+            manifest_nodeid: old.manifest_nodeid,
+            committer: old.committer,
+            message: old.message,
+            time: old.time,
+            changed_files: old.changed_files,
+        };
+        changeset.extras.clear();
+        assert_eq!(
+            Changeset::decode(&bincode::serialize(&baseline)?)?,
+            changeset
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_text() -> Result<()> {
+        let mut changeset = Changeset {
+            manifest_nodeid: [0xab; 20],
+            committer: "Jane <jane@example.com>".to_string(),
+            message: "Fix it  \n\nDetails.\n\n".to_string(),
+            time: Some(Utc.timestamp(1_000_000_000, 0)),
+            timezone: -3600,
+            changed_files: vec!["b".into(), "a".into()],
+            ..Changeset::default()
+        };
+        changeset.set_branch("stable");
+        changeset
+            .extras
+            .insert("note".to_string(), "x\ny".to_string());
+        let text = changeset.to_text();
+        assert_eq!(
+            String::from_utf8_lossy(&text),
+            format!(
+                "{}\nJane <jane@example.com>\n1000000000 -3600 branch:stable\0note:x\\ny\n\
+                 a\nb\n\nFix it\n\nDetails.",
+                "ab".repeat(20)
+            )
+        );
+        let parsed = Changeset::from_text(&text)?;
+        assert_eq!(parsed.message, "Fix it\n\nDetails.");
+        assert_eq!(parsed.changed_files, vec![PathBuf::from("a"), "b".into()]);
+        assert_eq!(parsed.extras, changeset.extras);
+        assert_eq!(parsed.date(), changeset.date());
+        Ok(())
+    }
+}
@@ -1,76 +1,1063 @@
-use std::fs::{File, OpenOptions};
-use std::io::Read;
+use std::collections::hash_map;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use chrono::Utc;
+use serde::Serialize;
 
-use crate::changeset::Changeset;
-use crate::dirstate::{Entry, Status};
-use crate::manifest::Manifest;
-use crate::repository::Repository;
+use crate::changeset::{Changeset, DEFAULT_BRANCH};
+use crate::config::{user_config, Config};
+use crate::dateutil::{parse_date, DateRange, DEFAULT_DATE_FORMAT};
+use crate::diff::{matching_blocks, split_lines};
+use crate::dirstate::{lexists, Dirstate, Entry, Status};
+use crate::filemerge::{filemerge, merge_flags, Tool};
+use crate::formatter::Format;
+use crate::fsmonitor;
+use crate::graph::Graph;
+use crate::manifest::Flag;
+use crate::matcher::Patterns;
+use crate::mergestate::{FileState, MergeState};
+use crate::patch;
+use crate::record::{Record, NULLID_REVISION};
+use crate::repository::{
+    write_atomic, Repository, DEFAULT_REQUIREMENTS, DIRSTATE_V2_REQUIREMENT,
+    TREE_MANIFEST_REQUIREMENT,
+};
+use crate::revlog::RevLog;
+use crate::revset;
+use crate::status;
+use crate::tags::TAGS_FILE;
+use crate::template::{Keywords, Template, Value};
+use crate::worker;
+
+const NULL_HASH: [u8; 20] = [0u8; 20];
 
 // Initialize a repository.
+// Tree manifests are chosen with `format.treemanifest`.
 pub fn init() -> Result<()> {
     let repo = Repository::new(".");
-    repo.init()?;
+    let mut requirements = DEFAULT_REQUIREMENTS.to_vec();
+    let config = Config::layered(None)?;
+    if config.get_bool("format", "treemanifest")? {
+        requirements.push(TREE_MANIFEST_REQUIREMENT);
+    }
+    if config.get_bool("format", "use-dirstate-v2")? {
+        requirements.push(DIRSTATE_V2_REQUIREMENT);
+    }
+    repo.init(&requirements)?;
+    Ok(())
+}
+
+// Show configuration values, optionally only one section or one
+// `section.key`, or edit the user's config file.
+pub fn config(name: Option<&str>, edit: bool, debug: bool) -> Result<()> {
+    if edit {
+        let path = user_config().context("Failed to find the home directory.")?;
+        if !path.exists() {
+            write_atomic(
+                &path,
+                "# User configuration for hg-rs.\n\
+                 [ui]\n\
+                 # Name and email address used for commits, for example\n\
+                 # username = Jane Doe <jdoe@example.com>\n",
+            )?;
+        }
+        let editor = Config::layered(None)?.editor();
+        run_editor(&editor, &path)?;
+        // Report mistakes while the edit is fresh.
+        Config::from_file(&path)?;
+        return Ok(());
+    }
+    let repo = Repository::from_cwd().ok();
+    let config = match &repo {
+        Some(repo) => repo.config()?,
+        None => Config::layered(None)?,
+    };
+    let (section, key) = match name {
+        Some(name) => match name.split_once('.') {
+            Some((section, key)) => (Some(section), Some(key)),
+            None => (Some(name), None),
+        },
+        None => (None, None),
+    };
+    let sections: Vec<&str> = match section {
+        Some(section) => vec![section],
+        None => config.sections().collect(),
+    };
+    let mut found = false;
+    for section in sections {
+        for (item, value) in config.items(section) {
+            if key.is_some_and(|key| key != item) {
+                continue;
+            }
+            found = true;
+            if debug {
+                let source = config.source(section, item).unwrap_or_default();
+                print!("{}: ", source);
+            }
+            match key {
+                Some(_) if !debug => println!("{}", value),
+                _ => println!("{}.{}={}", section, item, value),
+            }
+        }
+    }
+    if name.is_some() && !found {
+        bail!("No config value for {}.", name.unwrap_or_default());
+    }
+    Ok(())
+}
+
+// Open `path` in the user's editor and wait for it to exit. The editor is run
+// through the shell so that it may include arguments.
+fn run_editor(editor: &str, path: &Path) -> Result<()> {
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(editor)
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to run editor {}.", editor))?;
+    if !status.success() {
+        bail!("Editor {} exited with {}.", editor, status);
+    }
     Ok(())
 }
 
-// TODO: Handle multiple heads after merges are implemented.
-pub fn heads() -> Result<()> {
+// Show the open heads of every branch, newest first.
+pub fn heads(template_spec: Option<&str>) -> Result<()> {
     let repo = Repository::from_cwd()?;
+    let template = load_template(&repo, template_spec)?;
+    let date_format = date_format(&repo)?;
+    let mut heads: Vec<(u32, Changeset)> = Vec::new();
+    for rev in repo.branch_heads()?.values().flatten() {
+        let changeset = repo.changeset(*rev)?;
+        if !changeset.closes_branch() {
+            heads.push((*rev, changeset));
+        }
+    }
+    heads.sort_by_key(|(rev, _changeset)| std::cmp::Reverse(*rev));
+    let bookmarks = repo.bookmarks()?;
+    let tags = repo.tags()?;
     let mut changelog = repo.changelog_revlog()?;
-    let size = changelog.size()?;
-    let hunk = changelog.get_last_hunk()?;
-    let changeset: Changeset = bincode::deserialize(&hunk)?;
-    println!("{}", size);
-    print!("{}", changeset);
+    let format = Format::from_template(template_spec);
+    let mut records = Vec::new();
+    for (rev, changeset) in &heads {
+        let record = changelog.get_record(*rev)?;
+        if format.is_some() {
+            records.push(changeset_record(
+                &mut changelog,
+                *rev,
+                &record,
+                changeset,
+                &bookmarks.names_for(&record.hash),
+                &tags.names_for(&record.hash),
+            )?);
+            continue;
+        }
+        print!(
+            "{}",
+            show_changeset(
+                &mut changelog,
+                *rev,
+                &record,
+                changeset,
+                &bookmarks.names_for(&record.hash),
+                &tags.names_for(&record.hash),
+                false,
+                template.as_ref(),
+                &date_format,
+            )?
+        );
+    }
+    if let Some(format) = format {
+        format.write(&records)?;
+    }
+    Ok(())
+}
+
+// Show the most recently added changeset.
+pub fn tip(template_spec: Option<&str>) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let template = load_template(&repo, template_spec)?;
+    let date_format = date_format(&repo)?;
+    let mut changelog = repo.changelog_revlog()?;
+    let rev = match changelog.size()?.checked_sub(1) {
+        Some(rev) => rev,
+        None => return Ok(()),
+    };
+    let record = changelog.get_record(rev)?;
+    let changeset = repo
+        .entry_format()?
+        .decode_changeset(&changelog.get_hunk(rev)?)?;
+    let bookmarks = repo.bookmarks()?;
+    let tags = repo.tags()?;
+    if let Some(format) = Format::from_template(template_spec) {
+        let record = changeset_record(
+            &mut changelog,
+            rev,
+            &record,
+            &changeset,
+            &bookmarks.names_for(&record.hash),
+            &tags.names_for(&record.hash),
+        )?;
+        return format.write(&[record]);
+    }
+    print!(
+        "{}",
+        show_changeset(
+            &mut changelog,
+            rev,
+            &record,
+            &changeset,
+            &bookmarks.names_for(&record.hash),
+            &tags.names_for(&record.hash),
+            false,
+            template.as_ref(),
+            &date_format,
+        )?
+    );
+    Ok(())
+}
+
+// Options that select and format the changesets shown by `log`.
+#[derive(Debug, Default)]
+pub struct LogOptions {
+    pub revsets: Vec<String>,
+    pub limit: Option<usize>,
+    pub users: Vec<String>,
+    pub keywords: Vec<String>,
+    pub date: Option<String>,
+    pub branches: Vec<String>,
+    pub no_merges: bool,
+    pub only_merges: bool,
+    pub patch: bool,
+    pub stat: bool,
+    pub verbose: bool,
+    pub graph: bool,
+    pub template: Option<String>,
+    pub files: Patterns,
+}
+
+// Show changesets, newest first, or those selected by the given revsets.
+pub fn log(options: &LogOptions) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let template = load_template(&repo, options.template.as_deref())?;
+    let date_format = date_format(&repo)?;
+    let entry_format = repo.entry_format()?;
+    let mut changelog = repo.changelog_revlog()?;
+    let bookmarks = repo.bookmarks()?;
+    let tags = repo.tags()?;
+    let mut revs = if options.revsets.is_empty() {
+        (0..changelog.size()?).rev().collect()
+    } else {
+        union_revsets(&repo, &options.revsets)?
+    };
+    if options.graph {
+        revs.sort_unstable_by_key(|rev| std::cmp::Reverse(*rev));
+    }
+    let date = options.date.as_deref().map(DateRange::parse).transpose()?;
+    let users: Vec<String> = options
+        .users
+        .iter()
+        .map(|user| user.to_lowercase())
+        .collect();
+    let keywords: Vec<String> = options
+        .keywords
+        .iter()
+        .map(|keyword| keyword.to_lowercase())
+        .collect();
+    let matcher = repo.matcher(&options.files)?;
+    let in_files = |path: &Path| matcher.matches(path);
+
+    let mut shown = Vec::new();
+    for rev in revs {
+        if options.limit.is_some_and(|limit| shown.len() >= limit) {
+            break;
+        }
+        let record = changelog.get_record(rev)?;
+        let is_merge = record.parent2_revision.is_some();
+        if (options.no_merges && is_merge) || (options.only_merges && !is_merge) {
+            continue;
+        }
+        let changeset = entry_format.decode_changeset(&changelog.get_hunk(rev)?)?;
+        // Changesets list the files they changed, including removed files,
+        // which get no filelog revision to link back to them.
+        if !matcher.always() && !changeset.changed_files.iter().any(|path| in_files(path)) {
+            continue;
+        }
+        let committer = changeset.committer.to_lowercase();
+        if !users.is_empty() && !users.iter().any(|user| committer.contains(user)) {
+            continue;
+        }
+        let message = changeset.message.to_lowercase();
+        let matches_keyword = |keyword: &String| {
+            message.contains(keyword)
+                || committer.contains(keyword)
+                || changeset
+                    .changed_files
+                    .iter()
+                    .any(|path| path.to_string_lossy().to_lowercase().contains(keyword))
+        };
+        if !keywords.is_empty() && !keywords.iter().any(matches_keyword) {
+            continue;
+        }
+        if let Some(date) = &date {
+            if !changeset.time.is_some_and(|time| date.contains(&time)) {
+                continue;
+            }
+        }
+        if !options.branches.is_empty()
+            && !options
+                .branches
+                .iter()
+                .any(|branch| branch == changeset.branch())
+        {
+            continue;
+        }
+
+        shown.push((rev, record, changeset));
+    }
+
+    let shown_revs: HashSet<u32> = shown
+        .iter()
+        .map(|(rev, _record, _changeset)| *rev)
+        .collect();
+    let working_parent = repo.dirstate()?.parent1_hash;
+    let mut graph = Graph::default();
+    let format = Format::from_template(options.template.as_deref());
+    let mut records = Vec::new();
+    for (rev, record, changeset) in &shown {
+        if format.is_some() {
+            records.push(changeset_record(
+                &mut changelog,
+                *rev,
+                record,
+                changeset,
+                &bookmarks.names_for(&record.hash),
+                &tags.names_for(&record.hash),
+            )?);
+            continue;
+        }
+        let mut text = show_changeset(
+            &mut changelog,
+            *rev,
+            record,
+            changeset,
+            &bookmarks.names_for(&record.hash),
+            &tags.names_for(&record.hash),
+            options.verbose,
+            template.as_ref(),
+            &date_format,
+        )?;
+        if options.patch || options.stat {
+            let parent = record.parent1_revision.unwrap_or(NULLID_REVISION);
+            let changes = patch::changes(
+                &repo,
+                &patch::revision_snapshot(&repo, parent)?,
+                &patch::revision_snapshot(&repo, *rev)?,
+                &in_files,
+            )?;
+            if options.stat {
+                text.push_str(&patch::format_stat(&patch::stats(&changes)));
+            }
+            if options.patch {
+                let header = format!(
+                    "diff -r {} -r {}",
+                    short_hash(&mut changelog, parent)?,
+                    short_hash(&mut changelog, *rev)?
+                );
+                text.push_str(&String::from_utf8_lossy(&patch::format_diff(
+                    &changes, &header,
+                )));
+            }
+            text.push('\n');
+        }
+        if options.graph {
+            let parents = graph_parents(&mut changelog, *rev, &shown_revs)?;
+            // `x` marks changesets that close their branch.
+            let symbol = if record.hash == working_parent {
+                '@'
+            } else if changeset.closes_branch() {
+                'x'
+            } else {
+                'o'
+            };
+            text = graph.render(*rev, &parents, symbol, &text);
+        }
+        print!("{}", text);
+    }
+    if let Some(format) = format {
+        format.write(&records)?;
+    }
+    Ok(())
+}
+
+// The nearest ancestors of `rev` among the shown revisions, so the graph
+// stays connected when revisions in between are filtered out.
+fn graph_parents(changelog: &mut RevLog, rev: u32, shown: &HashSet<u32>) -> Result<Vec<u32>> {
+    let record = changelog.get_record(rev)?;
+    let mut pending: VecDeque<u32> = record
+        .parent1_revision
+        .into_iter()
+        .chain(record.parent2_revision)
+        .collect();
+    let mut visited = HashSet::new();
+    let mut parents = Vec::new();
+    while let Some(parent) = pending.pop_front() {
+        if !visited.insert(parent) {
+            continue;
+        }
+        if shown.contains(&parent) {
+            parents.push(parent);
+        } else {
+            let record = changelog.get_record(parent)?;
+            pending.extend(
+                record
+                    .parent1_revision
+                    .into_iter()
+                    .chain(record.parent2_revision),
+            );
+        }
+    }
+    Ok(parents)
+}
+
+// The abbreviated hash of a changelog revision, as shown in diff headers.
+fn short_hash(changelog: &mut RevLog, rev: u32) -> Result<String> {
+    let hash = if rev == NULLID_REVISION {
+        NULL_HASH
+    } else {
+        changelog.get_record(rev)?.hash
+    };
+    Ok(hex::encode(&hash[..6]))
+}
+
+// Evaluate several revsets, keeping the first occurrence of each revision.
+fn union_revsets(repo: &Repository, revsets: &[String]) -> Result<Vec<u32>> {
+    let mut seen = BTreeSet::new();
+    let mut revs = Vec::new();
+    for spec in revsets {
+        for rev in revset::revs(repo, spec)? {
+            if rev != NULLID_REVISION && seen.insert(rev) {
+                revs.push(rev);
+            }
+        }
+    }
+    Ok(revs)
+}
+
+// Show differences between the working copy and its parent, between the
+// working copy and one revision, or between two revisions.
+pub fn diff(
+    revsets: &[String],
+    patterns: &Patterns,
+    stat: bool,
+    template: Option<&str>,
+) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let matcher = repo.matcher(patterns)?;
+    let dirstate = repo.dirstate()?;
+    let (old_rev, new_rev) = match revsets {
+        [] => (repo.changelog_rev(&dirstate.parent1_hash)?, None),
+        [spec] => {
+            let revs = revset::revs(&repo, spec)?;
+            match revs.as_slice() {
+                [] => bail!("Empty revision set {}.", spec),
+                [rev] => (*rev, None),
+                [first, .., last] => (*first, Some(*last)),
+            }
+        }
+        [old, new] => (
+            revset::single(&repo, old)?,
+            Some(revset::single(&repo, new)?),
+        ),
+        _ => bail!("Too many revisions specified."),
+    };
+    let (old, new) = match new_rev {
+        Some(rev) => patch::revision_snapshots(&repo, old_rev, rev)?,
+        None => (
+            patch::revision_snapshot(&repo, old_rev)?,
+            patch::working_snapshot(&repo)?,
+        ),
+    };
+    let filter = |path: &Path| matcher.matches(path);
+    let changes = patch::changes(&repo, &old, &new, &filter)?;
+    let format = structured_format(template)?;
+    if stat || format.is_some() {
+        let stats = patch::stats(&changes);
+        return match format {
+            Some(format) => format.write(&stats),
+            None => {
+                print!("{}", patch::format_stat(&stats));
+                Ok(())
+            }
+        };
+    }
+
+    let mut changelog = repo.changelog_revlog()?;
+    let mut short_hash =
+        |rev: u32| -> Result<String> { Ok(hex::encode(&changelog.get_record(rev)?.hash[..6])) };
+    let mut header = format!("diff -r {}", short_hash(old_rev)?);
+    if let Some(rev) = new_rev {
+        header.push_str(&format!(" -r {}", short_hash(rev)?));
+    }
+    std::io::stdout().write_all(&patch::format_diff(&changes, &header))?;
+    Ok(())
+}
+
+// Parse a `-T` template argument, which may name a configured style.
+fn load_template(repo: &Repository, spec: Option<&str>) -> Result<Option<Template>> {
+    match spec {
+        Some(spec) if Format::from_template(Some(spec)).is_none() => {
+            Ok(Some(Template::from_spec(spec, &repo.config()?)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+// How `log` and `annotate` show dates, set with `ui.date-format`.
+fn date_format(repo: &Repository) -> Result<String> {
+    Ok(repo
+        .config()?
+        .get("ui", "date-format")
+        .unwrap_or(DEFAULT_DATE_FORMAT)
+        .to_string())
+}
+
+// The structured format for commands that support no other templates.
+fn structured_format(spec: Option<&str>) -> Result<Option<Format>> {
+    match (spec, Format::from_template(spec)) {
+        (Some(spec), None) => bail!("Unsupported template {:?} (use json or cbor).", spec),
+        (_, format) => Ok(format),
+    }
+}
+
+// A changeset as emitted by `-Tjson` and `-Tcbor`.
+#[derive(Serialize)]
+struct ChangesetRecord {
+    rev: u32,
+    node: String,
+    branch: String,
+    user: String,
+    // Seconds since the epoch and the timezone offset in seconds.
+    date: (i64, i32),
+    desc: String,
+    bookmarks: Vec<String>,
+    tags: Vec<String>,
+    parents: Vec<String>,
+    files: Vec<String>,
+}
+
+fn changeset_record(
+    changelog: &mut RevLog,
+    rev: u32,
+    record: &Record,
+    changeset: &Changeset,
+    bookmarks: &[&str],
+    tags: &[&str],
+) -> Result<ChangesetRecord> {
+    let mut parents = Vec::new();
+    for parent in record
+        .parent1_revision
+        .iter()
+        .chain(&record.parent2_revision)
+    {
+        parents.push(hex::encode(changelog.get_record(*parent)?.hash));
+    }
+    Ok(ChangesetRecord {
+        rev,
+        node: hex::encode(record.hash),
+        branch: changeset.branch().to_string(),
+        user: changeset.committer.clone(),
+        date: (
+            changeset.time.map_or(0, |time| time.timestamp()),
+            changeset.timezone,
+        ),
+        desc: changeset.message.clone(),
+        bookmarks: bookmarks.iter().map(|name| name.to_string()).collect(),
+        tags: tags.iter().map(|name| name.to_string()).collect(),
+        parents,
+        files: changeset
+            .changed_files
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect(),
+    })
+}
+
+// The template keywords describing a changeset.
+fn changeset_keywords(
+    changelog: &mut RevLog,
+    rev: u32,
+    record: &Record,
+    changeset: &Changeset,
+    bookmarks: &[&str],
+    tags: &[&str],
+) -> Result<Keywords> {
+    let mut keywords = Keywords::new();
+    keywords.insert("rev".to_string(), Value::text(rev.to_string()));
+    keywords.insert("node".to_string(), Value::text(hex::encode(record.hash)));
+    keywords.insert("author".to_string(), Value::text(&changeset.committer));
+    keywords.insert("desc".to_string(), Value::text(&changeset.message));
+    keywords.insert(
+        "date".to_string(),
+        match changeset.date() {
+            Some(date) => Value::Date(date),
+            None => Value::text(""),
+        },
+    );
+    keywords.insert(
+        "files".to_string(),
+        Value::list(
+            "file",
+            changeset
+                .changed_files
+                .iter()
+                .map(|path| path.display().to_string()),
+        ),
+    );
+    keywords.insert("branch".to_string(), Value::text(changeset.branch()));
+    keywords.insert(
+        "extras".to_string(),
+        Value::list(
+            "extra",
+            changeset
+                .extras
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value)),
+        ),
+    );
+    keywords.insert(
+        "bookmarks".to_string(),
+        Value::list("bookmark", bookmarks.iter().copied()),
+    );
+    keywords.insert("tags".to_string(), Value::list("tag", tags.iter().copied()));
+    for (name, parent) in &[
+        ("p1", record.parent1_revision),
+        ("p2", record.parent2_revision),
+    ] {
+        let (parent_rev, parent_hash) = match parent {
+            Some(parent) => (parent.to_string(), changelog.get_record(*parent)?.hash),
+            None => ("-1".to_string(), NULL_HASH),
+        };
+        keywords.insert(format!("{}rev", name), Value::text(parent_rev));
+        keywords.insert(
+            format!("{}node", name),
+            Value::text(hex::encode(parent_hash)),
+        );
+    }
+    Ok(keywords)
+}
+
+// Format a changeset with a template, or in the default layout without one.
+#[allow(clippy::too_many_arguments)]
+fn show_changeset(
+    changelog: &mut RevLog,
+    rev: u32,
+    record: &Record,
+    changeset: &Changeset,
+    bookmarks: &[&str],
+    tags: &[&str],
+    verbose: bool,
+    template: Option<&Template>,
+    date_format: &str,
+) -> Result<String> {
+    match template {
+        Some(template) => template.render(&changeset_keywords(
+            changelog, rev, record, changeset, bookmarks, tags,
+        )?),
+        None => Ok(format_changeset(
+            rev,
+            &record.hash,
+            changeset,
+            bookmarks,
+            tags,
+            verbose,
+            date_format,
+        )),
+    }
+}
+
+fn format_changeset(
+    rev: u32,
+    hash: &[u8; 20],
+    changeset: &Changeset,
+    bookmarks: &[&str],
+    tags: &[&str],
+    verbose: bool,
+    date_format: &str,
+) -> String {
+    let mut out = String::new();
+    // Writing to a String cannot fail.
+    let _ = writeln!(out, "changeset: {}:{}", rev, hex::encode(hash));
+    for bookmark in bookmarks {
+        let _ = writeln!(out, "bookmark:  {}", bookmark);
+    }
+    for tag in tags {
+        let _ = writeln!(out, "tag:       {}", tag);
+    }
+    if changeset.branch() != DEFAULT_BRANCH {
+        let _ = writeln!(out, "branch:    {}", changeset.branch());
+    }
+    let _ = writeln!(out, "user:      {}", changeset.committer);
+    if let Some(date) = changeset.date() {
+        let _ = writeln!(out, "date:      {}", date.format(date_format));
+    }
+    if verbose {
+        if !changeset.changed_files.is_empty() {
+            let files: Vec<String> = changeset
+                .changed_files
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            let _ = writeln!(out, "files:     {}", files.join(" "));
+        }
+        let _ = writeln!(out, "description:\n{}", changeset.message);
+    } else {
+        let summary = changeset.message.lines().next().unwrap_or_default();
+        let _ = writeln!(out, "summary:   {}", summary);
+    }
+    out.push('\n');
+    out
+}
+
+// Show or set the branch of the working directory.
+pub fn branch(name: Option<&str>, force: bool) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let name = match name {
+        Some(name) => name.trim(),
+        None => {
+            println!("{}", repo.branch()?);
+            return Ok(());
+        }
+    };
+    if name.is_empty() || name.contains(':') || name.contains('\n') {
+        bail!("Invalid branch name {:?}.", name);
+    }
+    let dirstate = repo.dirstate()?;
+    let parent = repo.changeset(repo.changelog_rev(&dirstate.parent1_hash)?)?;
+    if !force && name != parent.branch() && repo.branch_heads()?.contains_key(name) {
+        bail!(
+            "A branch named {} already exists (use 'hg-rs branch -f' to force).",
+            name
+        );
+    }
+    repo.set_branch(name)?;
+    println!("marked working directory as branch {}", name);
     Ok(())
 }
 
-pub fn log() -> Result<()> {
+// List named branches, most recently committed first.
+pub fn branches(show_closed: bool) -> Result<()> {
     let repo = Repository::from_cwd()?;
     let mut changelog = repo.changelog_revlog()?;
-    let size = changelog.size()?;
-    for rev in (0..size).rev() {
-        let hunk = changelog.get_hunk(rev)?;
-        let changeset: Changeset = bincode::deserialize(&hunk)?;
+    let repo_heads = changelog.heads()?;
+    let mut branches = Vec::new();
+    for (name, heads) in repo.branch_heads()? {
+        let mut open_heads = Vec::new();
+        for rev in &heads {
+            if !repo.changeset(*rev)?.closes_branch() {
+                open_heads.push(*rev);
+            }
+        }
+        let closed = open_heads.is_empty();
+        let active = open_heads.iter().any(|rev| repo_heads.contains(rev));
+        let tip = *open_heads
+            .last()
+            .or_else(|| heads.last())
+            .expect("Branches have heads.");
+        branches.push((active, tip, name, closed));
+    }
+    branches.sort_by_key(|(active, tip, _name, _closed)| std::cmp::Reverse((*active, *tip)));
+    for (active, tip, name, closed) in branches {
+        if closed && !show_closed {
+            continue;
+        }
+        let note = if closed {
+            " (closed)"
+        } else if !active {
+            " (inactive)"
+        } else {
+            ""
+        };
         println!(
-            "changeset: {}:{}",
+            "{:<30} {:>5}:{:.12}{}",
+            name,
+            tip,
+            hex::encode(changelog.get_record(tip)?.hash),
+            note
+        );
+    }
+    Ok(())
+}
+
+pub enum BookmarkAction {
+    Set {
+        rev: Option<String>,
+        inactive: bool,
+        force: bool,
+    },
+    Delete,
+    Rename {
+        old: String,
+        force: bool,
+    },
+}
+
+// Create, move, rename or delete bookmarks.
+pub fn bookmark(action: BookmarkAction, names: &[String]) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let mut bookmarks = repo.bookmarks()?;
+    for name in names {
+        if name.is_empty()
+            || name.contains(':')
+            || name.contains('\n')
+            || name.parse::<u32>().is_ok()
+        {
+            bail!("Invalid bookmark name {:?}.", name);
+        }
+    }
+    match action {
+        BookmarkAction::Delete => {
+            if names.is_empty() {
+                bail!("Bookmark name required.");
+            }
+            for name in names {
+                bookmarks
+                    .remove(name)
+                    .with_context(|| format!("Bookmark {} does not exist.", name))?;
+            }
+        }
+        BookmarkAction::Rename { old, force } => {
+            let new = match names {
+                [new] => new,
+                _ => bail!("New bookmark name required."),
+            };
+            if !force && bookmarks.get(new).is_some() {
+                bail!("Bookmark {} already exists (use -f to force).", new);
+            }
+            let was_active = bookmarks.active.as_deref() == Some(old.as_str());
+            let nodeid = bookmarks
+                .remove(&old)
+                .with_context(|| format!("Bookmark {} does not exist.", old))?;
+            bookmarks.set(new, nodeid);
+            if was_active {
+                bookmarks.active = Some(new.clone());
+            }
+        }
+        BookmarkAction::Set {
             rev,
-            hex::encode(changeset.manifest_nodeid)
+            inactive,
+            force,
+        } => {
+            if names.is_empty() {
+                if !inactive {
+                    bail!("Bookmark name required.");
+                }
+                bookmarks.active = None;
+            }
+            let working_parent = repo.dirstate()?.parent1_hash;
+            let nodeid = match rev {
+                Some(spec) => {
+                    repo.changelog_revlog()?
+                        .get_record(repo.lookup(&spec)?)?
+                        .hash
+                }
+                None => working_parent,
+            };
+            for name in names {
+                if !force && bookmarks.get(name).is_some_and(|mark| *mark != nodeid) {
+                    bail!("Bookmark {} already exists (use -f to force).", name);
+                }
+                bookmarks.set(name, nodeid);
+            }
+            if let Some(name) = names.last() {
+                if !inactive && nodeid == working_parent {
+                    bookmarks.active = Some(name.clone());
+                } else if bookmarks.active.as_ref() == Some(name) {
+                    bookmarks.active = None;
+                }
+            }
+        }
+    }
+    repo.commit_bookmarks(bookmarks)?;
+    Ok(())
+}
+
+// List bookmarks, marking the active one with `*`.
+pub fn bookmarks() -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let bookmarks = repo.bookmarks()?;
+    if bookmarks.iter().next().is_none() {
+        println!("no bookmarks set");
+    }
+    for (name, nodeid) in bookmarks.iter() {
+        let marker = if bookmarks.active.as_ref() == Some(name) {
+            "*"
+        } else {
+            " "
+        };
+        println!(
+            " {} {:<25} {:>5}:{:.12}",
+            marker,
+            name,
+            repo.changelog_rev(nodeid)?,
+            hex::encode(nodeid)
         );
-        println!("user:      {}", changeset.committer);
-        if let Some(time) = changeset.time {
-            println!("date:      {}", time);
+    }
+    Ok(())
+}
+
+// Add or remove tags. Global tags are committed to `.hgtags`; local tags are
+// kept in the repository only.
+pub fn tag(
+    names: &[String],
+    rev: Option<&str>,
+    local: bool,
+    remove: bool,
+    force: bool,
+) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let mut dirstate = repo.dirstate()?;
+    let tags = repo.tags()?;
+    for name in names {
+        if name.is_empty()
+            || name.contains(':')
+            || name.contains('\n')
+            || name.contains('\r')
+            || name.parse::<u32>().is_ok()
+            || ["tip", ".", "null"].contains(&name.as_str())
+        {
+            bail!("Invalid tag name {:?}.", name);
+        }
+        if remove {
+            let existing = if local { &tags.local } else { &tags.global };
+            if !existing.contains_key(name) {
+                bail!("Tag {} does not exist.", name);
+            }
+        } else if !force && tags.get(name).is_some() {
+            bail!("Tag {} already exists (use -f to force).", name);
+        }
+    }
+    let nodeid = match (remove, rev) {
+        (true, _) => NULL_HASH,
+        (false, Some(spec)) => {
+            repo.changelog_revlog()?
+                .get_record(repo.lookup(spec)?)?
+                .hash
+        }
+        (false, None) => dirstate.parent1_hash,
+    };
+    if !remove && nodeid == NULL_HASH {
+        bail!("Cannot tag the null revision.");
+    }
+
+    if local {
+        let mut local_tags = tags.local;
+        for name in names {
+            if remove {
+                local_tags.remove(name);
+            } else {
+                local_tags.insert(name.clone(), nodeid);
+            }
         }
-        println!("summary:   {}", changeset.message);
-        println!();
+        return repo.commit_local_tags(&local_tags);
+    }
+
+    if dirstate.parent2_hash != NULL_HASH {
+        bail!("Uncommitted merge.");
+    }
+    if repo
+        .committable_files(&mut dirstate)?
+        .iter()
+        .any(|(path, _entry)| path.as_path() != Path::new(TAGS_FILE))
+    {
+        bail!("Uncommitted changes (commit them before tagging).");
+    }
+    let tags_path = repo.file_path(TAGS_FILE);
+    let mut contents = if tags_path.exists() {
+        std::fs::read_to_string(&tags_path)?
+    } else {
+        String::new()
+    };
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    for name in names {
+        contents.push_str(&format!("{} {}\n", hex::encode(nodeid), name));
+    }
+    std::fs::write(&tags_path, contents)?;
+    if !dirstate.entries().contains_key(Path::new(TAGS_FILE)) {
+        dirstate.mut_entries().insert(
+            TAGS_FILE.into(),
+            Entry::from_file(&tags_path, Status::Added)?,
+        );
+        repo.commit_dirstate(dirstate)?;
+    }
+    let message = if remove {
+        format!("Removed tag {}", names.join(", "))
+    } else {
+        format!(
+            "Added tag {} for changeset {:.12}",
+            names.join(", "),
+            hex::encode(nodeid)
+        )
+    };
+    commit(&CommitOptions {
+        message: Some(message),
+        patterns: Patterns::new(vec![format!("path:{}", TAGS_FILE)]),
+        ..CommitOptions::default()
+    })
+}
+
+// List tags, most recently tagged revision first.
+pub fn tags() -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let tags = repo.tags()?;
+    let mut listing = vec![(repo.lookup("tip")?, "tip", &tags.tip, "")];
+    for (name, nodeid) in &tags.global {
+        listing.push((repo.changelog_rev(nodeid)?, name.as_str(), nodeid, ""));
+    }
+    for (name, nodeid) in &tags.local {
+        listing.push((repo.changelog_rev(nodeid)?, name.as_str(), nodeid, " local"));
+    }
+    listing.sort_by_key(|(rev, _name, _nodeid, _kind)| std::cmp::Reverse(*rev));
+    for (rev, name, nodeid, kind) in listing {
+        println!(
+            "{:<30} {:>5}:{:.12}{}",
+            name,
+            rev,
+            hex::encode(nodeid),
+            kind
+        );
     }
     Ok(())
 }
 
 // Dump the contents of an index file.
-pub fn debug_index<P: AsRef<Path>>(path: P) -> Result<()> {
+pub fn debug_index<P: AsRef<Path>>(path: P, template: Option<&str>) -> Result<()> {
     let repo = Repository::from_cwd()?;
     let mut revlog = repo.revlog(path)?;
-    revlog.debug_index()?;
+    revlog.debug_index(structured_format(template)?)?;
     Ok(())
 }
 
-pub fn debug_changelog_index() -> Result<()> {
+pub fn debug_changelog_index(template: Option<&str>) -> Result<()> {
     let repo = Repository::from_cwd()?;
     let mut changelog = repo.changelog_revlog()?;
-    changelog.debug_index()?;
+    changelog.debug_index(structured_format(template)?)?;
     Ok(())
 }
 
 // Dump the contents of the manifest's index file.
-pub fn debug_manifest_index() -> Result<()> {
+pub fn debug_manifest_index(template: Option<&str>) -> Result<()> {
     let repo = Repository::from_cwd()?;
     let mut revlog = repo.manifest_revlog()?;
-    revlog.debug_index()?;
+    revlog.debug_index(structured_format(template)?)?;
     Ok(())
 }
 
@@ -84,159 +1071,1100 @@ pub fn snapshot<P: AsRef<Path>>(path: P) -> Result<()> {
     let record = revlog.add_revision(&buffer)?;
 
     let mut manifest_revlog = repo.manifest_revlog()?;
-    let mut manifest: Manifest = match manifest_revlog.get_last_hunk() {
-        Ok(hunk) => bincode::deserialize(&hunk)?,
-        Err(_) => Manifest::default(),
-    };
-    manifest.entries.insert(path.as_ref().into(), record.hash);
-    manifest_revlog.add_revision(&bincode::serialize(&manifest)?)?;
+    let last = manifest_revlog.size()?.checked_sub(1);
+    let parent = manifest_revlog
+        .get_record(last.unwrap_or(NULLID_REVISION))?
+        .hash;
+    let mut manifest = repo.read_manifest(&parent)?;
+    let flag = Entry::from_file(&path, Status::Normal)?.flag();
+    manifest.insert(path.as_ref().into(), record.hash, flag);
+    repo.write_manifest(&manifest, [parent, [0u8; 20]], 0)?;
     Ok(())
 }
 
 // Display contents of a revision.
-pub fn debug_data<P: AsRef<Path>>(path: P, rev: u32) -> Result<()> {
+// A plain number is a revision of the file's revlog; any other specifier
+// selects the file's revision in that changeset.
+pub fn debug_data<P: AsRef<Path>>(path: P, rev: &str) -> Result<()> {
     let repo = Repository::from_cwd()?;
     let mut revlog = repo.revlog(&path)?;
+    let rev = match rev.parse::<u32>() {
+        Ok(rev) => rev,
+        Err(_) => {
+            let manifest = repo.manifest(&repo.changeset(repo.lookup(rev)?)?)?;
+            let nodeid = manifest.entries.get(path.as_ref()).with_context(|| {
+                format!("{} is not in revision {}.", path.as_ref().display(), rev)
+            })?;
+            revlog
+                .rev_for_hash(nodeid)?
+                .context("Failed to find file revision.")?
+        }
+    };
     let hunk = revlog.get_hunk(rev)?;
     print!("{}", String::from_utf8(hunk)?);
     Ok(())
 }
 
-pub fn debug_manifest_data(rev: u32) -> Result<()> {
+pub fn debug_manifest_data(rev: &str) -> Result<()> {
     let repo = Repository::from_cwd()?;
-    let mut manifest_revlog = repo.manifest_revlog()?;
-    let hunk = manifest_revlog.get_hunk(rev)?;
-    let manifest: Manifest = bincode::deserialize(&hunk)?;
-    print!("{}", manifest);
+    let nodeid = match rev.parse::<u32>() {
+        Ok(rev) => repo.manifest_revlog()?.get_record(rev)?.hash,
+        Err(_) => repo.changeset(repo.lookup(rev)?)?.manifest_nodeid,
+    };
+    print!("{}", repo.read_manifest(&nodeid)?);
     Ok(())
 }
 
-pub fn debug_changelog_data(rev: u32) -> Result<()> {
+pub fn debug_changelog_data(rev: &str) -> Result<()> {
     let repo = Repository::from_cwd()?;
-    let mut changelog = repo.changelog_revlog()?;
-    let hunk = changelog.get_hunk(rev)?;
-    let changeset: Changeset = bincode::deserialize(&hunk)?;
+    let changeset = repo.changeset(repo.lookup(rev)?)?;
     print!("{}", changeset);
     Ok(())
 }
 
-pub fn add<P: AsRef<Path>>(path: P) -> Result<()> {
-    use std::os::unix::fs::PermissionsExt;
+// Columns shown by `annotate` next to each line.
+#[derive(Debug, Default)]
+pub struct AnnotateOptions {
+    pub rev: Option<String>,
+    pub user: bool,
+    pub number: bool,
+    pub changeset: bool,
+    pub date: bool,
+    pub line_number: bool,
+    pub template: Option<String>,
+}
 
+// A tracked file as emitted by `-Tjson` and `-Tcbor`.
+#[derive(Serialize)]
+struct FileRecord {
+    path: String,
+}
+
+// List the files tracked at a revision, or in the working copy.
+pub fn files(rev: Option<&str>, patterns: &Patterns, template: Option<&str>) -> Result<()> {
     let repo = Repository::from_cwd()?;
-    let mut dirstate = repo.dirstate()?;
-    let file = OpenOptions::new().read(true).open(&path)?;
-    let metadata = file.metadata()?;
-    let entries = dirstate.mut_entries();
-    entries.entry(path.as_ref().into()).or_insert(Entry {
-        status: Status::Added,
-        mode: metadata.permissions().mode(),
-        size: metadata.len(),
-        mtime: metadata.modified()?,
-    });
-    repo.commit_dirstate(dirstate)?;
+    let matcher = repo.matcher(patterns)?;
+    let format = structured_format(template)?;
+    let mut paths: Vec<PathBuf> = match rev {
+        Some(spec) => repo
+            .manifest(&repo.changeset(revset::single(&repo, spec)?)?)?
+            .entries
+            .into_keys()
+            .collect(),
+        None => repo
+            .dirstate()?
+            .entries()
+            .iter()
+            .filter(|(_path, entry)| entry.status != Status::Removed)
+            .map(|(path, _entry)| path.clone())
+            .collect(),
+    };
+    paths.retain(|path| matcher.matches(path));
+    paths.sort();
+    match format {
+        Some(format) => {
+            let records: Vec<FileRecord> = paths
+                .iter()
+                .map(|path| FileRecord {
+                    path: path.display().to_string(),
+                })
+                .collect();
+            format.write(&records)?;
+        }
+        None => {
+            for path in paths {
+                println!("{}", path.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Show each line of files with the changeset that last changed it.
+pub fn annotate(files: &[PathBuf], options: &AnnotateOptions) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let template = load_template(&repo, options.template.as_deref())?;
+    let date_format = date_format(&repo)?;
+    let entry_format = repo.entry_format()?;
+    let rev = match &options.rev {
+        Some(spec) => revset::single(&repo, spec)?,
+        None => repo.changelog_rev(&repo.dirstate()?.parent1_hash)?,
+    };
+    let manifest = repo.manifest(&repo.changeset(rev)?)?;
+    let mut changelog = repo.changelog_revlog()?;
+    let mut changesets: HashMap<u32, (Record, Changeset)> = HashMap::new();
+    let format = Format::from_template(options.template.as_deref());
+    let mut records = Vec::new();
+    for path in files {
+        let nodeid = manifest
+            .entries
+            .get(path)
+            .with_context(|| format!("{} is not tracked at revision {}.", path.display(), rev))?;
+        let lines = annotate_file(&repo, path, nodeid)?;
+        let binary = lines.iter().any(|(_rev, _number, line)| line.contains(&0));
+        let mut annotated = AnnotateRecord {
+            path: path.display().to_string(),
+            binary,
+            lines: Vec::new(),
+        };
+        if binary {
+            match format {
+                Some(_) => records.push(annotated),
+                None => println!("{}: binary file", path.display()),
+            }
+            continue;
+        }
+
+        let mut rows = Vec::new();
+        for (line_rev, number, line) in lines {
+            if let hash_map::Entry::Vacant(vacant) = changesets.entry(line_rev) {
+                let record = changelog.get_record(line_rev)?;
+                let changeset = entry_format.decode_changeset(&changelog.get_hunk(line_rev)?)?;
+                vacant.insert((record, changeset));
+            }
+            let (record, changeset) = &changesets[&line_rev];
+            let line = String::from_utf8_lossy(&line).into_owned();
+            if format.is_some() {
+                annotated.lines.push(AnnotateLine {
+                    rev: line_rev,
+                    node: hex::encode(record.hash),
+                    user: changeset.committer.clone(),
+                    date: (
+                        changeset.time.map_or(0, |time| time.timestamp()),
+                        changeset.timezone,
+                    ),
+                    lineno: number,
+                    line,
+                });
+                continue;
+            }
+            if let Some(template) = &template {
+                let mut keywords = Keywords::new();
+                keywords.insert("rev".to_string(), Value::text(line_rev.to_string()));
+                keywords.insert("node".to_string(), Value::text(hex::encode(record.hash)));
+                keywords.insert("author".to_string(), Value::text(&changeset.committer));
+                if let Some(date) = changeset.date() {
+                    keywords.insert("date".to_string(), Value::Date(date));
+                }
+                keywords.insert("path".to_string(), Value::text(path.display().to_string()));
+                keywords.insert("lineno".to_string(), Value::text(number.to_string()));
+                keywords.insert("line".to_string(), Value::text(line));
+                print!("{}", template.render(&keywords)?);
+                continue;
+            }
+            let mut columns = Vec::new();
+            if options.user {
+                columns.push(changeset.committer.clone());
+            }
+            if options.number || !(options.user || options.changeset || options.date) {
+                columns.push(line_rev.to_string());
+            }
+            if options.changeset {
+                columns.push(hex::encode(&record.hash[..6]));
+            }
+            if options.date {
+                columns.push(
+                    changeset
+                        .date()
+                        .map(|date| date.format(&date_format).to_string())
+                        .unwrap_or_default(),
+                );
+            }
+            rows.push((columns, number, line));
+        }
+        if format.is_some() {
+            records.push(annotated);
+            continue;
+        }
+
+        // Right-align each column to its widest value.
+        let widths: Vec<usize> = (0..rows.first().map_or(0, |(columns, _, _)| columns.len()))
+            .map(|i| {
+                rows.iter()
+                    .map(|(columns, _, _)| columns[i].len())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        for (columns, number, line) in rows {
+            let columns: Vec<String> = columns
+                .iter()
+                .zip(&widths)
+                .map(|(column, width)| format!("{:>width$}", column, width = width))
+                .collect();
+            let mut prefix = columns.join(" ");
+            if options.line_number {
+                prefix.push_str(&format!(":{}", number));
+            }
+            print!("{}: {}", prefix, line);
+            if !line.ends_with('\n') {
+                println!();
+            }
+        }
+    }
+    if let Some(format) = format {
+        format.write(&records)?;
+    }
     Ok(())
 }
 
-pub fn status() -> Result<()> {
+// An annotated file as emitted by `-Tjson` and `-Tcbor`.
+#[derive(Serialize)]
+struct AnnotateRecord {
+    path: String,
+    binary: bool,
+    lines: Vec<AnnotateLine>,
+}
+
+#[derive(Serialize)]
+struct AnnotateLine {
+    rev: u32,
+    node: String,
+    user: String,
+    date: (i64, i32),
+    lineno: usize,
+    line: String,
+}
+
+// Each line of a file revision with the changelog revision that introduced
+// it and its line number there.
+fn annotate_file(
+    repo: &Repository,
+    path: &Path,
+    nodeid: &[u8; 20],
+) -> Result<Vec<(u32, usize, Vec<u8>)>> {
+    let mut revlog = repo.revlog(path)?;
+    let target = revlog
+        .rev_for_hash(nodeid)?
+        .with_context(|| format!("Failed to find revision of {}.", path.display()))?;
+    let mut texts: HashMap<u32, Vec<u8>> = HashMap::new();
+    let mut annotations: HashMap<u32, Vec<(u32, usize)>> = HashMap::new();
+    // Ancestors come in ascending order, so parents are annotated first.
+    for rev in revlog.ancestors(target)? {
+        let record = revlog.get_record(rev)?;
+        let text = revlog.get_hunk(rev)?;
+        let lines = split_lines(&text);
+        let mut annotation: Vec<(u32, usize)> = (0..lines.len())
+            .map(|index| (record.link_revision, index + 1))
+            .collect();
+        // Lines shared with a parent came from it, preferring the first parent.
+        for parent in record
+            .parent2_revision
+            .into_iter()
+            .chain(record.parent1_revision)
+        {
+            let parent_lines = split_lines(&texts[&parent]);
+            let parent_annotation = &annotations[&parent];
+            for block in matching_blocks(&parent_lines, &lines) {
+                for i in 0..block.length {
+                    annotation[block.b_start + i] = parent_annotation[block.a_start + i];
+                }
+            }
+        }
+        texts.insert(rev, text);
+        annotations.insert(rev, annotation);
+    }
+    let lines = split_lines(&texts[&target])
+        .into_iter()
+        .map(<[u8]>::to_vec)
+        .collect::<Vec<_>>();
+    Ok(annotations[&target]
+        .iter()
+        .zip(lines)
+        .map(|((rev, number), line)| (*rev, *number, line))
+        .collect())
+}
+
+// Start tracking the unknown files that match, or all of them without
+// patterns. Files found by a pattern rather than named are listed. Ignored
+// files are only added when named.
+pub fn add(patterns: &Patterns) -> Result<()> {
     let repo = Repository::from_cwd()?;
+    let matcher = repo.matcher(patterns)?;
+    for file in matcher.files() {
+        if !lexists(repo.file_path(file)) {
+            bail!("{}: No such file or directory.", file.display());
+        }
+    }
     let mut dirstate = repo.dirstate()?;
+    // Directories the dirstate caches only hold tracked files.
+    let walk = repo.walk_cached(&dirstate)?;
+    let named = |path: &PathBuf| matcher.files().contains(path);
     let entries = dirstate.mut_entries();
-    for dir_entry in std::fs::read_dir(".")?
-        .filter_map(Result::ok)
-        .filter(|dir_entry| {
-            !dir_entry
-                .file_name()
-                .to_str()
-                .map(|s| s.starts_with("."))
-                .unwrap_or(false)
-        })
+    for path in walk
+        .files
+        .iter()
+        .chain(walk.ignored.iter().filter(|path| named(path)))
     {
-        let path: PathBuf = dir_entry
-            .path()
-            .file_name()
-            .context("Could not get file name.")?
-            .into();
-        let status_symbol = if let Some(entry) = entries.get(&path) {
-            match entry.status {
-                Status::Added => "A",
-                Status::Merged | Status::Removed => unimplemented!(),
-                Status::Normal => {
-                    let metadata = dir_entry.metadata()?;
-                    if entry.mtime == metadata.modified()? && entry.size == metadata.len() {
-                        "C"
-                    } else {
-                        "M"
-                    }
-                }
+        if !matcher.matches(path) {
+            continue;
+        }
+        if let hash_map::Entry::Vacant(vacant) = entries.entry(path.clone()) {
+            if !named(path) {
+                println!("adding {}", path.display());
             }
-        } else {
-            "?"
-        };
-        println!("{} {}", status_symbol, path.display());
+            vacant.insert(Entry::from_file(repo.file_path(path), Status::Added)?);
+        }
     }
+    dirstate.set_cached_directories(walk.directories);
+    repo.commit_dirstate(dirstate)?;
+    Ok(())
+}
+
+// A file's status as emitted by `-Tjson` and `-Tcbor`.
+#[derive(Serialize)]
+struct StatusRecord<'a> {
+    path: String,
+    status: &'a str,
+}
+
+// Options that select what `status` compares and which files it shows.
+// Without any category flags, modified, added, removed, deleted and unknown
+// files are shown.
+#[derive(Debug, Default)]
+pub struct StatusOptions {
+    pub revs: Vec<String>,
+    pub change: Option<String>,
+    pub all: bool,
+    pub modified: bool,
+    pub added: bool,
+    pub removed: bool,
+    pub deleted: bool,
+    pub unknown: bool,
+    pub ignored: bool,
+    pub clean: bool,
+    pub no_status: bool,
+    pub print0: bool,
+    pub template: Option<String>,
+    pub patterns: Patterns,
+}
+
+// Status letters in the order their files are listed.
+const STATUS_ORDER: [&str; 7] = ["M", "A", "R", "!", "?", "I", "C"];
+
+// Show changed files in the working copy, or between two revisions.
+pub fn status(options: &StatusOptions) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let matcher = repo.matcher(&options.patterns)?;
+    let template_spec = options.template.as_deref();
+    let template = load_template(&repo, template_spec)?;
+    let categories = [
+        (options.modified, "M"),
+        (options.added, "A"),
+        (options.removed, "R"),
+        (options.deleted, "!"),
+        (options.unknown, "?"),
+        (options.ignored, "I"),
+        (options.clean, "C"),
+    ];
+    let shown: Vec<&str> = if options.all {
+        STATUS_ORDER.to_vec()
+    } else if categories.iter().any(|(enabled, _status)| *enabled) {
+        categories
+            .iter()
+            .filter(|(enabled, _status)| *enabled)
+            .map(|(_enabled, status)| *status)
+            .collect()
+    } else {
+        vec!["M", "A", "R", "!", "?"]
+    };
+    let clean = shown.contains(&"C");
 
+    let (old_rev, new_rev) = match (&options.change, options.revs.as_slice()) {
+        (Some(_), [_, ..]) => bail!("Cannot specify both --rev and --change."),
+        (Some(spec), []) => {
+            let rev = revset::single(&repo, spec)?;
+            let parent = repo.changelog_revlog()?.get_record(rev)?.parent1_revision;
+            (parent.unwrap_or(NULLID_REVISION), Some(rev))
+        }
+        (None, []) => (repo.changelog_rev(&repo.dirstate()?.parent1_hash)?, None),
+        (None, [old]) => (revset::single(&repo, old)?, None),
+        (None, [old, new]) => (
+            revset::single(&repo, old)?,
+            Some(revset::single(&repo, new)?),
+        ),
+        _ => bail!("Too many revisions specified."),
+    };
+    let mut statuses = match new_rev {
+        Some(new_rev) => status::revision_status(&repo, old_rev, new_rev, clean)?,
+        None => status::working_status(&repo, old_rev)?,
+    };
+    statuses.retain(|(status, path)| shown.contains(status) && matcher.matches(path));
+    statuses.sort_by_key(|(status, path)| {
+        let order = STATUS_ORDER.iter().position(|s| s == status);
+        (order, path.clone())
+    });
+
+    if let Some(format) = Format::from_template(template_spec) {
+        let records: Vec<StatusRecord> = statuses
+            .iter()
+            .map(|(status, path)| StatusRecord {
+                path: path.display().to_string(),
+                status,
+            })
+            .collect();
+        return format.write(&records);
+    }
+    let terminator = if options.print0 { '\0' } else { '\n' };
+    for (status, path) in statuses {
+        match &template {
+            Some(template) => {
+                let mut keywords = Keywords::new();
+                keywords.insert("status".to_string(), Value::text(status));
+                keywords.insert("path".to_string(), Value::text(path.display().to_string()));
+                print!("{}", template.render(&keywords)?);
+            }
+            None if options.no_status => print!("{}{}", path.display(), terminator),
+            None => print!("{} {}{}", status, path.display(), terminator),
+        }
+    }
     Ok(())
 }
 
-pub fn commit(message: &str) -> Result<()> {
+// Options for `commit`.
+#[derive(Debug, Default)]
+pub struct CommitOptions {
+    pub message: Option<String>,
+    pub logfile: Option<PathBuf>,
+    pub user: Option<String>,
+    pub date: Option<String>,
+    pub addremove: bool,
+    pub close_branch: bool,
+    pub patterns: Patterns,
+}
+
+// Commit the changes to the given files, or to every tracked file.
+pub fn commit(options: &CommitOptions) -> Result<()> {
     let repo = Repository::from_cwd()?;
+    if repo.mergestate()?.unresolved().next().is_some() {
+        bail!("Unresolved merge conflicts (see 'hg-rs resolve').");
+    }
+    let config = repo.config()?;
+    let entry_format = repo.entry_format()?;
+    let committer = match &options.user {
+        Some(user) => user.clone(),
+        None => config.username()?,
+    };
+    let date = match &options.date {
+        Some(date) => parse_date(date)?,
+        None => parse_date("now")?,
+    };
+    let matcher = repo.matcher(&options.patterns)?;
+    let selected = |path: &Path| matcher.matches(path);
+    if options.addremove {
+        let mut dirstate = repo.dirstate()?;
+        addremove(&repo, &mut dirstate, &selected)?;
+        repo.commit_dirstate(dirstate)?;
+    }
     let mut changelog = repo.changelog_revlog()?;
-    let mut changeset: Changeset = changelog
-        .get_last_hunk()
-        .and_then(|hunk| bincode::deserialize(&hunk).map_err(anyhow::Error::from))
-        .unwrap_or_default();
+    let mut dirstate = repo.dirstate()?;
+    let parent1 = repo.changelog_rev(&dirstate.parent1_hash)?;
+    let parent2 = repo.changelog_rev(&dirstate.parent2_hash)?;
+    let parent1_changeset = repo.changeset(parent1)?;
+    let parent2_changeset = repo.changeset(parent2)?;
+    if !matcher.always() {
+        if parent2 != NULLID_REVISION {
+            bail!("Cannot partially commit a merge.");
+        }
+        for file in matcher.files() {
+            if !dirstate.entries().keys().any(|path| path.starts_with(file)) {
+                bail!("{}: file not tracked.", file.display());
+            }
+        }
+    }
 
-    let mut manifest_revlog = repo.manifest_revlog()?;
-    let mut manifest: Manifest = manifest_revlog
-        .get_last_hunk()
-        .and_then(|hunk| bincode::deserialize(&hunk).map_err(anyhow::Error::from))
-        .unwrap_or_default();
+    let mut manifest = repo.manifest(&parent1_changeset)?;
+    let parent2_manifest = repo.manifest(&parent2_changeset)?;
+    let link_revision = changelog.size()?;
+    let branch = repo.branch()?;
+
+    let mut commitable_files = repo.committable_files(&mut dirstate)?;
+    commitable_files.retain(|(path, _entry)| selected(path));
+    if commitable_files.is_empty()
+        && parent2 == NULLID_REVISION
+        && !options.close_branch
+        && branch == parent1_changeset.branch()
+    {
+        bail!("nothing changed");
+    }
+    let message = match (&options.message, &options.logfile) {
+        (Some(message), _) => message.clone(),
+        (None, Some(logfile)) if logfile == Path::new("-") => {
+            let mut message = String::new();
+            std::io::stdin().read_to_string(&mut message)?;
+            message
+        }
+        (None, Some(logfile)) => std::fs::read_to_string(logfile).with_context(|| {
+            format!("Failed to read commit message from {}.", logfile.display())
+        })?,
+        (None, None) => {
+            let mut lines = vec![
+                String::new(),
+                "HG: Enter commit message.  Lines beginning with 'HG:' are removed.".to_string(),
+                "HG: Leave message empty to abort commit.".to_string(),
+                "HG: --".to_string(),
+                format!("HG: user: {}", committer),
+                format!("HG: branch '{}'", branch),
+            ];
+            let mut paths: Vec<(&PathBuf, &Status)> = commitable_files
+                .iter()
+                .map(|(path, entry)| (*path, &entry.status))
+                .collect();
+            paths.sort_by_key(|(path, _status)| *path);
+            for (path, status) in paths {
+                let action = match status {
+                    Status::Added => "added",
+                    Status::Removed => "removed",
+                    _ => "changed",
+                };
+                lines.push(format!("HG: {} {}", action, path.display()));
+            }
+            edit_message(&repo, &config, &lines.join("\n"))?
+        }
+    };
+    let message = message.trim_end();
+    if message.is_empty() {
+        bail!("Empty commit message.");
+    }
 
     // Update manifest with committed files.
-    let mut dirstate = repo.dirstate()?;
-    let mut commitable_files = dirstate.committable_files();
-    for (path, _entry) in &commitable_files {
+    for (path, entry) in &commitable_files {
+        if entry.status == Status::Removed {
+            manifest.remove(path);
+            continue;
+        }
+        let flag = Entry::from_file(repo.file_path(path), Status::Normal)?.flag();
         // Update revlog of each file.
         // TODO: Defer writing to revlogs until end, when we actually know the ChangeSetId.
-        let mut revlog = repo.revlog(&path)?;
-        let mut file = File::open(&path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        let record = revlog.add_revision(&buffer)?;
-        manifest.entries.insert(path.into(), record.hash);
+        let mut revlog = repo.revlog(path)?;
+        let buffer = repo.working_data(path)?;
+        let file_parent1 = match manifest.entries.get(*path) {
+            Some(nodeid) => revlog.rev_for_hash(nodeid)?,
+            None => None,
+        };
+        let file_parent2 = match parent2_manifest.entries.get(*path) {
+            Some(nodeid) if manifest.entries.get(*path) != Some(nodeid) => {
+                revlog.rev_for_hash(nodeid)?
+            }
+            _ => None,
+        };
+        // A file whose contents did not change, such as one that only became
+        // executable, keeps its filelog revision.
+        let nodeid = match (file_parent1, file_parent2) {
+            (Some(parent), None) if revlog.get_hunk(parent)? == buffer => {
+                revlog.get_record(parent)?.hash
+            }
+            _ => {
+                revlog
+                    .add_revision_with_parents(&buffer, file_parent1, file_parent2, link_revision)?
+                    .hash
+            }
+        };
+        manifest.insert(path.into(), nodeid, flag);
     }
 
     // Update changelog with newest changeset.
-    let record = manifest_revlog.add_revision(&bincode::serialize(&manifest)?)?;
-    changeset.manifest_nodeid = record.hash;
-    changeset.message = message.to_string();
-    changeset.committer = "mingyli34@gmail.com".to_string();
-    changeset.changed_files = commitable_files
-        .iter()
-        .map(|(path, _entry)| PathBuf::clone(path))
-        .collect();
-    changeset.time = Some(Utc::now());
-    let record = changelog.add_revision(&bincode::serialize(&changeset)?)?;
+    let manifest_nodeid = repo.write_manifest(
+        &manifest,
+        [
+            parent1_changeset.manifest_nodeid,
+            parent2_changeset.manifest_nodeid,
+        ],
+        link_revision,
+    )?;
+    let mut changeset = Changeset {
+        manifest_nodeid,
+        message: message.to_string(),
+        committer,
+        changed_files: commitable_files
+            .iter()
+            .map(|(path, _entry)| PathBuf::clone(path))
+            .collect(),
+        time: Some(date.with_timezone(&Utc)),
+        timezone: -date.offset().local_minus_utc(),
+        ..Changeset::default()
+    };
+    changeset.set_branch(&branch);
+    if options.close_branch {
+        changeset
+            .extras
+            .insert("close".to_string(), "1".to_string());
+    }
+    let record = changelog.add_revision_with_parents(
+        &entry_format.encode_changeset(&changeset)?,
+        Some(parent1),
+        Some(parent2),
+        link_revision,
+    )?;
 
     // Update dirstate with newest data.
+    let mut removed = HashSet::new();
     for (path, entry) in &mut commitable_files {
-        use std::os::unix::fs::PermissionsExt;
-        let metadata = std::fs::metadata(path)?;
-        entry.status = Status::Normal;
-        entry.size = metadata.len();
-        entry.mode = metadata.permissions().mode();
-        entry.mtime = metadata.modified()?;
+        if entry.status == Status::Removed {
+            removed.insert(PathBuf::clone(path));
+            continue;
+        }
+        **entry = Entry::from_file(repo.file_path(path), Status::Normal)?;
     }
+    dirstate
+        .mut_entries()
+        .retain(|path, _entry| !removed.contains(path));
+    let mut bookmarks = repo.bookmarks()?;
+    bookmarks.advance(&dirstate.parent1_hash, record.hash);
+    repo.commit_bookmarks(bookmarks)?;
     dirstate.parent1_hash = record.hash;
+    dirstate.parent2_hash = NULL_HASH;
+    repo.refresh_cached_directories(&mut dirstate)?;
+    repo.commit_dirstate(dirstate)?;
+    repo.clear_mergestate()?;
+
+    Ok(())
+}
+
+// Let the user write a commit message starting from `template`, dropping
+// the `HG:` lines afterwards.
+fn edit_message(repo: &Repository, config: &Config, template: &str) -> Result<String> {
+    let mut file = tempfile::Builder::new()
+        .prefix("hg-rs-editor-")
+        .suffix(".commit.txt")
+        .tempfile_in(repo.repo_path(""))?;
+    file.write_all(template.as_bytes())?;
+    file.flush()?;
+    run_editor(&config.editor(), file.path())?;
+    let text = std::fs::read_to_string(file.path())?;
+    Ok(text
+        .lines()
+        .filter(|line| !line.starts_with("HG:"))
+        .map(|line| format!("{}\n", line))
+        .collect())
+}
+
+// Track unknown files and forget missing ones among the selected paths.
+fn addremove(
+    repo: &Repository,
+    dirstate: &mut Dirstate,
+    selected: &dyn Fn(&Path) -> bool,
+) -> Result<()> {
+    let entries = dirstate.mut_entries();
+    for path in repo.walk()?.files {
+        if !selected(&path) {
+            continue;
+        }
+        if let hash_map::Entry::Vacant(vacant) = entries.entry(path) {
+            println!("adding {}", vacant.key().display());
+            let entry = Entry::from_file(repo.file_path(vacant.key()), Status::Added)?;
+            vacant.insert(entry);
+        }
+    }
+    entries.retain(|path, entry| {
+        if entry.status == Status::Removed || lexists(repo.file_path(path)) || !selected(path) {
+            return true;
+        }
+        println!("removing {}", path.display());
+        // Files that were only added are forgotten entirely.
+        let added = entry.status == Status::Added;
+        entry.status = Status::Removed;
+        !added
+    });
+    Ok(())
+}
+
+// Write the revision of a file with the given nodeid into the working copy,
+// as a symlink or an executable file if its manifest flag says so.
+fn checkout_file(
+    repo: &Repository,
+    path: &Path,
+    nodeid: &[u8; 20],
+    flag: Option<Flag>,
+) -> Result<()> {
+    write_file(repo, path, &repo.file_data(path, nodeid)?, flag)
+}
+
+// Write a file into the working copy, as a symlink to `data` or an
+// executable file if the flag says so.
+fn write_file(repo: &Repository, path: &Path, data: &[u8], flag: Option<Flag>) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let file_path = repo.file_path(path);
+    if let Some(parent) = file_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    // Replace rather than write through an existing symlink.
+    if std::fs::symlink_metadata(&file_path)
+        .is_ok_and(|metadata| metadata.file_type().is_symlink() || flag == Some(Flag::Symlink))
+    {
+        std::fs::remove_file(&file_path)?;
+    }
+    if flag == Some(Flag::Symlink) {
+        std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(data), &file_path)?;
+        return Ok(());
+    }
+    std::fs::write(&file_path, data)?;
+    let mut permissions = std::fs::metadata(&file_path)?.permissions();
+    let mode = permissions.mode();
+    // Executable bits follow the read bits, as in Mercurial.
+    let mode = match flag {
+        Some(Flag::Executable) => mode | ((mode & 0o444) >> 2),
+        _ => mode & !0o111,
+    };
+    if mode != permissions.mode() {
+        permissions.set_mode(mode);
+        std::fs::set_permissions(&file_path, permissions)?;
+    }
+    Ok(())
+}
+
+fn remove_file(repo: &Repository, path: &Path) -> Result<()> {
+    let file_path = repo.file_path(path);
+    if lexists(&file_path) {
+        std::fs::remove_file(file_path)?;
+    }
+    Ok(())
+}
+
+// Update the working copy to a revision, defaulting to the tip. Updating to
+// a bookmark activates it.
+pub fn update(rev: Option<&str>, clean: bool) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let mut dirstate = repo.dirstate()?;
+    if !clean {
+        if dirstate.parent2_hash != NULL_HASH {
+            bail!("Outstanding uncommitted merge (use -C to discard).");
+        }
+        if !repo.committable_files(&mut dirstate)?.is_empty() {
+            bail!("Uncommitted changes (use -C to discard).");
+        }
+    }
+    let mut changelog = repo.changelog_revlog()?;
+    let mut bookmarks = repo.bookmarks()?;
+    let (rev, bookmark) = match rev {
+        Some(spec) => {
+            let bookmark = match (spec.parse::<u32>(), bookmarks.get(spec)) {
+                (Err(_), Some(_)) => Some(spec.to_string()),
+                _ => None,
+            };
+            (revset::single(&repo, spec)?, bookmark)
+        }
+        None => (
+            changelog.size()?.checked_sub(1).unwrap_or(NULLID_REVISION),
+            None,
+        ),
+    };
+    let target_hash = changelog.get_record(rev)?.hash;
+    let target_changeset = repo.changeset(rev)?;
+    let current = repo.manifest(&repo.changeset(repo.changelog_rev(&dirstate.parent1_hash)?)?)?;
+    let target = repo.manifest(&target_changeset)?;
+
+    let workers = repo.workers()?;
+    let removals: Vec<&PathBuf> = current
+        .entries
+        .keys()
+        .filter(|path| !target.entries.contains_key(*path))
+        .collect();
+    worker::map(workers, &removals, |path| remove_file(&repo, path))?;
+    let removed = removals.len();
+
+    // Write files on worker threads, after removals so that a file may
+    // replace a directory.
+    let files: Vec<(&PathBuf, &[u8; 20])> = target.entries.iter().collect();
+    let checkouts = worker::map(workers, &files, |(path, nodeid)| {
+        let unchanged = current.entries.get(*path) == Some(*nodeid)
+            && current.flag(path) == target.flag(path)
+            && match dirstate.entries().get(*path) {
+                Some(entry) => {
+                    entry.status == Status::Normal && entry.matches_file(repo.file_path(path))
+                }
+                None => false,
+            };
+        if !unchanged {
+            checkout_file(&repo, path, nodeid, target.flag(path))?;
+        }
+        let entry = Entry::from_file(repo.file_path(path), Status::Normal)?;
+        Ok(((*path).clone(), entry, !unchanged))
+    })?;
+    let mut updated = 0;
+    let mut entries = HashMap::new();
+    for (path, entry, written) in checkouts {
+        updated += usize::from(written);
+        entries.insert(path, entry);
+    }
+
+    *dirstate.mut_entries() = entries;
+    dirstate.parent1_hash = target_hash;
+    dirstate.parent2_hash = NULL_HASH;
+    repo.refresh_cached_directories(&mut dirstate)?;
     repo.commit_dirstate(dirstate)?;
+    repo.clear_mergestate()?;
+    repo.set_branch(target_changeset.branch())?;
+    println!("{} files updated, {} files removed", updated, removed);
+    if bookmarks.active != bookmark {
+        if let Some(active) = &bookmarks.active {
+            println!("(leaving bookmark {})", active);
+        }
+        if let Some(bookmark) = &bookmark {
+            println!("(activating bookmark {})", bookmark);
+        }
+        bookmarks.active = bookmark;
+        repo.commit_bookmarks(bookmarks)?;
+    }
+    Ok(())
+}
 
+// Merge another revision into the working copy.
+pub fn merge(rev: &str, tool: Option<&str>) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let rev = repo.lookup(rev)?;
+    let mut dirstate = repo.dirstate()?;
+    if dirstate.parent2_hash != NULL_HASH {
+        bail!("Outstanding uncommitted merge.");
+    }
+    if !repo.committable_files(&mut dirstate)?.is_empty() {
+        bail!("Uncommitted changes.");
+    }
+    let mut changelog = repo.changelog_revlog()?;
+    let local_rev = repo.changelog_rev(&dirstate.parent1_hash)?;
+    let other_hash = changelog.get_record(rev)?.hash;
+    let ancestor = changelog
+        .common_ancestor(local_rev, rev)?
+        .unwrap_or(NULLID_REVISION);
+    if ancestor == rev {
+        bail!("Nothing to merge.");
+    }
+    if ancestor == local_rev {
+        bail!("Nothing to merge (use 'hg-rs update' instead).");
+    }
+
+    let local = repo.manifest(&repo.changeset(local_rev)?)?;
+    let other = repo.manifest(&repo.changeset(rev)?)?;
+    let base = repo.manifest(&repo.changeset(ancestor)?)?;
+    let tool = Tool::select(tool, &repo.config()?)?;
+    let mut mergestate = MergeState::new(dirstate.parent1_hash, other_hash);
+    let (mut updated, mut merged, mut removed, mut unresolved) = (0, 0, 0, 0);
+    for (path, change) in local.diff(&other) {
+        let local_nodeid = change.before().map(|(nodeid, _flag)| nodeid);
+        let other_nodeid = change.after().map(|(nodeid, _flag)| nodeid);
+        let local_flag = change.before().and_then(|(_nodeid, flag)| flag);
+        let other_flag = change.after().and_then(|(_nodeid, flag)| flag);
+        let base_nodeid = base.entries.get(path);
+        if change.after() == base_nodeid.map(|nodeid| (nodeid, base.flag(path))) {
+            continue;
+        }
+        let path = &path.to_path_buf();
+        let file_path = repo.file_path(path);
+        let flag = match local_nodeid {
+            Some(_) => merge_flags(local_flag, base.flag(path), other_flag).unwrap_or_else(|| {
+                println!(
+                    "warning: conflicting flags for {}, keeping the local flag",
+                    path.display()
+                );
+                local_flag
+            }),
+            None => other_flag,
+        };
+        match (local_nodeid, other_nodeid) {
+            (Some(local_nodeid), Some(other_nodeid))
+                if local_nodeid != base_nodeid.unwrap_or(&NULL_HASH) =>
+            {
+                let base_nodeid = *base_nodeid.unwrap_or(&NULL_HASH);
+                mergestate.add(path, *local_nodeid, *other_nodeid, base_nodeid);
+                let resolved = filemerge(
+                    &tool,
+                    &file_path,
+                    &repo.file_data(path, local_nodeid)?,
+                    &repo.file_data(path, &base_nodeid)?,
+                    &repo.file_data(path, other_nodeid)?,
+                )?;
+                if flag != local_flag {
+                    write_file(&repo, path, &repo.working_data(path)?, flag)?;
+                }
+                if resolved {
+                    mergestate.mark(path, FileState::Resolved);
+                    merged += 1;
+                } else {
+                    unresolved += 1;
+                }
+                dirstate
+                    .mut_entries()
+                    .insert(path.clone(), Entry::from_file(&file_path, Status::Merged)?);
+            }
+            (_, Some(other_nodeid)) => {
+                if local_nodeid.is_none() && base_nodeid.is_some() {
+                    println!(
+                        "note: {} was removed locally but changed in other, keeping it",
+                        path.display()
+                    );
+                }
+                checkout_file(&repo, path, other_nodeid, flag)?;
+                dirstate
+                    .mut_entries()
+                    .insert(path.clone(), Entry::from_file(&file_path, Status::Merged)?);
+                updated += 1;
+            }
+            (Some(_), None) if local_nodeid == base_nodeid => {
+                remove_file(&repo, path)?;
+                if let Some(entry) = dirstate.mut_entries().get_mut(path) {
+                    entry.status = Status::Removed;
+                }
+                removed += 1;
+            }
+            (Some(_), None) => {
+                println!(
+                    "note: {} was changed locally but removed in other, keeping it",
+                    path.display()
+                );
+            }
+            (None, None) => unreachable!("Both sides cannot be missing."),
+        }
+    }
+
+    dirstate.parent2_hash = other_hash;
+    repo.commit_dirstate(dirstate)?;
+    repo.commit_mergestate(mergestate)?;
+    println!(
+        "{} files updated, {} files merged, {} files removed, {} files unresolved",
+        updated, merged, removed, unresolved
+    );
+    if unresolved > 0 {
+        println!("use 'hg-rs resolve' to retry unresolved file merges");
+    }
+    Ok(())
+}
+
+pub enum ResolveAction {
+    List,
+    Mark,
+    Unmark,
+    Remerge,
+}
+
+// List, mark or re-merge files recorded in the merge state.
+pub fn resolve(
+    action: ResolveAction,
+    files: &[PathBuf],
+    all: bool,
+    tool: Option<&str>,
+) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let mut mergestate = repo.mergestate()?;
+    if !mergestate.is_active() {
+        if let ResolveAction::List = action {
+            return Ok(());
+        }
+        bail!("No merge in progress.");
+    }
+    let selected: Vec<PathBuf> = if !files.is_empty() {
+        files.to_vec()
+    } else if all {
+        mergestate.files().keys().cloned().collect()
+    } else if let ResolveAction::List = action {
+        mergestate.files().keys().cloned().collect()
+    } else {
+        bail!("No files specified (use --all to select all files).");
+    };
+
+    match action {
+        ResolveAction::List => {
+            for path in &selected {
+                if let Some(entry) = mergestate.files().get(path) {
+                    let symbol = match entry.state {
+                        FileState::Unresolved => "U",
+                        FileState::Resolved => "R",
+                    };
+                    println!("{} {}", symbol, path.display());
+                }
+            }
+            return Ok(());
+        }
+        ResolveAction::Mark | ResolveAction::Unmark => {
+            let state = match action {
+                ResolveAction::Mark => FileState::Resolved,
+                _ => FileState::Unresolved,
+            };
+            for path in &selected {
+                if !mergestate.mark(path, state) {
+                    bail!("{} is not part of the merge.", path.display());
+                }
+            }
+        }
+        ResolveAction::Remerge => {
+            let tool = Tool::select(tool, &repo.config()?)?;
+            for path in &selected {
+                let entry = mergestate
+                    .files()
+                    .get(path)
+                    .with_context(|| format!("{} is not part of the merge.", path.display()))?;
+                if all && entry.state == FileState::Resolved {
+                    continue;
+                }
+                let file_path = repo.file_path(path);
+                if file_path.exists() {
+                    let mut backup = file_path.clone().into_os_string();
+                    backup.push(".orig");
+                    std::fs::copy(&file_path, backup)?;
+                }
+                let resolved = filemerge(
+                    &tool,
+                    &file_path,
+                    &repo.file_data(path, &entry.local_nodeid)?,
+                    &repo.file_data(path, &entry.base_nodeid)?,
+                    &repo.file_data(path, &entry.other_nodeid)?,
+                )?;
+                let state = if resolved {
+                    FileState::Resolved
+                } else {
+                    FileState::Unresolved
+                };
+                mergestate.mark(path, state);
+            }
+        }
+    }
+
+    let done = mergestate.unresolved().next().is_none();
+    repo.commit_mergestate(mergestate)?;
+    if done {
+        println!("(no more unresolved files)");
+    }
+    Ok(())
+}
+
+// Watch the working directory so that status and commit only examine the
+// files that changed. With `daemon` the watcher runs in the background.
+pub fn fsmonitor(daemon: bool, stop: bool) -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    if stop {
+        return fsmonitor::stop(&repo);
+    }
+    if daemon {
+        std::process::Command::new(std::env::current_exe()?)
+            .arg("fsmonitor")
+            .current_dir(repo.file_path(""))
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()?;
+        return Ok(());
+    }
+    fsmonitor::run(&repo)
+}
+
+// Clean up after an interrupted write, restoring state files that can't be
+// read from their previous versions.
+pub fn recover() -> Result<()> {
+    let repo = Repository::from_cwd()?;
+    let restored = repo.recover()?;
+    if restored.is_empty() {
+        println!("no interrupted writes found");
+    }
+    for path in restored {
+        println!("restored {} from backup", path.display());
+    }
     Ok(())
 }
 
-pub fn debug_dirstate() -> Result<()> {
+pub fn debug_dirstate(template: Option<&str>) -> Result<()> {
     let repo = Repository::from_cwd()?;
     let dirstate = repo.dirstate()?;
-    dirstate.debug()?;
+    dirstate.debug(structured_format(template)?)?;
     Ok(())
 }
@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+/// The system-wide config file, read before the user's.
+pub const SYSTEM_CONFIG: &str = "/etc/hg-rs/hgrc";
+
+// `section.key=value` settings from `--config`, applied over every file.
+static OVERRIDES: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Settings read from hgrc-style files. Later files override earlier ones,
+/// and every value remembers where it was set.
+///
+/// ```text
+/// [merge-tools]
+/// kdiff3.args = $base $local $other -o $output
+/// %include ~/.hgrc.local
+/// %unset kdiff3.priority
+/// ```
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    sections: BTreeMap<String, BTreeMap<String, Item>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Item {
+    value: String,
+    source: String,
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let mut config = Config::default();
+        config.read_file(path)?;
+        Ok(config)
+    }
+
+    #[cfg(test)]
+    pub fn parse(text: &str) -> Result<Config> {
+        let mut config = Config::default();
+        config.read(text, "", None)?;
+        Ok(config)
+    }
+
+    /// Read the system, user and repository config files in that order, then
+    /// apply the `--config` overrides. `HGRCPATH` replaces the system and
+    /// user files with a `:`-separated list of files.
+    pub fn layered(repo_config: Option<&Path>) -> Result<Config> {
+        let mut config = Config::default();
+        match std::env::var_os("HGRCPATH") {
+            Some(paths) => {
+                for path in std::env::split_paths(&paths) {
+                    config.read_file(path)?;
+                }
+            }
+            None => {
+                config.read_file(SYSTEM_CONFIG)?;
+                if let Some(path) = user_config() {
+                    config.read_file(path)?;
+                }
+            }
+        }
+        if let Some(path) = repo_config {
+            config.read_file(path)?;
+        }
+        for setting in OVERRIDES.get().into_iter().flatten() {
+            config.set_override(setting)?;
+        }
+        Ok(config)
+    }
+
+    /// Remember `section.key=value` settings to apply over every layered config.
+    pub fn set_overrides(settings: Vec<String>) -> Result<()> {
+        for setting in &settings {
+            Config::default().set_override(setting)?;
+        }
+        OVERRIDES
+            .set(settings)
+            .map_err(|_| anyhow!("Config overrides were already set."))
+    }
+
+    fn set_override(&mut self, setting: &str) -> Result<()> {
+        let (name, value) = setting
+            .split_once('=')
+            .with_context(|| format!("Malformed --config option {:?}.", setting))?;
+        match name.trim().split_once('.') {
+            Some((section, key)) if !section.is_empty() && !key.is_empty() => {
+                self.insert(section, key, value.trim(), "--config");
+                Ok(())
+            }
+            _ => bail!("Malformed --config option {:?}.", setting),
+        }
+    }
+
+    fn read_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(());
+        }
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}.", path.display()))?;
+        self.read(&text, &path.display().to_string(), path.parent())
+    }
+
+    // Apply the lines of `text`, labelling values with `origin` and resolving
+    // relative `%include` paths against `directory`.
+    fn read(&mut self, text: &str, origin: &str, directory: Option<&Path>) -> Result<()> {
+        let mut section: Option<String> = None;
+        let mut last_key: Option<String> = None;
+        for (number, line) in text.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+                continue;
+            }
+            if line.starts_with(char::is_whitespace) {
+                // Indented lines continue the previous value.
+                if let (Some(section), Some(key)) = (&section, &last_key) {
+                    if let Some(item) = self
+                        .sections
+                        .get_mut(section)
+                        .and_then(|keys| keys.get_mut(key))
+                    {
+                        item.value.push('\n');
+                        item.value.push_str(trimmed);
+                        continue;
+                    }
+                }
+            }
+            if let Some(include) = trimmed.strip_prefix("%include") {
+                let include = expand_home(include.trim());
+                let include = match directory {
+                    Some(directory) => directory.join(include),
+                    None => include,
+                };
+                self.read_file(include)?;
+                continue;
+            }
+            if let Some(key) = trimmed.strip_prefix("%unset") {
+                if let Some(keys) = section.as_ref().and_then(|s| self.sections.get_mut(s)) {
+                    keys.remove(key.trim());
+                }
+                last_key = None;
+                continue;
+            }
+            if trimmed.starts_with('[') && trimmed.ends_with(']') {
+                section = Some(trimmed[1..trimmed.len() - 1].trim().to_string());
+                last_key = None;
+                continue;
+            }
+            let (key, value) = match (trimmed.find('='), &section) {
+                (Some(index), Some(_)) => (trimmed[..index].trim(), trimmed[index + 1..].trim()),
+                _ => {
+                    return Err(anyhow!(
+                        "Failed to parse config line {}: {}",
+                        number + 1,
+                        line
+                    ))
+                }
+            };
+            let section = section.as_ref().expect("Section was checked above.");
+            let source = format!("{}:{}", origin, number + 1);
+            self.insert(section, key, value, &source);
+            last_key = Some(key.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.item(section, key).map(|item| item.value.as_str())
+    }
+
+    /// A boolean value, false when unset. Accepts the spellings Mercurial
+    /// does: `1`, `yes`, `true` and `on`, or `0`, `no`, `false` and `off`.
+    pub fn get_bool(&self, section: &str, key: &str) -> Result<bool> {
+        match self
+            .get(section, key)
+            .map(str::to_ascii_lowercase)
+            .as_deref()
+        {
+            None | Some("0" | "no" | "false" | "off") => Ok(false),
+            Some("1" | "yes" | "true" | "on") => Ok(true),
+            Some(value) => bail!("{}.{} is not a boolean ({:?}).", section, key, value),
+        }
+    }
+
+    /// Where the value of `section.key` was set, as `file:line` or `--config`.
+    pub fn source(&self, section: &str, key: &str) -> Option<&str> {
+        self.item(section, key).map(|item| item.source.as_str())
+    }
+
+    fn item(&self, section: &str, key: &str) -> Option<&Item> {
+        self.sections.get(section).and_then(|keys| keys.get(key))
+    }
+
+    /// The names of all sections that have values, sorted.
+    pub fn sections(&self) -> impl Iterator<Item = &str> {
+        self.sections
+            .iter()
+            .filter(|(_section, keys)| !keys.is_empty())
+            .map(|(section, _keys)| section.as_str())
+    }
+
+    /// The keys and values of a section, sorted by key.
+    pub fn items(&self, section: &str) -> impl Iterator<Item = (&str, &str)> {
+        self.sections
+            .get(section)
+            .into_iter()
+            .flat_map(|keys| keys.iter())
+            .map(|(key, item)| (key.as_str(), item.value.as_str()))
+    }
+
+    fn insert(&mut self, section: &str, key: &str, value: &str, source: &str) {
+        self.sections
+            .entry(section.to_string())
+            .or_default()
+            .insert(
+                key.to_string(),
+                Item {
+                    value: value.to_string(),
+                    source: source.to_string(),
+                },
+            );
+    }
+
+    /// The committer name, from `HGUSER`, then `ui.username`, then `EMAIL`.
+    pub fn username(&self) -> Result<String> {
+        std::env::var("HGUSER")
+            .ok()
+            .or_else(|| self.get("ui", "username").map(String::from))
+            .or_else(|| std::env::var("EMAIL").ok())
+            .filter(|name| !name.trim().is_empty())
+            .context("No username found (set ui.username in your hgrc or HGUSER).")
+    }
+
+    /// The command used to edit text, from `HGEDITOR`, `ui.editor`, `VISUAL`
+    /// or `EDITOR`, falling back to vi.
+    pub fn editor(&self) -> String {
+        std::env::var("HGEDITOR")
+            .ok()
+            .or_else(|| self.get("ui", "editor").map(String::from))
+            .or_else(|| std::env::var("VISUAL").ok())
+            .or_else(|| std::env::var("EDITOR").ok())
+            .unwrap_or_else(|| "vi".to_string())
+    }
+}
+
+/// The user's own config file, `~/.hgrc`.
+pub fn user_config() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".hgrc"))
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let config = Config::parse(
+            "# comment\n[ui]\nmerge = :merge3\n\n[merge-tools]\nmeld.args = $local\n  $other\n",
+        )?;
+        assert_eq!(config.get("ui", "merge"), Some(":merge3"));
+        assert_eq!(
+            config.get("merge-tools", "meld.args"),
+            Some("$local\n$other")
+        );
+        assert_eq!(config.get("ui", "username"), None);
+        assert!(Config::parse("key = value").is_err());
+
+        let config = Config::parse("[format]\ntreemanifest = Yes\nother = maybe\n")?;
+        assert!(config.get_bool("format", "treemanifest")?);
+        assert!(!config.get_bool("format", "missing")?);
+        assert!(config.get_bool("format", "other").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_and_unset() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(
+            dir.path().join("extra"),
+            "[ui]\nusername = included\nverbose = true\n",
+        )?;
+        let hgrc = dir.path().join("hgrc");
+        fs::write(
+            &hgrc,
+            "[ui]\neditor = vim\n%include extra\n%unset verbose\n",
+        )?;
+        let mut config = Config::from_file(&hgrc)?;
+        assert_eq!(config.get("ui", "username"), Some("included"));
+        assert_eq!(config.get("ui", "verbose"), None);
+        assert_eq!(
+            config.source("ui", "username"),
+            Some(format!("{}:2", dir.path().join("extra").display()).as_str())
+        );
+        assert_eq!(
+            config.source("ui", "editor"),
+            Some(format!("{}:2", hgrc.display()).as_str())
+        );
+
+        config.set_override("ui.username=Jane <jane@example.com>")?;
+        assert_eq!(
+            config.get("ui", "username"),
+            Some("Jane <jane@example.com>")
+        );
+        assert_eq!(config.source("ui", "username"), Some("--config"));
+        assert!(config.set_override("ui=x").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone, Utc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+
+/// How dates are shown unless `ui.date-format` is set, as in Mercurial.
+pub const DEFAULT_DATE_FORMAT: &str = "%a %b %d %H:%M:%S %Y %z";
+
+// Date formats accepted by `parse_date`, as in Mercurial. Missing fields
+// default to the current year, today and midnight.
+const DATE_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%d",
+    "%m-%d",
+    "%m/%d",
+    "%m/%d/%y",
+    "%m/%d/%Y",
+    "%a %b %d %H:%M:%S %Y",
+    "%a %b %d %I:%M:%S%p %Y",
+    "%a, %d %b %Y %H:%M:%S",
+    "%b %d %H:%M:%S %Y",
+    "%b %d %I:%M:%S%p %Y",
+    "%b %d %H:%M:%S",
+    "%b %d %I:%M:%S%p",
+    "%b %d %H:%M",
+    "%b %d %I:%M%p",
+    "%b %d %Y",
+    "%b %d",
+    "%H:%M:%S",
+    "%I:%M:%S%p",
+    "%H:%M",
+    "%I:%M%p",
+];
+
+/// Parse a single point in time as accepted by `commit -d`: `now`, `today`,
+/// `yesterday`, `UNIXTIME OFFSET` with the offset in seconds west of UTC, or
+/// one of `DATE_FORMATS` followed by an optional timezone like `+0200`,
+/// `-05:00`, `Z` or `UTC`. Dates without a timezone are in local time.
+pub fn parse_date(text: &str) -> Result<DateTime<FixedOffset>> {
+    let text = text.trim();
+    let invalid = || anyhow!("Invalid date {:?}.", text);
+    let now = Local::now();
+    let today = now.naive_local().date();
+    let day = match text {
+        "now" => return Ok(now.with_timezone(now.offset())),
+        "today" => Some(today),
+        "yesterday" => today.pred_opt(),
+        _ => None,
+    };
+    if let Some(day) = day {
+        let midnight = day.and_hms_opt(0, 0, 0).ok_or_else(invalid)?;
+        return local_fixed(midnight);
+    }
+    if let Some((seconds, offset)) = text.split_once(' ') {
+        if let (Ok(seconds), Ok(offset)) = (seconds.parse::<i64>(), offset.parse::<i32>()) {
+            let zone = FixedOffset::west_opt(offset).ok_or_else(invalid)?;
+            return Ok(zone.timestamp(seconds, 0));
+        }
+    }
+
+    let timezone = Regex::new(r"\s*(?:([+-])(\d\d):?(\d\d)|Z|UTC|GMT)$")?;
+    let (text, zone) = match timezone.captures(text) {
+        Some(captures) => {
+            let offset = match (captures.get(1), captures.get(2), captures.get(3)) {
+                (Some(sign), Some(hours), Some(minutes)) => {
+                    let seconds = hours.as_str().parse::<i32>()? * 3600
+                        + minutes.as_str().parse::<i32>()? * 60;
+                    if sign.as_str() == "-" {
+                        -seconds
+                    } else {
+                        seconds
+                    }
+                }
+                _ => 0,
+            };
+            let start = captures.get(0).expect("The whole match exists.").start();
+            let zone = FixedOffset::east_opt(offset).ok_or_else(invalid)?;
+            (text[..start].trim(), Some(zone))
+        }
+        None => (text, None),
+    };
+
+    for format in DATE_FORMATS {
+        let mut text = text.to_string();
+        let mut format = format.to_string();
+        if !format.contains("%Y") && !format.contains("%y") {
+            if format.contains("%d") {
+                text.push_str(&format!(" {}", today.year()));
+                format.push_str(" %Y");
+            } else {
+                text.push_str(&format!(" {}", today.format("%Y-%m-%d")));
+                format.push_str(" %Y-%m-%d");
+            }
+        }
+        if !format.contains("%H") && !format.contains("%I") {
+            text.push_str(" 00:00:00");
+            format.push_str(" %H:%M:%S");
+        }
+        if let Ok(time) = NaiveDateTime::parse_from_str(&text, &format) {
+            return match zone {
+                Some(zone) => zone.from_local_datetime(&time).single().ok_or_else(invalid),
+                None => local_fixed(time),
+            };
+        }
+    }
+    bail!("Invalid date {:?}.", text)
+}
+
+fn local_fixed(time: NaiveDateTime) -> Result<DateTime<FixedOffset>> {
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map(|time| time.with_timezone(time.offset()))
+        .ok_or_else(|| anyhow!("Invalid local time {}.", time))
+}
+
+/// An inclusive range of times. A missing bound is unbounded.
+#[derive(Debug, PartialEq)]
+pub struct DateRange {
+    pub start: Option<DateTime<Utc>>,
+    pub end: Option<DateTime<Utc>>,
+}
+
+impl DateRange {
+    /// Parse a date range in one of the forms
+    /// - `DATE`, every time within the precision of DATE (e.g. a whole day),
+    /// - `<DATE`, at or before DATE,
+    /// - `>DATE`, at or after DATE,
+    /// - `DATE to DATE`, between both dates, and
+    /// - `-DAYS`, within the last number of days.
+    pub fn parse(spec: &str) -> Result<DateRange> {
+        let spec = spec.trim();
+        if let Some(date) = spec.strip_prefix('<') {
+            let (_start, end) = bounds(date)?;
+            Ok(DateRange {
+                start: None,
+                end: Some(end),
+            })
+        } else if let Some(date) = spec.strip_prefix('>') {
+            let (start, _end) = bounds(date)?;
+            Ok(DateRange {
+                start: Some(start),
+                end: None,
+            })
+        } else if let Some(days) = spec.strip_prefix('-') {
+            let days: i64 = days
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid day count in {:?}.", spec))?;
+            Ok(DateRange {
+                start: Some(Utc::now() - Duration::days(days)),
+                end: None,
+            })
+        } else if let Some((from, to)) = spec.split_once(" to ") {
+            Ok(DateRange {
+                start: Some(bounds(from)?.0),
+                end: Some(bounds(to)?.1),
+            })
+        } else {
+            let (start, end) = bounds(spec)?;
+            Ok(DateRange {
+                start: Some(start),
+                end: Some(end),
+            })
+        }
+    }
+
+    pub fn contains(&self, time: &DateTime<Utc>) -> bool {
+        self.start.is_none_or(|start| start <= *time) && self.end.is_none_or(|end| *time <= end)
+    }
+}
+
+// The earliest and latest times a date written with limited precision,
+// like `2020-06` or `2020-06-22 10:30`, could refer to. Dates without a
+// timezone are in local time.
+fn bounds(text: &str) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let text = text.trim();
+    let invalid = || anyhow!("Invalid date {:?}.", text);
+    let today = Local::now().naive_local().date();
+    let day = match text {
+        "now" => {
+            let now = Utc::now();
+            return Ok((now, now));
+        }
+        "today" => Some(today),
+        "yesterday" => today.pred_opt(),
+        _ => None,
+    };
+    if let Some(day) = day {
+        let start = day.and_hms_opt(0, 0, 0).ok_or_else(invalid)?;
+        return Ok((
+            local(start)?,
+            local(start + Duration::days(1))? - Duration::seconds(1),
+        ));
+    }
+
+    let (date, time) = match text.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time.trim())),
+        None => (text, None),
+    };
+    let mut date_parts = date.split('-').map(|part| part.parse::<u32>());
+    let year = date_parts
+        .next()
+        .ok_or_else(invalid)?
+        .map_err(|_| invalid())? as i32;
+    let month = date_parts.next().transpose().map_err(|_| invalid())?;
+    let day = date_parts.next().transpose().map_err(|_| invalid())?;
+    if date_parts.next().is_some() || (time.is_some() && day.is_none()) {
+        return Err(invalid());
+    }
+    let mut time_parts = time
+        .map(|time| time.split(':').map(|part| part.parse::<u32>()).collect())
+        .unwrap_or_else(|| Ok(Vec::new()))
+        .map_err(|_| invalid())?
+        .into_iter();
+    let (hour, minute, second) = (time_parts.next(), time_parts.next(), time_parts.next());
+
+    let start = NaiveDate::from_ymd_opt(year, month.unwrap_or(1), day.unwrap_or(1))
+        .and_then(|date| {
+            date.and_hms_opt(hour.unwrap_or(0), minute.unwrap_or(0), second.unwrap_or(0))
+        })
+        .ok_or_else(invalid)?;
+    // Advance by one unit of the least significant field that was given.
+    let next = if second.is_some() {
+        start + Duration::seconds(1)
+    } else if minute.is_some() {
+        start + Duration::minutes(1)
+    } else if hour.is_some() {
+        start + Duration::hours(1)
+    } else if day.is_some() {
+        start + Duration::days(1)
+    } else if let Some(month) = month {
+        let (year, month) = if month == 12 {
+            (year + 1, 1)
+        } else {
+            (year, month + 1)
+        };
+        NaiveDate::from_ymd_opt(year, month, 1)
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .ok_or_else(invalid)?
+    } else {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1)
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .ok_or_else(invalid)?
+    };
+    Ok((local(start)?, local(next)? - Duration::seconds(1)))
+}
+
+fn local(time: chrono::NaiveDateTime) -> Result<DateTime<Utc>> {
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| anyhow!("Invalid local time {}.", time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_of(text: &str) -> Result<DateTime<Utc>> {
+        Ok(bounds(text)?.0)
+    }
+
+    #[test]
+    fn test_date_range() -> Result<()> {
+        let range = DateRange::parse("2020-06")?;
+        assert!(range.contains(&start_of("2020-06-30 23:59:59")?));
+        assert!(!range.contains(&start_of("2020-07-01")?));
+
+        let range = DateRange::parse("2020-06-22 to 2020-06-23")?;
+        assert!(range.contains(&start_of("2020-06-23 12:00")?));
+        assert!(!range.contains(&start_of("2020-06-21 12:00")?));
+
+        assert!(DateRange::parse("<2020")?.contains(&start_of("2019-01-01")?));
+        assert!(DateRange::parse("-1")?.contains(&Utc::now()));
+        assert!(DateRange::parse("2020-13-01").is_err());
+        assert!(DateRange::parse("next week").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_date() -> Result<()> {
+        let date = parse_date("2006-02-01 13:00:30 +0100")?;
+        assert_eq!(date.to_rfc3339(), "2006-02-01T13:00:30+01:00");
+        let date = parse_date("Mon Jan 02 15:04:05 2006 -07:00")?;
+        assert_eq!(date.to_rfc3339(), "2006-01-02T15:04:05-07:00");
+        let date = parse_date("1165432709 -3600")?;
+        assert_eq!(date.to_rfc3339(), "2006-12-06T20:18:29+01:00");
+        let date = parse_date("Dec 6 2006 UTC")?;
+        assert_eq!(date.to_rfc3339(), "2006-12-06T00:00:00+00:00");
+        let date = parse_date("12/6/2006 Z")?;
+        assert_eq!(date.to_rfc3339(), "2006-12-06T00:00:00+00:00");
+        assert_eq!(
+            parse_date("2020-06-22")?.with_timezone(&Utc),
+            start_of("2020-06-22")?
+        );
+        assert!(parse_date("Dec 6 10:30 +0000")?.year() >= 2020);
+        assert!(parse_date("next week").is_err());
+        Ok(())
+    }
+}
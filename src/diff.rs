@@ -0,0 +1,244 @@
+/// A run of `length` equal elements starting at `a_start` in `a` and `b_start` in `b`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Block {
+    pub a_start: usize,
+    pub b_start: usize,
+    pub length: usize,
+}
+
+/// Split bytes into lines, keeping the trailing newline of each line.
+pub fn split_lines(bytes: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        if *byte == b'\n' {
+            lines.push(&bytes[start..=i]);
+            start = i + 1;
+        }
+    }
+    if start < bytes.len() {
+        lines.push(&bytes[start..]);
+    }
+    lines
+}
+
+/// Return the blocks that `a` and `b` have in common, in increasing order.
+/// The last block is always an empty sentinel at the end of both sequences.
+pub fn matching_blocks<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    for (x, y) in common_pairs(a, b) {
+        match blocks.last_mut() {
+            Some(block)
+                if block.a_start + block.length == x && block.b_start + block.length == y =>
+            {
+                block.length += 1
+            }
+            _ => blocks.push(Block {
+                a_start: x,
+                b_start: y,
+                length: 1,
+            }),
+        }
+    }
+    blocks.push(Block {
+        a_start: a.len(),
+        b_start: b.len(),
+        length: 0,
+    });
+    blocks
+}
+
+// Ranges `(a_start, a_end, b_start, b_end)` where `a` and `b` differ.
+fn changes<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize, usize, usize)> {
+    let mut changes = Vec::new();
+    let (mut a_pos, mut b_pos) = (0, 0);
+    for block in matching_blocks(a, b) {
+        if a_pos < block.a_start || b_pos < block.b_start {
+            changes.push((a_pos, block.a_start, b_pos, block.b_start));
+        }
+        a_pos = block.a_start + block.length;
+        b_pos = block.b_start + block.length;
+    }
+    changes
+}
+
+/// Count the lines added and removed going from `a` to `b`.
+pub fn line_changes(a: &[u8], b: &[u8]) -> (usize, usize) {
+    changes(&split_lines(a), &split_lines(b)).iter().fold(
+        (0, 0),
+        |(added, removed), (a_start, a_end, b_start, b_end)| {
+            (added + b_end - b_start, removed + a_end - a_start)
+        },
+    )
+}
+
+/// Format the differences between `a` and `b` as unified diff hunks, each
+/// with up to `context` unchanged lines around the changes.
+pub fn unified_hunks(a: &[u8], b: &[u8], context: usize) -> Vec<u8> {
+    let a_lines = split_lines(a);
+    let b_lines = split_lines(b);
+    let changes = changes(&a_lines, &b_lines);
+
+    // Group changes whose surrounding context would overlap into one hunk.
+    let mut groups: Vec<&[(usize, usize, usize, usize)]> = Vec::new();
+    let mut group_start = 0;
+    for i in 1..=changes.len() {
+        if i == changes.len() || changes[i].0 - changes[i - 1].1 > 2 * context {
+            groups.push(&changes[group_start..i]);
+            group_start = i;
+        }
+    }
+
+    let mut out = Vec::new();
+    let write_line = |out: &mut Vec<u8>, prefix: u8, line: &[u8]| {
+        out.push(prefix);
+        out.extend_from_slice(line);
+        if !line.ends_with(b"\n") {
+            out.extend_from_slice(b"\n\\ No newline at end of file\n");
+        }
+    };
+    for group in groups.iter().filter(|group| !group.is_empty()) {
+        let first = group[0];
+        let last = group[group.len() - 1];
+        let a_start = first.0.saturating_sub(context);
+        let a_end = (last.1 + context).min(a_lines.len());
+        let b_start = first.2 - (first.0 - a_start);
+        let b_end = last.3 + (a_end - last.1);
+        let start_line = |start: usize, length: usize| if length == 0 { start } else { start + 1 };
+        out.extend_from_slice(
+            format!(
+                "@@ -{},{} +{},{} @@\n",
+                start_line(a_start, a_end - a_start),
+                a_end - a_start,
+                start_line(b_start, b_end - b_start),
+                b_end - b_start
+            )
+            .as_bytes(),
+        );
+        let mut a_pos = a_start;
+        for (change_a_start, change_a_end, change_b_start, change_b_end) in group.iter() {
+            for line in &a_lines[a_pos..*change_a_start] {
+                write_line(&mut out, b' ', line);
+            }
+            for line in &a_lines[*change_a_start..*change_a_end] {
+                write_line(&mut out, b'-', line);
+            }
+            for line in &b_lines[*change_b_start..*change_b_end] {
+                write_line(&mut out, b'+', line);
+            }
+            a_pos = *change_a_end;
+        }
+        for line in &a_lines[a_pos..a_end] {
+            write_line(&mut out, b' ', line);
+        }
+    }
+    out
+}
+
+// Indices of the elements in a longest common subsequence, computed with
+// Myers' O(ND) algorithm.
+fn common_pairs<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let max = n + m;
+    let offset = max as usize + 1;
+    let mut v = vec![0isize; 2 * offset + 1];
+    let mut trace = Vec::new();
+    'search: for d in 0..=max {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let index = (k + offset as isize) as usize;
+            let mut x = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+                v[index + 1]
+            } else {
+                v[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let index = (k + offset as isize) as usize;
+        let prev_k = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[(prev_k + offset as isize) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            pairs.push((x as usize, y as usize));
+        }
+        if d > 0 {
+            x = prev_x;
+            y = prev_y;
+        }
+    }
+    pairs.reverse();
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_lines() {
+        assert_eq!(split_lines(b"a\nb\nc"), vec![&b"a\n"[..], b"b\n", b"c"]);
+        assert!(split_lines(b"").is_empty());
+    }
+
+    #[test]
+    fn test_matching_blocks() {
+        let a = ["a", "b", "c", "d"];
+        let b = ["a", "x", "c", "d", "e"];
+        assert_eq!(
+            matching_blocks(&a, &b),
+            vec![
+                Block {
+                    a_start: 0,
+                    b_start: 0,
+                    length: 1
+                },
+                Block {
+                    a_start: 2,
+                    b_start: 2,
+                    length: 2
+                },
+                Block {
+                    a_start: 4,
+                    b_start: 5,
+                    length: 0
+                },
+            ]
+        );
+        assert_eq!(matching_blocks::<u8>(&[], &[]).len(), 1);
+    }
+
+    #[test]
+    fn test_unified_hunks() {
+        let a = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let b = b"1\n2\nthree\n4\n5\n6\n7\n8\n9\n10";
+        assert_eq!(
+            String::from_utf8(unified_hunks(a, b, 1)).unwrap(),
+            "@@ -2,3 +2,3 @@\n 2\n-3\n+three\n 4\n\
+             @@ -9,1 +9,2 @@\n 9\n+10\n\\ No newline at end of file\n"
+        );
+        assert_eq!(line_changes(a, b), (2, 1));
+        assert!(unified_hunks(a, a, 3).is_empty());
+    }
+}
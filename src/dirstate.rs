@@ -1,12 +1,21 @@
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs::OpenOptions;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+use crate::formatter::Format;
+use crate::manifest::Flag;
+use crate::repository::write_atomic;
+use crate::worker;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Status {
     Normal,
     Merged,
@@ -14,7 +23,7 @@ pub enum Status {
     Removed,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Entry {
     pub status: Status,
     pub mode: u32,
@@ -22,11 +31,166 @@ pub struct Entry {
     pub mtime: SystemTime,
 }
 
+impl Entry {
+    pub fn from_file<P: AsRef<Path>>(path: P, status: Status) -> Result<Entry> {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Symlinks are tracked themselves rather than the files they point to.
+        let metadata = std::fs::symlink_metadata(path)?;
+        Ok(Entry {
+            status,
+            mode: metadata.permissions().mode(),
+            size: metadata.len(),
+            mtime: metadata.modified()?,
+        })
+    }
+
+    /// The manifest flag for the recorded file type and permissions.
+    pub fn flag(&self) -> Option<Flag> {
+        flag_for_mode(self.mode)
+    }
+
+    // Whether the file on disk still has the recorded size, modification time
+    // and manifest flag.
+    pub fn matches_file<P: AsRef<Path>>(&self, path: P) -> bool {
+        use std::os::unix::fs::PermissionsExt;
+
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) => {
+                metadata.len() == self.size
+                    && flag_for_mode(metadata.permissions().mode()) == self.flag()
+                    && metadata
+                        .modified()
+                        .map(|mtime| mtime == self.mtime)
+                        .unwrap_or(false)
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Whether a file exists, counting symlinks whose target does not.
+pub fn lexists<P: AsRef<Path>>(path: P) -> bool {
+    std::fs::symlink_metadata(path).is_ok()
+}
+
+// Symlinks get the `l` flag and files executable by their owner get `x`.
+fn flag_for_mode(mode: u32) -> Option<Flag> {
+    const FILE_TYPE: u32 = 0o170000;
+    const SYMLINK: u32 = 0o120000;
+    if mode & FILE_TYPE == SYMLINK {
+        Some(Flag::Symlink)
+    } else if mode & 0o100 != 0 {
+        Some(Flag::Executable)
+    } else {
+        None
+    }
+}
+
+// A v2 dirstate file starts with this, followed by its docket.
+const DOCKET_MAGIC: &[u8] = b"dirstate-v2\n";
+
+// The v2 dirstate file, which points at a data file of directory nodes.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Docket {
+    parent1_hash: [u8; 20],
+    parent2_hash: [u8; 20],
+    // The data file is `dirstate.<data_id>`.
+    data_id: String,
+    // The bytes of the data file in use, which may be followed by a
+    // partial write.
+    data_size: u64,
+    // The bytes of nodes that newer nodes replaced.
+    unreachable: u64,
+    root: NodeRef,
+}
+
+// Where a node is in the data file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct NodeRef {
+    offset: u64,
+    len: u64,
+}
+
+// A directory in the data file: its tracked files and its subdirectories
+// with tracked files, by name.
+#[derive(Serialize, Deserialize)]
+struct Node {
+    files: Vec<(OsString, Entry)>,
+    directories: Vec<(OsString, NodeRef)>,
+    cached: Option<CachedDirectory>,
+}
+
+#[derive(Debug, PartialEq)]
+struct OnDisk {
+    docket: Docket,
+    // Each directory's node and the digest of its bytes.
+    nodes: HashMap<PathBuf, (NodeRef, [u8; 20])>,
+}
+
+fn data_path(docket_path: &Path, data_id: &str) -> PathBuf {
+    let mut name = docket_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", data_id));
+    docket_path.with_file_name(name)
+}
+
+fn digest(bytes: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.input(bytes);
+    hasher.result().into()
+}
+
+// A dirstate entry as shown by `debugdirstate`.
+#[derive(Serialize)]
+struct DebugEntry<'a> {
+    path: &'a str,
+    status: &'a Status,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+}
+
 #[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
 pub struct Dirstate {
     pub parent1_hash: [u8; 20],
-    parent2_hash: [u8; 20],
+    pub parent2_hash: [u8; 20],
     entries: HashMap<PathBuf, Entry>,
+    // Directories whose listing is known from the entries, which only the
+    // v2 format stores.
+    #[serde(skip)]
+    directories: HashMap<PathBuf, CachedDirectory>,
+    // Where a v2 dirstate was read from, so that writing it back only
+    // appends the directories that changed.
+    #[serde(skip)]
+    on_disk: Option<OnDisk>,
+}
+
+/// A directory whose children were all tracked files or directories with
+/// tracked files when it had modification time `mtime`. Until that changes,
+/// walks can list it from the dirstate instead of reading it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CachedDirectory {
+    pub mtime: SystemTime,
+    // The `Children::fingerprint` of the directory then, as the entries may
+    // have changed since.
+    pub fingerprint: u64,
+}
+
+/// The tracked files directly in a directory and its subdirectories that
+/// contain tracked files, by name.
+#[derive(Debug, Default)]
+pub struct Children {
+    pub files: BTreeSet<OsString>,
+    pub directories: BTreeSet<OsString>,
+}
+
+impl Children {
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.files.hash(&mut hasher);
+        self.directories.hash(&mut hasher);
+        hasher.finish()
+    }
 }
 
 impl Dirstate {
@@ -41,8 +205,171 @@ impl Dirstate {
     }
 
     pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let file = OpenOptions::new().write(true).create(true).open(path)?;
-        bincode::serialize_into(file, &self)?;
+        write_atomic(path, bincode::serialize(&self)?)
+    }
+
+    /// Read a v2 dirstate from its docket, `path`, and the data file that the
+    /// docket names.
+    pub fn from_v2_file<P: AsRef<Path>>(path: P) -> Result<Dirstate> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Dirstate::default());
+        }
+        let docket: Docket = match std::fs::read(path)?.strip_prefix(DOCKET_MAGIC) {
+            Some(bytes) => bincode::deserialize(bytes)?,
+            None => bail!("{} is not a v2 dirstate.", path.display()),
+        };
+        let data = std::fs::read(data_path(path, &docket.data_id))?;
+        let data = data
+            .get(..docket.data_size as usize)
+            .context("Dirstate data file is truncated.")?;
+        let mut dirstate = Dirstate {
+            parent1_hash: docket.parent1_hash,
+            parent2_hash: docket.parent2_hash,
+            ..Dirstate::default()
+        };
+        let mut nodes = HashMap::new();
+        let mut stack = vec![(PathBuf::new(), docket.root)];
+        while let Some((directory, node_ref)) = stack.pop() {
+            let bytes = data
+                .get(node_ref.offset as usize..(node_ref.offset + node_ref.len) as usize)
+                .context("Dirstate node is out of bounds.")?;
+            let node: Node = bincode::deserialize(bytes)?;
+            for (name, entry) in node.files {
+                dirstate.entries.insert(directory.join(name), entry);
+            }
+            for (name, child) in node.directories {
+                stack.push((directory.join(name), child));
+            }
+            if let Some(cached) = node.cached {
+                dirstate.directories.insert(directory.clone(), cached);
+            }
+            nodes.insert(directory, (node_ref, digest(bytes)));
+        }
+        dirstate.on_disk = Some(OnDisk { docket, nodes });
+        Ok(dirstate)
+    }
+
+    /// Write a v2 dirstate. Directories are appended to the data file only if
+    /// they or a directory under them changed since the dirstate was read,
+    /// until most of the data file is unreachable and it is rewritten.
+    pub fn write_to_v2_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        use std::io::Write;
+
+        let path = path.as_ref();
+        let previous = self
+            .on_disk
+            .as_ref()
+            .filter(|on_disk| on_disk.docket.unreachable * 2 <= on_disk.docket.data_size);
+        let (data_id, data_size, mut unreachable, mut file) = match previous {
+            Some(OnDisk { docket, .. }) => {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .open(data_path(path, &docket.data_id))?;
+                // Drop whatever an interrupted write left after the data in use.
+                file.set_len(docket.data_size)?;
+                (
+                    docket.data_id.clone(),
+                    docket.data_size,
+                    docket.unreachable,
+                    file,
+                )
+            }
+            None => {
+                let data_id = format!(
+                    "{:x}{:x}",
+                    SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)?
+                        .as_nanos(),
+                    std::process::id()
+                );
+                let file = std::fs::File::create(data_path(path, &data_id))?;
+                (data_id, 0, 0, file)
+            }
+        };
+        let old_nodes = previous.map(|on_disk| &on_disk.nodes);
+
+        let mut children = self.children();
+        children.entry(PathBuf::new()).or_default();
+        // Write directories before the directories containing them.
+        let mut directories: Vec<&PathBuf> = children.keys().collect();
+        directories.sort_by_key(|directory| std::cmp::Reverse(directory.components().count()));
+        let mut appended = Vec::new();
+        let mut refs: HashMap<&Path, NodeRef> = HashMap::new();
+        for directory in directories {
+            let names = &children[directory];
+            let node = Node {
+                files: names
+                    .files
+                    .iter()
+                    .map(|name| (name.clone(), self.entries[&directory.join(name)].clone()))
+                    .collect(),
+                directories: names
+                    .directories
+                    .iter()
+                    .map(|name| (name.clone(), refs[directory.join(name).as_path()]))
+                    .collect(),
+                cached: self.directories.get(directory).cloned(),
+            };
+            let bytes = bincode::serialize(&node)?;
+            let old = old_nodes.and_then(|nodes| nodes.get(directory));
+            let node_ref = match old {
+                Some((node_ref, old_digest)) if *old_digest == digest(&bytes) => *node_ref,
+                _ => {
+                    unreachable += old.map_or(0, |(node_ref, _digest)| node_ref.len);
+                    let node_ref = NodeRef {
+                        offset: data_size + appended.len() as u64,
+                        len: bytes.len() as u64,
+                    };
+                    appended.extend(bytes);
+                    node_ref
+                }
+            };
+            refs.insert(directory, node_ref);
+        }
+        for (directory, (node_ref, _digest)) in old_nodes.into_iter().flatten() {
+            if !children.contains_key(directory) {
+                unreachable += node_ref.len;
+            }
+        }
+        file.write_all(&appended)?;
+        file.sync_data()?;
+
+        let docket = Docket {
+            parent1_hash: self.parent1_hash,
+            parent2_hash: self.parent2_hash,
+            data_id,
+            data_size: data_size + appended.len() as u64,
+            unreachable,
+            root: refs[Path::new("")],
+        };
+        let mut bytes = DOCKET_MAGIC.to_vec();
+        bytes.extend(bincode::serialize(&docket)?);
+        write_atomic(path, bytes)?;
+        // After compacting, keep the data file of the previous docket, which
+        // the repository keeps as a backup, and remove older ones.
+        if previous.is_none() {
+            let keep = [
+                Some(data_path(path, &docket.data_id)),
+                self.on_disk
+                    .as_ref()
+                    .map(|on_disk| data_path(path, &on_disk.docket.data_id)),
+            ];
+            let mut prefix = path.file_name().unwrap_or_default().to_os_string();
+            prefix.push(".");
+            let prefix = prefix.to_string_lossy().into_owned();
+            let directory = path.parent().unwrap_or_else(|| Path::new("."));
+            for dir_entry in std::fs::read_dir(directory)? {
+                let data = dir_entry?.path();
+                let name = data.file_name().unwrap_or_default().to_string_lossy();
+                if name.starts_with(&prefix)
+                    && !name.ends_with(".tmp")
+                    && !keep.contains(&Some(data.clone()))
+                {
+                    std::fs::remove_file(&data)?;
+                }
+            }
+        }
         Ok(())
     }
 
@@ -54,40 +381,109 @@ impl Dirstate {
         &mut self.entries
     }
 
+    pub fn cached_directories(&self) -> &HashMap<PathBuf, CachedDirectory> {
+        &self.directories
+    }
+
+    pub fn set_cached_directories(&mut self, directories: HashMap<PathBuf, CachedDirectory>) {
+        self.directories = directories;
+    }
+
+    /// The children of every directory with tracked files under it, keyed by
+    /// directory relative to the root.
+    pub fn children(&self) -> HashMap<PathBuf, Children> {
+        let mut children: HashMap<PathBuf, Children> = HashMap::new();
+        for path in self.entries.keys() {
+            let (parent, name) = match (path.parent(), path.file_name()) {
+                (Some(parent), Some(name)) => (parent, name),
+                _ => continue,
+            };
+            children
+                .entry(parent.to_path_buf())
+                .or_default()
+                .files
+                .insert(name.to_os_string());
+            for directory in parent.ancestors() {
+                let (parent, name) = match (directory.parent(), directory.file_name()) {
+                    (Some(parent), Some(name)) => (parent, name),
+                    _ => break,
+                };
+                let siblings = &mut children
+                    .entry(parent.to_path_buf())
+                    .or_default()
+                    .directories;
+                if !siblings.insert(name.to_os_string()) {
+                    break;
+                }
+            }
+        }
+        children
+    }
+
     /// Return the files that should be included in the new commit.
     /// Qualifying files are
     /// - added,
     /// - removed,
     /// - merged, and
     /// - normal files with different modification time or size.
-    pub fn committable_files(&mut self) -> Vec<(&PathBuf, &mut Entry)> {
-        self.entries
-            .iter_mut()
-            .filter_map(|(path, entry)| match entry.status {
-                Status::Added | Status::Removed => Some((path, entry)),
-                Status::Normal => {
-                    let file = OpenOptions::new().read(true).open(&path).ok()?;
-                    let metadata = file.metadata().ok()?;
-                    if metadata.len() != entry.size || metadata.modified().ok()? != entry.mtime {
-                        Some((path, entry))
-                    } else {
-                        None
-                    }
-                }
-                Status::Merged => unimplemented!("Merge is unimplemented."),
+    ///
+    /// Normal files are stat'ed under `root` on up to `workers` threads, and
+    /// only those in `candidates` if given, which others are known not to
+    /// have changed.
+    pub fn committable_files(
+        &mut self,
+        root: &Path,
+        workers: usize,
+        candidates: Option<&BTreeSet<PathBuf>>,
+    ) -> Result<Vec<(&PathBuf, &mut Entry)>> {
+        let normal: Vec<(&PathBuf, &Entry)> = self
+            .entries
+            .iter()
+            .filter(|(path, entry)| {
+                entry.status == Status::Normal
+                    && candidates.is_none_or(|candidates| candidates.contains(*path))
             })
-            .collect()
-    }
-
-    pub fn debug(&self) -> Result<()> {
-        for (name, entry) in self.entries.iter() {
-            println!(
-                "{:?}  {}  {}",
-                entry.status,
-                entry.size,
-                name.to_str()
-                    .context("Failed to convert PathBuf to string.")?
-            );
+            .collect();
+        let modified = worker::map(workers, &normal, |(path, entry)| {
+            let file_path = root.join(path);
+            Ok(lexists(&file_path) && !entry.matches_file(&file_path))
+        })?;
+        let modified: HashSet<PathBuf> = normal
+            .into_iter()
+            .zip(modified)
+            .filter(|(_normal, modified)| *modified)
+            .map(|((path, _entry), _modified)| path.clone())
+            .collect();
+        Ok(self
+            .entries
+            .iter_mut()
+            .filter(|(path, entry)| entry.status != Status::Normal || modified.contains(*path))
+            .collect())
+    }
+
+    pub fn debug(&self, format: Option<Format>) -> Result<()> {
+        let mut records = Vec::new();
+        for (path, entry) in &self.entries {
+            let mtime = entry
+                .mtime
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs());
+            records.push(DebugEntry {
+                path: path
+                    .to_str()
+                    .context("Failed to convert PathBuf to string.")?,
+                status: &entry.status,
+                mode: entry.mode,
+                size: entry.size,
+                mtime,
+            });
+        }
+        records.sort_by_key(|record| record.path);
+        if let Some(format) = format {
+            return format.write(&records);
+        }
+        for record in records {
+            println!("{:?}  {}  {}", record.status, record.size, record.path);
         }
         Ok(())
     }
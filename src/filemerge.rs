@@ -0,0 +1,222 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::config::Config;
+use crate::manifest::Flag;
+use crate::simplemerge::{self, Style};
+
+const DEFAULT_TOOL: &str = ":merge";
+const DEFAULT_ARGS: &str = "$local $base $other";
+
+/// A strategy for merging the conflicting versions of a file.
+#[derive(Debug, PartialEq)]
+pub enum Tool {
+    /// Keep the local version.
+    Local,
+    /// Take the other version.
+    Other,
+    /// Concatenate both sides of each conflict.
+    Union,
+    /// Leave conflict markers in the file.
+    Merge,
+    /// Leave conflict markers that include the base version in the file.
+    Merge3,
+    /// Keep the local version and leave the file unresolved.
+    Fail,
+    /// Run an external program configured under `[merge-tools]`.
+    External { executable: String, args: String },
+}
+
+impl Tool {
+    /// Look up a tool by name. Names starting with `:` are internal tools;
+    /// anything else is an external tool whose `executable` and `args` may be
+    /// set in the `[merge-tools]` section of the config.
+    pub fn from_name(name: &str, config: &Config) -> Result<Tool> {
+        match name {
+            ":local" => Ok(Tool::Local),
+            ":other" => Ok(Tool::Other),
+            ":union" => Ok(Tool::Union),
+            ":merge" => Ok(Tool::Merge),
+            ":merge3" => Ok(Tool::Merge3),
+            ":fail" => Ok(Tool::Fail),
+            _ if name.starts_with(':') => Err(anyhow!("Unknown internal merge tool {}.", name)),
+            _ => Ok(Tool::External {
+                executable: config
+                    .get("merge-tools", &format!("{}.executable", name))
+                    .unwrap_or(name)
+                    .to_string(),
+                args: config
+                    .get("merge-tools", &format!("{}.args", name))
+                    .unwrap_or(DEFAULT_ARGS)
+                    .to_string(),
+            }),
+        }
+    }
+
+    /// The tool named by `--tool`, falling back to `ui.merge` and then `:merge`.
+    pub fn select(name: Option<&str>, config: &Config) -> Result<Tool> {
+        let name = name
+            .or_else(|| config.get("ui", "merge"))
+            .unwrap_or(DEFAULT_TOOL);
+        Tool::from_name(name, config)
+    }
+}
+
+/// Merge the local, base and other versions of a file into `output`.
+/// Return whether the file is resolved.
+pub fn filemerge<P: AsRef<Path>>(
+    tool: &Tool,
+    output: P,
+    local: &[u8],
+    base: &[u8],
+    other: &[u8],
+) -> Result<bool> {
+    let output = output.as_ref();
+    let style = match tool {
+        Tool::Local => return write(output, local).map(|_| true),
+        Tool::Other => return write(output, other).map(|_| true),
+        Tool::Fail => return write(output, local).map(|_| false),
+        Tool::External { executable, args } => {
+            return external(executable, args, output, local, base, other)
+        }
+        Tool::Union => Style::Union,
+        Tool::Merge => Style::Merge,
+        Tool::Merge3 => Style::Merge3,
+    };
+    if [local, base, other].iter().any(|bytes| is_binary(bytes)) {
+        println!("warning: {} looks like a binary file.", output.display());
+        return write(output, local).map(|_| false);
+    }
+    let result = simplemerge::merge(base, local, other, style);
+    write(output, &result.text)?;
+    Ok(!result.conflicts)
+}
+
+/// Merge the flags of a file three ways, as its contents are: a side that
+/// kept the base flag takes the other side's. Returns None when both sides
+/// changed the flag differently.
+pub fn merge_flags(
+    local: Option<Flag>,
+    base: Option<Flag>,
+    other: Option<Flag>,
+) -> Option<Option<Flag>> {
+    if local == other || other == base {
+        Some(local)
+    } else if local == base {
+        Some(other)
+    } else {
+        None
+    }
+}
+
+// Run an external merge tool. As in Mercurial, `$local` is the working copy
+// file itself, which the tool edits in place unless it writes to `$output`,
+// the same file.
+fn external(
+    executable: &str,
+    args: &str,
+    output: &Path,
+    local: &[u8],
+    base: &[u8],
+    other: &[u8],
+) -> Result<bool> {
+    let temp_dir = tempfile::tempdir()?;
+    let file_name = output
+        .file_name()
+        .context("Failed to get file name.")?
+        .to_string_lossy();
+    let version_path = |version: &str| temp_dir.path().join(format!("{}~{}", file_name, version));
+    let (base_path, other_path) = (version_path("base"), version_path("other"));
+    write(&base_path, base)?;
+    write(&other_path, other)?;
+    write(output, local)?;
+
+    let args: Vec<String> = args
+        .split_whitespace()
+        .map(|arg| {
+            arg.replace("$local", &output.to_string_lossy())
+                .replace("$base", &base_path.to_string_lossy())
+                .replace("$other", &other_path.to_string_lossy())
+                .replace("$output", &output.to_string_lossy())
+        })
+        .collect();
+    let status = Command::new(executable)
+        .args(&args)
+        .status()
+        .with_context(|| format!("Failed to run merge tool {}.", executable))?;
+    Ok(status.success())
+}
+
+fn write(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes.contains(&0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_name() -> Result<()> {
+        let config = Config::parse("[merge-tools]\nmeld.args = $local $output\n")?;
+        assert_eq!(Tool::from_name(":union", &config)?, Tool::Union);
+        assert!(Tool::from_name(":bogus", &config).is_err());
+        assert_eq!(
+            Tool::from_name("meld", &config)?,
+            Tool::External {
+                executable: "meld".to_string(),
+                args: "$local $output".to_string(),
+            }
+        );
+        assert_eq!(Tool::select(None, &config)?, Tool::Merge);
+        Ok(())
+    }
+
+    #[test]
+    fn test_filemerge() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let output = base_dir.path().join("file");
+        assert!(filemerge(&Tool::Merge, &output, b"b\n", b"a\n", b"a\n")?);
+        assert_eq!(fs::read(&output)?, b"b\n");
+        assert!(!filemerge(&Tool::Merge, &output, b"b\n", b"a\n", b"c\n")?);
+        assert!(filemerge(&Tool::Other, &output, b"b\n", b"a\n", b"c\n")?);
+        assert_eq!(fs::read(&output)?, b"c\n");
+        assert!(!filemerge(&Tool::Fail, &output, b"b\n", b"a\n", b"c\n")?);
+        assert_eq!(fs::read(&output)?, b"b\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_flags() {
+        let (exec, link) = (Some(Flag::Executable), Some(Flag::Symlink));
+        assert_eq!(merge_flags(exec, None, None), Some(exec));
+        assert_eq!(merge_flags(None, None, exec), Some(exec));
+        assert_eq!(merge_flags(None, exec, exec), Some(None));
+        assert_eq!(merge_flags(exec, None, exec), Some(exec));
+        assert_eq!(merge_flags(exec, None, link), None);
+    }
+
+    #[test]
+    fn test_external() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let output = base_dir.path().join("file");
+        // A tool that writes its result over `$local`.
+        let tool = Tool::External {
+            executable: "cp".to_string(),
+            args: "$other $local".to_string(),
+        };
+        assert!(filemerge(&tool, &output, b"b\n", b"a\n", b"c\n")?);
+        assert_eq!(fs::read(&output)?, b"c\n");
+        Ok(())
+    }
+}
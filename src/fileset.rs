@@ -0,0 +1,524 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use regex::bytes::Regex;
+
+use crate::dirstate::{lexists, Entry, Status};
+use crate::manifest::{Flag, Manifest};
+use crate::matcher::Patterns;
+use crate::record::NULLID_REVISION;
+use crate::repository::Repository;
+use crate::status;
+
+/// A parsed fileset expression, as used by `set:` patterns.
+///
+/// ```text
+/// binary() and size('>10MB') and revs('release::', added())
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// A bare word or a string, which is a `glob:` pattern unless a
+    /// function takes it as an argument.
+    Pattern(String),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Minus(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Func(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Symbol(String),
+    String(String),
+    LParen,
+    RParen,
+    Comma,
+    And,
+    Or,
+    Not,
+    Minus,
+}
+
+// Symbols may contain glob characters, so `*.rs` needs no quotes.
+fn is_symbol_char(c: char) -> bool {
+    c.is_alphanumeric() || "._/*?[]{}@$:".contains(c) || c as u32 > 127
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        match c {
+            _ if c.is_whitespace() => pos += 1,
+            '(' | ')' | ',' | '&' | '|' | '+' | '!' | '-' => {
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    ',' => Token::Comma,
+                    '&' => Token::And,
+                    '|' | '+' => Token::Or,
+                    '!' => Token::Not,
+                    _ => Token::Minus,
+                });
+                pos += 1;
+            }
+            '\'' | '"' => {
+                let mut value = String::new();
+                pos += 1;
+                loop {
+                    match chars.get(pos) {
+                        None => bail!("Unterminated string in fileset {:?}.", text),
+                        Some('\\') if pos + 1 < chars.len() => {
+                            value.push(chars[pos + 1]);
+                            pos += 2;
+                        }
+                        Some(quote) if *quote == c => {
+                            pos += 1;
+                            break;
+                        }
+                        Some(other) => {
+                            value.push(*other);
+                            pos += 1;
+                        }
+                    }
+                }
+                tokens.push(Token::String(value));
+            }
+            _ if is_symbol_char(c) => {
+                let start = pos;
+                while pos < chars.len() && is_symbol_char(chars[pos]) {
+                    pos += 1;
+                }
+                let symbol: String = chars[start..pos].iter().collect();
+                tokens.push(match symbol.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Symbol(symbol),
+                });
+            }
+            _ => bail!("Syntax error in fileset {:?} at {:?}.", text, c),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            token => Err(anyhow!("Expected {:?} but found {:?}.", expected, token)),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_not()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                    expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+                }
+                Some(Token::Minus) => {
+                    self.next();
+                    expr = Expr::Minus(Box::new(expr), Box::new(self.parse_not()?));
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::Symbol(name)) if self.peek() == Some(&Token::LParen) => {
+                self.next();
+                let mut args = Vec::new();
+                if self.peek() == Some(&Token::RParen) {
+                    self.next();
+                    return Ok(Expr::Func(name, args));
+                }
+                loop {
+                    args.push(self.parse_or()?);
+                    match self.next() {
+                        Some(Token::Comma) => continue,
+                        Some(Token::RParen) => return Ok(Expr::Func(name, args)),
+                        token => bail!("Expected ',' or ')' but found {:?}.", token),
+                    }
+                }
+            }
+            Some(Token::Symbol(text)) | Some(Token::String(text)) => Ok(Expr::Pattern(text)),
+            token => bail!("Unexpected {:?} in fileset.", token),
+        }
+    }
+}
+
+/// Parse a fileset.
+pub fn parse(text: &str) -> Result<Expr> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+    if parser.peek().is_none() {
+        bail!("Empty fileset.");
+    }
+    let expr = parser.parse_or()?;
+    if let Some(token) = parser.peek() {
+        bail!("Unexpected {:?} in fileset {:?}.", token, text);
+    }
+    Ok(expr)
+}
+
+/// Evaluate a fileset against the working copy. Its files are those tracked
+/// or removed, and unknown and ignored files.
+pub fn evaluate(repo: &Repository, text: &str) -> Result<BTreeSet<PathBuf>> {
+    let expr = parse(text)?;
+    Context::working(repo)?.eval(&expr)
+}
+
+// The files a fileset selects from, with their status letters as shown by
+// `status`, and where to read them.
+struct Context<'a> {
+    repo: &'a Repository,
+    files: BTreeMap<PathBuf, &'static str>,
+    // The manifest of the revision, or None for the working copy.
+    manifest: Option<Manifest>,
+}
+
+impl<'a> Context<'a> {
+    fn working(repo: &'a Repository) -> Result<Context<'a>> {
+        let parent = repo.changelog_rev(&repo.dirstate()?.parent1_hash)?;
+        Ok(Context {
+            repo,
+            files: status::working_status(repo, parent)?
+                .into_iter()
+                .map(swap)
+                .collect(),
+            manifest: None,
+        })
+    }
+
+    // A revision's files, with their status against its first parent.
+    fn revision(repo: &'a Repository, rev: u32) -> Result<Context<'a>> {
+        let parent = repo.changelog_revlog()?.get_record(rev)?.parent1_revision;
+        let statuses = status::revision_status(repo, parent.unwrap_or(NULLID_REVISION), rev, true)?;
+        Ok(Context {
+            repo,
+            files: statuses.into_iter().map(swap).collect(),
+            manifest: Some(repo.manifest(&repo.changeset(rev)?)?),
+        })
+    }
+
+    // The contents of a file, unless it is missing.
+    fn data(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        match &self.manifest {
+            Some(manifest) => manifest
+                .entries
+                .get(path)
+                .map(|nodeid| self.repo.file_data(path, nodeid))
+                .transpose(),
+            None if lexists(self.repo.file_path(path)) => Ok(Some(self.repo.working_data(path)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn flag(&self, path: &Path) -> Result<Option<Flag>> {
+        match &self.manifest {
+            Some(manifest) => Ok(manifest.flag(path)),
+            None if lexists(self.repo.file_path(path)) => {
+                Ok(Entry::from_file(self.repo.file_path(path), Status::Normal)?.flag())
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn size(&self, path: &Path) -> Result<Option<u64>> {
+        match &self.manifest {
+            Some(_) => Ok(self.data(path)?.map(|data| data.len() as u64)),
+            None => Ok(std::fs::symlink_metadata(self.repo.file_path(path))
+                .ok()
+                .map(|metadata| metadata.len())),
+        }
+    }
+
+    fn filter(&self, predicate: impl Fn(&Path) -> Result<bool>) -> Result<BTreeSet<PathBuf>> {
+        let mut paths = BTreeSet::new();
+        for path in self.files.keys() {
+            if predicate(path)? {
+                paths.insert(path.clone());
+            }
+        }
+        Ok(paths)
+    }
+
+    // Files whose contents satisfy `predicate`.
+    fn filter_data(&self, predicate: impl Fn(&[u8]) -> bool) -> Result<BTreeSet<PathBuf>> {
+        self.filter(|path| Ok(self.data(path)?.is_some_and(|data| predicate(&data))))
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<BTreeSet<PathBuf>> {
+        match expr {
+            Expr::Pattern(pattern) => {
+                let matcher = self.repo.matcher(&Patterns {
+                    include: vec![pattern.clone()],
+                    ..Patterns::default()
+                })?;
+                self.filter(|path| Ok(matcher.matches(path)))
+            }
+            Expr::And(a, b) => Ok(&self.eval(a)? & &self.eval(b)?),
+            Expr::Or(a, b) => Ok(&self.eval(a)? | &self.eval(b)?),
+            Expr::Minus(a, b) => Ok(&self.eval(a)? - &self.eval(b)?),
+            Expr::Not(a) => Ok(&self.files.keys().cloned().collect() - &self.eval(a)?),
+            Expr::Func(name, args) => self.func(name, args),
+        }
+    }
+
+    fn func(&self, name: &str, args: &[Expr]) -> Result<BTreeSet<PathBuf>> {
+        let status = match name {
+            "modified" => Some("M"),
+            "added" => Some("A"),
+            "removed" => Some("R"),
+            "deleted" => Some("!"),
+            "unknown" => Some("?"),
+            "ignored" => Some("I"),
+            "clean" => Some("C"),
+            _ => None,
+        };
+        if let Some(status) = status {
+            expect_args(name, args, 0)?;
+            return Ok(self
+                .files
+                .iter()
+                .filter(|(_path, file_status)| **file_status == status)
+                .map(|(path, _status)| path.clone())
+                .collect());
+        }
+        match name {
+            "binary" => {
+                expect_args(name, args, 0)?;
+                self.filter_data(is_binary)
+            }
+            "exec" | "symlink" => {
+                expect_args(name, args, 0)?;
+                let flag = match name {
+                    "exec" => Flag::Executable,
+                    _ => Flag::Symlink,
+                };
+                self.filter(|path| Ok(self.flag(path)? == Some(flag)))
+            }
+            "size" => {
+                expect_args(name, args, 1)?;
+                let (min, max) = parse_size(string_arg(name, &args[0])?)?;
+                self.filter(|path| {
+                    Ok(self
+                        .size(path)?
+                        .is_some_and(|size| min <= size && size <= max))
+                })
+            }
+            "grep" => {
+                expect_args(name, args, 1)?;
+                let regex = Regex::new(string_arg(name, &args[0])?)?;
+                self.filter_data(|data| regex.is_match(data))
+            }
+            "encoding" => {
+                expect_args(name, args, 1)?;
+                let decodes: fn(&[u8]) -> bool =
+                    match string_arg(name, &args[0])?.to_ascii_lowercase().as_str() {
+                        "utf-8" | "utf8" => |data| std::str::from_utf8(data).is_ok(),
+                        "ascii" | "us-ascii" => |data| data.is_ascii(),
+                        encoding => bail!("Unknown encoding {:?}.", encoding),
+                    };
+                self.filter_data(decodes)
+            }
+            "eol" => {
+                expect_args(name, args, 1)?;
+                let style = string_arg(name, &args[0])?;
+                let has_style: fn(&[u8]) -> bool = match style {
+                    "dos" | "win" => |data| data.windows(2).any(|pair| pair == b"\r\n"),
+                    "unix" => |data| {
+                        data.iter()
+                            .enumerate()
+                            .any(|(i, c)| *c == b'\n' && (i == 0 || data[i - 1] != b'\r'))
+                    },
+                    "mac" => |data| {
+                        data.iter()
+                            .enumerate()
+                            .any(|(i, c)| *c == b'\r' && data.get(i + 1) != Some(&b'\n'))
+                    },
+                    _ => bail!("Unknown end of line style {:?}.", style),
+                };
+                self.filter_data(|data| !is_binary(data) && has_style(data))
+            }
+            "revs" => {
+                expect_args(name, args, 2)?;
+                let mut paths = BTreeSet::new();
+                for rev in crate::revset::revs(self.repo, string_arg(name, &args[0])?)? {
+                    paths.extend(Context::revision(self.repo, rev)?.eval(&args[1])?);
+                }
+                Ok(paths)
+            }
+            _ => bail!("Unknown fileset function {}.", name),
+        }
+    }
+}
+
+fn swap((status, path): (&'static str, PathBuf)) -> (PathBuf, &'static str) {
+    (path, status)
+}
+
+fn is_binary(data: &[u8]) -> bool {
+    data.contains(&0)
+}
+
+fn expect_args(name: &str, args: &[Expr], count: usize) -> Result<()> {
+    if args.len() != count {
+        bail!(
+            "{}() takes {} arguments but {} were given.",
+            name,
+            count,
+            args.len()
+        );
+    }
+    Ok(())
+}
+
+fn string_arg<'e>(name: &str, arg: &'e Expr) -> Result<&'e str> {
+    match arg {
+        Expr::Pattern(text) => Ok(text),
+        _ => bail!("{}() expects a string.", name),
+    }
+}
+
+// Parse a size specification into an inclusive range of bytes:
+// `>1MB`, `<=10k`, `1k-2k`, or a size, which matches up to the next unit.
+fn parse_size(spec: &str) -> Result<(u64, u64)> {
+    let spec = spec.trim();
+    let bytes = |text: &str| -> Result<(u64, u64)> {
+        let text = text.trim().to_ascii_lowercase();
+        let units = [
+            ("gb", 1 << 30),
+            ("g", 1 << 30),
+            ("mb", 1 << 20),
+            ("m", 1 << 20),
+            ("kb", 1 << 10),
+            ("k", 1 << 10),
+            ("b", 1),
+        ];
+        let (number, unit) = units
+            .iter()
+            .find_map(|(suffix, unit)| Some((text.strip_suffix(suffix)?, *unit)))
+            .unwrap_or((text.as_str(), 1));
+        let number: f64 = number
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid size {:?}.", spec))?;
+        Ok(((number * unit as f64) as u64, unit))
+    };
+    if let Some(rest) = spec.strip_prefix(">=") {
+        Ok((bytes(rest)?.0, u64::MAX))
+    } else if let Some(rest) = spec.strip_prefix("<=") {
+        Ok((0, bytes(rest)?.0))
+    } else if let Some(rest) = spec.strip_prefix('>') {
+        Ok((bytes(rest)?.0 + 1, u64::MAX))
+    } else if let Some(rest) = spec.strip_prefix('<') {
+        Ok((0, bytes(rest)?.0.saturating_sub(1)))
+    } else if let Some((low, high)) = spec.split_once('-') {
+        Ok((bytes(low)?.0, bytes(high)?.0))
+    } else {
+        let (size, unit) = bytes(spec)?;
+        Ok((size, size + unit - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(text: &str) -> Box<Expr> {
+        Box::new(Expr::Pattern(text.to_string()))
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(
+            parse("**.rs and not size('>1k') or binary()")?,
+            Expr::Or(
+                Box::new(Expr::And(
+                    pattern("**.rs"),
+                    Box::new(Expr::Not(Box::new(Expr::Func(
+                        "size".to_string(),
+                        vec![Expr::Pattern(">1k".to_string())]
+                    ))))
+                )),
+                Box::new(Expr::Func("binary".to_string(), vec![]))
+            )
+        );
+        assert_eq!(
+            parse("revs('tip', added()) - 'a b'")?,
+            Expr::Minus(
+                Box::new(Expr::Func(
+                    "revs".to_string(),
+                    vec![
+                        Expr::Pattern("tip".to_string()),
+                        Expr::Func("added".to_string(), vec![])
+                    ]
+                )),
+                pattern("a b")
+            )
+        );
+        assert!(parse("binary(").is_err());
+        assert!(parse("").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_size() -> Result<()> {
+        assert_eq!(parse_size(">1MB")?, (1_048_577, u64::MAX));
+        assert_eq!(parse_size("<=10k")?, (0, 10_240));
+        assert_eq!(parse_size("1k-2k")?, (1024, 2048));
+        assert_eq!(parse_size("1k")?, (1024, 2047));
+        assert_eq!(parse_size("12")?, (12, 12));
+        assert!(parse_size("big").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+use std::io::Write;
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// Structured output, selected with `-Tjson` or `-Tcbor` in place of a
+/// template. Commands emit a single array with one record per item.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Cbor,
+}
+
+impl Format {
+    pub fn from_template(spec: Option<&str>) -> Option<Format> {
+        match spec {
+            Some("json") => Some(Format::Json),
+            Some("cbor") => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes<T: Serialize>(self, records: &[T]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Format::Json => {
+                let mut bytes = serde_json::to_vec_pretty(records)?;
+                bytes.push(b'\n');
+                bytes
+            }
+            Format::Cbor => serde_cbor::to_vec(&records)?,
+        })
+    }
+
+    pub fn write<T: Serialize>(self, records: &[T]) -> Result<()> {
+        std::io::stdout().write_all(&self.to_bytes(records)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Item {
+        path: &'static str,
+        size: u64,
+    }
+
+    #[test]
+    fn test_to_bytes() -> Result<()> {
+        assert_eq!(Format::from_template(Some("json")), Some(Format::Json));
+        assert_eq!(Format::from_template(Some("{rev}")), None);
+
+        let items = [Item { path: "a", size: 1 }];
+        let json: serde_json::Value = serde_json::from_slice(&Format::Json.to_bytes(&items)?)?;
+        assert_eq!(json, serde_json::json!([{"path": "a", "size": 1}]));
+        let cbor: serde_cbor::Value = serde_cbor::from_slice(&Format::Cbor.to_bytes(&items)?)?;
+        assert!(matches!(cbor, serde_cbor::Value::Array(items) if items.len() == 1));
+        Ok(())
+    }
+}
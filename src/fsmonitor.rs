@@ -0,0 +1,611 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use crate::dirstate::Dirstate;
+use crate::repository::{write_atomic, Repository};
+
+// The daemon's files, under the repository directory:
+//
+// - `fsmonitor/session` names the running daemon's session and process id.
+// - `fsmonitor/journal` lists what changed during the session, one record a
+//   line: `f PATH` for a changed path, `r` when paths may have been missed
+//   and the working copy must be walked, and `c NAME` once the daemon has
+//   seen the cookie file `fsmonitor/cookies/NAME` created.
+const SESSION: &str = "fsmonitor/session";
+const JOURNAL: &str = "fsmonitor/journal";
+const COOKIES: &str = "fsmonitor/cookies";
+
+// What `status` last found, written by clients: the clock, a fingerprint of
+// the tracked files, then the paths that were not clean.
+const STATE: &str = "fsmonitor.state";
+
+// How long a client waits for the daemon to see its cookie.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(2);
+
+// A point in a daemon's journal.
+#[derive(Debug, PartialEq)]
+struct Clock {
+    session: String,
+    offset: u64,
+}
+
+impl Clock {
+    fn parse(text: &str) -> Option<Clock> {
+        let (session, offset) = text.rsplit_once(':')?;
+        Some(Clock {
+            session: session.to_string(),
+            offset: offset.parse().ok()?,
+        })
+    }
+
+    fn format(&self) -> String {
+        format!("{}:{}", self.session, self.offset)
+    }
+}
+
+/// What a running fsmonitor daemon reports since the state last saved by
+/// `status`.
+#[derive(Debug)]
+pub struct Query {
+    clock: Clock,
+    fingerprint: u64,
+    candidates: Option<BTreeSet<PathBuf>>,
+}
+
+impl Query {
+    /// The files that may have changed since the saved state, including those
+    /// that were not clean then, or None if the working copy must be walked.
+    pub fn candidates(&self) -> Option<&BTreeSet<PathBuf>> {
+        self.candidates.as_ref()
+    }
+
+    /// Save the state of the working copy as of this query, given the paths
+    /// that are not clean.
+    pub fn save<'p>(
+        &self,
+        repo: &Repository,
+        notable: impl Iterator<Item = &'p PathBuf>,
+    ) -> Result<()> {
+        let mut text = format!("{}\n{:016x}\n", self.clock.format(), self.fingerprint);
+        for path in notable {
+            let path = path.to_str().context("Failed to get Unicode string.")?;
+            text.push_str(path);
+            text.push('\n');
+        }
+        write_atomic(repo.repo_path(STATE), text)
+    }
+}
+
+/// Ask the fsmonitor daemon what changed, if `fsmonitor.mode` is `on` and the
+/// daemon is running.
+pub fn query(repo: &Repository, dirstate: &Dirstate) -> Result<Option<Query>> {
+    match repo.config()?.get("fsmonitor", "mode") {
+        None | Some("off") => return Ok(None),
+        Some("on") => {}
+        Some(mode) => bail!("Unknown fsmonitor.mode {}.", mode),
+    }
+    let session = match running_session(repo) {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+    let state = std::fs::read_to_string(repo.repo_path(STATE)).unwrap_or_default();
+    let mut lines = state.lines();
+    let saved_clock = lines.next().and_then(Clock::parse);
+    let saved_fingerprint = lines
+        .next()
+        .and_then(|line| u64::from_str_radix(line, 16).ok());
+    let start = match &saved_clock {
+        Some(clock) if clock.session == session => clock.offset,
+        _ => 0,
+    };
+
+    let (records, offset) = match sync(repo, start)? {
+        Some(synced) => synced,
+        None => return Ok(None),
+    };
+    // The journal is only ours if the daemon did not start a new session
+    // while we read it.
+    if running_session(repo).as_ref() != Some(&session) {
+        return Ok(None);
+    }
+    let fingerprint = fingerprint(dirstate);
+    let valid = saved_clock.is_some_and(|clock| clock.session == session)
+        && saved_fingerprint == Some(fingerprint)
+        && !records.iter().any(|record| record.is_none());
+    let candidates = valid.then(|| {
+        records
+            .into_iter()
+            .flatten()
+            .chain(lines.map(PathBuf::from))
+            .collect()
+    });
+    Ok(Some(Query {
+        clock: Clock { session, offset },
+        fingerprint,
+        candidates,
+    }))
+}
+
+// The session of the running daemon.
+fn running_session(repo: &Repository) -> Option<String> {
+    let text = std::fs::read_to_string(repo.repo_path(SESSION)).ok()?;
+    let (session, pid) = text.trim_end().split_once(' ')?;
+    Path::new("/proc")
+        .join(pid)
+        .exists()
+        .then(|| session.to_string())
+}
+
+// Create a cookie file and wait for the daemon to journal it, so that every
+// change made before now is in the journal. Returns the paths recorded from
+// `start` up to the cookie, with None for a rescan, and the offset after it.
+#[allow(clippy::type_complexity)]
+fn sync(repo: &Repository, start: u64) -> Result<Option<(Vec<Option<PathBuf>>, u64)>> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let name = format!(
+        "{}-{}",
+        std::process::id(),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos()
+    );
+    let mut file = std::fs::File::open(repo.repo_path(JOURNAL))?;
+    // A shorter journal belongs to a newer session.
+    if file.metadata()?.len() < start {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(start))?;
+    let mut watch = JournalWatch::new(&repo.repo_path(JOURNAL))?;
+    let cookie = repo.repo_path(COOKIES).join(&name);
+    std::fs::write(&cookie, "")?;
+    let marker = format!("c {}\n", name);
+    let deadline = Instant::now() + SYNC_TIMEOUT;
+    let mut journal = String::new();
+    loop {
+        file.read_to_string(&mut journal)?;
+        if journal.contains(&marker) {
+            break;
+        }
+        match deadline.checked_duration_since(Instant::now()) {
+            Some(timeout) => watch.wait(timeout)?,
+            None => break,
+        }
+    }
+    std::fs::remove_file(cookie)?;
+    let end = match journal.find(&marker) {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let records = journal[..end]
+        .lines()
+        .filter_map(|line| match line.split_once(' ') {
+            Some(("f", path)) => Some(Some(PathBuf::from(path))),
+            Some(("c", _name)) => None,
+            _ => Some(None),
+        })
+        .collect();
+    Ok(Some((records, start + (end + marker.len()) as u64)))
+}
+
+// Notifications of writes to the journal.
+#[cfg(target_os = "linux")]
+struct JournalWatch(inotify::Inotify);
+
+#[cfg(target_os = "linux")]
+impl JournalWatch {
+    fn new(journal: &Path) -> Result<JournalWatch> {
+        let inotify = inotify::Inotify::init()?;
+        inotify.watches().add(journal, inotify::WatchMask::MODIFY)?;
+        Ok(JournalWatch(inotify))
+    }
+
+    // Block until the journal is written to, or for at most `timeout`.
+    fn wait(&mut self, timeout: Duration) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut poll_fd = libc::pollfd {
+            fd: self.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().clamp(1, i32::MAX as u128) as i32;
+        // SAFETY: `poll_fd` outlives the call, which reads one entry.
+        if unsafe { libc::poll(&mut poll_fd, 1, timeout) } < 0 {
+            let error = std::io::Error::last_os_error();
+            if error.kind() != std::io::ErrorKind::Interrupted {
+                return Err(error.into());
+            }
+        }
+        // The events only say that there is more to read.
+        let mut buffer = [0; 1024];
+        match self.0.read_events(&mut buffer) {
+            Err(error) if error.kind() != std::io::ErrorKind::WouldBlock => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Without inotify, the journal is polled.
+#[cfg(not(target_os = "linux"))]
+struct JournalWatch;
+
+#[cfg(not(target_os = "linux"))]
+impl JournalWatch {
+    fn new(_journal: &Path) -> Result<JournalWatch> {
+        Ok(JournalWatch)
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<()> {
+        std::thread::sleep(timeout.min(Duration::from_millis(10)));
+        Ok(())
+    }
+}
+
+// A fingerprint of the tracked paths. Files that stop being tracked never
+// change on disk, so a saved state is only used for the same tracked files.
+fn fingerprint(dirstate: &Dirstate) -> u64 {
+    dirstate
+        .entries()
+        .keys()
+        .map(|path| {
+            let mut hasher = DefaultHasher::new();
+            path.hash(&mut hasher);
+            hasher.finish()
+        })
+        .fold(0, u64::wrapping_add)
+}
+
+/// Stop the repository's daemon by removing its session.
+pub fn stop(repo: &Repository) -> Result<()> {
+    if running_session(repo).is_none() {
+        bail!("fsmonitor is not running.");
+    }
+    std::fs::remove_file(repo.repo_path(SESSION))?;
+    Ok(())
+}
+
+/// Watch the working copy and journal its changes until the session is
+/// removed or replaced.
+#[cfg(target_os = "linux")]
+pub fn run(repo: &Repository) -> Result<()> {
+    daemon::Daemon::start(repo)?.run()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn run(_repo: &Repository) -> Result<()> {
+    bail!("fsmonitor requires Linux.")
+}
+
+#[cfg(target_os = "linux")]
+mod daemon {
+    use std::collections::{BTreeSet, HashMap};
+    use std::ffi::OsStr;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use anyhow::Result;
+    use inotify::{EventMask, Inotify, WatchMask};
+
+    use super::{COOKIES, JOURNAL, SESSION};
+    use crate::repository::{write_atomic, Repository};
+
+    // Start a new session once the journal grows past this many bytes.
+    const MAX_JOURNAL_SIZE: u64 = 64 << 20;
+
+    pub struct Daemon<'a> {
+        repo: &'a Repository,
+        inotify: Inotify,
+        session: String,
+        journal: File,
+        // Watched directories of the working copy, by watch descriptor.
+        directories: HashMap<i32, PathBuf>,
+        monitor_dir: i32,
+        cookies_dir: i32,
+    }
+
+    fn is_hidden(name: &OsStr) -> bool {
+        name.to_string_lossy().starts_with('.')
+    }
+
+    impl<'a> Daemon<'a> {
+        pub fn start(repo: &'a Repository) -> Result<Daemon<'a>> {
+            std::fs::create_dir_all(repo.repo_path(COOKIES))?;
+            let inotify = Inotify::init()?;
+            let monitor_dir = inotify
+                .watches()
+                .add(
+                    repo.repo_path("fsmonitor"),
+                    WatchMask::DELETE | WatchMask::MOVED_TO | WatchMask::ONLYDIR,
+                )?
+                .get_watch_descriptor_id();
+            let cookies_dir = inotify
+                .watches()
+                .add(
+                    repo.repo_path(COOKIES),
+                    WatchMask::CREATE | WatchMask::ONLYDIR,
+                )?
+                .get_watch_descriptor_id();
+            let mut daemon = Daemon {
+                repo,
+                inotify,
+                session: String::new(),
+                journal: File::create(repo.repo_path(JOURNAL))?,
+                directories: HashMap::new(),
+                monitor_dir,
+                cookies_dir,
+            };
+            daemon.watch(Path::new(""), &mut Vec::new())?;
+            daemon.new_session()?;
+            Ok(daemon)
+        }
+
+        // Empty the journal and publish a new session, which invalidates
+        // every client's clock.
+        fn new_session(&mut self) -> Result<()> {
+            self.journal = File::create(self.repo.repo_path(JOURNAL))?;
+            self.session = format!(
+                "{}-{}",
+                std::process::id(),
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)?
+                    .as_nanos()
+            );
+            write_atomic(
+                self.repo.repo_path(SESSION),
+                format!("{} {}\n", self.session, std::process::id()),
+            )
+        }
+
+        fn is_current(&self) -> bool {
+            std::fs::read_to_string(self.repo.repo_path(SESSION))
+                .is_ok_and(|text| text.split(' ').next() == Some(self.session.as_str()))
+        }
+
+        // Watch a directory and the directories under it, adding the paths
+        // of files in them to `records`.
+        fn watch(&mut self, directory: &Path, records: &mut Vec<String>) -> Result<()> {
+            let mask = WatchMask::CREATE
+                | WatchMask::DELETE
+                | WatchMask::MODIFY
+                | WatchMask::ATTRIB
+                | WatchMask::MOVED_FROM
+                | WatchMask::MOVED_TO
+                | WatchMask::DONT_FOLLOW
+                | WatchMask::ONLYDIR
+                | WatchMask::EXCL_UNLINK;
+            let mut directories = vec![directory.to_path_buf()];
+            while let Some(directory) = directories.pop() {
+                let wd = match self
+                    .inotify
+                    .watches()
+                    .add(self.repo.file_path(&directory), mask)
+                {
+                    Ok(wd) => wd,
+                    // Removed before we got to it.
+                    Err(_) => continue,
+                };
+                self.directories
+                    .insert(wd.get_watch_descriptor_id(), directory.clone());
+                let dir_entries = match std::fs::read_dir(self.repo.file_path(&directory)) {
+                    Ok(dir_entries) => dir_entries,
+                    Err(_) => continue,
+                };
+                for dir_entry in dir_entries {
+                    let dir_entry = dir_entry?;
+                    let path = directory.join(dir_entry.file_name());
+                    if dir_entry.file_type()?.is_dir() {
+                        if !is_hidden(&dir_entry.file_name()) {
+                            directories.push(path);
+                        }
+                    } else {
+                        records.push(record(&path));
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        // Stop watching a directory that moved away, and those under it.
+        fn unwatch(&mut self, directory: &Path) {
+            let wds: Vec<i32> = self
+                .directories
+                .iter()
+                .filter(|(_wd, path)| path.starts_with(directory))
+                .map(|(wd, _path)| *wd)
+                .collect();
+            for wd in wds {
+                self.directories.remove(&wd);
+            }
+        }
+
+        pub fn run(mut self) -> Result<()> {
+            let mut buffer = vec![0; 64 * 1024];
+            loop {
+                let mut records = Vec::new();
+                for event in self.inotify.read_events_blocking(&mut buffer)? {
+                    let wd = event.wd.get_watch_descriptor_id();
+                    if event.mask.contains(EventMask::Q_OVERFLOW) {
+                        // Watch any directories whose creation was missed.
+                        records.push("r".to_string());
+                        self.watch(Path::new(""), &mut Vec::new())?;
+                        continue;
+                    }
+                    let name = match event.name {
+                        Some(name) => name,
+                        None => {
+                            if event.mask.contains(EventMask::IGNORED) {
+                                self.directories.remove(&wd);
+                            }
+                            continue;
+                        }
+                    };
+                    if wd == self.cookies_dir {
+                        records.push(format!("c {}", name.to_string_lossy()));
+                        continue;
+                    }
+                    if wd == self.monitor_dir {
+                        if name == "session" && !self.is_current() {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    let path = match self.directories.get(&wd) {
+                        Some(directory) => directory.join(name),
+                        None => continue,
+                    };
+                    if !event.mask.contains(EventMask::ISDIR) {
+                        records.push(record(&path));
+                    } else if is_hidden(name) {
+                        continue;
+                    } else if event
+                        .mask
+                        .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                    {
+                        // Files may have been created in it before the watch.
+                        self.watch(&path, &mut records)?;
+                    } else if event.mask.contains(EventMask::MOVED_FROM) {
+                        // The files under it are gone without events.
+                        records.push("r".to_string());
+                        self.unwatch(&path);
+                    }
+                }
+                self.append(records)?;
+            }
+        }
+
+        fn append(&mut self, records: Vec<String>) -> Result<()> {
+            let mut seen = BTreeSet::new();
+            let mut text = String::new();
+            for record in records {
+                if record.starts_with("c ") || seen.insert(record.clone()) {
+                    text.push_str(&record);
+                    text.push('\n');
+                }
+            }
+            if self.journal.metadata()?.len() > MAX_JOURNAL_SIZE {
+                self.new_session()?;
+            }
+            self.journal.write_all(text.as_bytes())?;
+            Ok(())
+        }
+    }
+
+    // A journal record for a path. Paths that do not fit on one line
+    // make clients walk the working copy instead.
+    fn record(path: &Path) -> String {
+        match path.to_str() {
+            Some(path) if !path.contains('\n') => format!("f {}", path),
+            _ => "r".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::dirstate::{Entry, Status};
+    use crate::repository::DEFAULT_REQUIREMENTS;
+
+    #[test]
+    fn test_clock() {
+        let clock = Clock {
+            session: "12-345".to_string(),
+            offset: 678,
+        };
+        assert_eq!(Clock::parse(&clock.format()), Some(clock));
+        assert_eq!(Clock::parse("12-345"), None);
+    }
+
+    #[test]
+    fn test_query() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let repo = Repository::new(base_dir.path());
+        repo.init(DEFAULT_REQUIREMENTS)?;
+        std::fs::write(repo.repo_path("hgrc"), "[fsmonitor]\nmode = on\n")?;
+        std::fs::create_dir_all(repo.repo_path(COOKIES))?;
+        std::fs::write(repo.repo_path(JOURNAL), "")?;
+        let session = format!("s1 {}\n", std::process::id());
+        std::fs::write(repo.repo_path(SESSION), session)?;
+        let append = |text: &str| -> Result<()> {
+            let mut journal = std::fs::OpenOptions::new()
+                .append(true)
+                .open(repo.repo_path(JOURNAL))?;
+            journal.write_all(text.as_bytes())?;
+            Ok(())
+        };
+        std::fs::write(base_dir.path().join("a"), "a")?;
+        let mut dirstate = Dirstate::default();
+        let entry = Entry::from_file(base_dir.path().join("a"), Status::Normal)?;
+        dirstate.mut_entries().insert("a".into(), entry.clone());
+        // Query and save the state with every file clean. Candidates are
+        // listed separated by spaces.
+        let candidates = |dirstate: &Dirstate| -> Result<Option<String>> {
+            let query = query(&repo, dirstate)?.context("fsmonitor is not running.")?;
+            query.save(&repo, std::iter::empty())?;
+            Ok(query.candidates().map(|candidates| {
+                let paths: Vec<String> = candidates
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect();
+                paths.join(" ")
+            }))
+        };
+
+        // Stand in for the daemon by journaling each cookie, until the test
+        // is done or has surely failed.
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let daemon = scope.spawn(|| -> Result<()> {
+                let deadline = Instant::now() + Duration::from_secs(30);
+                let mut seen = HashSet::new();
+                while !done.load(Ordering::SeqCst) && Instant::now() < deadline {
+                    for dir_entry in std::fs::read_dir(repo.repo_path(COOKIES))? {
+                        let name = dir_entry?.file_name().to_string_lossy().into_owned();
+                        if seen.insert(name.clone()) {
+                            append(&format!("c {}\n", name))?;
+                        }
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Ok(())
+            });
+            let result = (|| -> Result<()> {
+                // Without a saved state the working copy is walked.
+                assert_eq!(candidates(&dirstate)?, None);
+                append("f b\nf a\n")?;
+                assert_eq!(candidates(&dirstate)?.as_deref(), Some("a b"));
+                // Paths that were not clean stay candidates.
+                let query = query(&repo, &dirstate)?.context("fsmonitor is not running.")?;
+                assert_eq!(query.candidates(), Some(&BTreeSet::new()));
+                query.save(&repo, [PathBuf::from("b")].iter())?;
+                append("f c\n")?;
+                assert_eq!(candidates(&dirstate)?.as_deref(), Some("b c"));
+                // Rescans, unknown clocks and other tracked files fall back
+                // to a walk.
+                append("r\n")?;
+                assert_eq!(candidates(&dirstate)?, None);
+                std::fs::write(repo.repo_path(STATE), "s0:0\n")?;
+                assert_eq!(candidates(&dirstate)?, None);
+                assert_eq!(candidates(&dirstate)?.as_deref(), Some(""));
+                dirstate.mut_entries().insert("d".into(), entry);
+                assert_eq!(candidates(&dirstate)?, None);
+                assert_eq!(candidates(&dirstate)?.as_deref(), Some(""));
+                Ok(())
+            })();
+            done.store(true, Ordering::SeqCst);
+            daemon.join().expect("The daemon thread panicked.")?;
+            result
+        })
+    }
+}
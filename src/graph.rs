@@ -0,0 +1,222 @@
+/// Draws a changeset DAG as ASCII art next to each changeset's text, one
+/// node at a time from the newest revision to the oldest.
+///
+/// ```text
+/// @    changeset: 3:...
+/// |\
+/// | o  changeset: 2:...
+/// | |
+/// o |  changeset: 1:...
+/// |/
+/// o  changeset: 0:...
+/// ```
+#[derive(Debug, Default)]
+pub struct Graph {
+    // Revisions that columns are waiting for, left to right.
+    columns: Vec<u32>,
+    last_column_change: isize,
+    last_index: usize,
+}
+
+impl Graph {
+    /// Render `rev`, whose parents among the shown revisions are `parents`,
+    /// as the node `symbol` followed by the lines of `text`.
+    pub fn render(&mut self, rev: u32, parents: &[u32], symbol: char, text: &str) -> String {
+        if !self.columns.contains(&rev) {
+            self.columns.push(rev);
+        }
+        let index = self
+            .columns
+            .iter()
+            .position(|column| *column == rev)
+            .expect("The revision has a column.");
+        let (known, new): (Vec<u32>, Vec<u32>) = parents
+            .iter()
+            .partition(|parent| self.columns.contains(parent));
+        let ncols = self.columns.len();
+        let mut next = self.columns.clone();
+        next.splice(index..=index, new.iter().copied());
+        let mut edges: Vec<(usize, usize)> = known
+            .iter()
+            .map(|parent| {
+                let end = next
+                    .iter()
+                    .position(|column| column == parent)
+                    .expect("Known parents have a column.");
+                (index, end)
+            })
+            .collect();
+        if !new.is_empty() {
+            edges.push((index, index));
+        }
+        if new.len() > 1 {
+            edges.push((index, index + 1));
+        }
+        let column_change = next.len() as isize - ncols as isize;
+        self.columns = next;
+        self.draw(index, edges, ncols, column_change, symbol, text)
+    }
+
+    fn draw(
+        &mut self,
+        index: usize,
+        mut edges: Vec<(usize, usize)>,
+        ncols: usize,
+        column_change: isize,
+        symbol: char,
+        text: &str,
+    ) -> String {
+        let mut text: Vec<&str> = text.lines().collect();
+        if column_change == -1 {
+            // Edges to the right skip over the column being closed.
+            for (start, end) in edges.iter_mut() {
+                if *end > *start {
+                    *end += 1;
+                }
+            }
+        }
+        // Spread a closing edge over an extra line when there is room.
+        let add_padding_line = text.len() > 2
+            && column_change == -1
+            && edges.iter().any(|(start, end)| start + 1 < *end);
+        let fix_tail = text.len() <= 2 && !add_padding_line;
+
+        let mut node_line = repeat("| ", index);
+        node_line.push(symbol);
+        node_line.push(' ');
+        node_line.extend(self.node_line_tail(index, ncols, column_change, fix_tail));
+
+        let mut shift_line = repeat("| ", index);
+        let (spaces, edge) = match column_change {
+            -1 => (1, "/ "),
+            0 => (2, "| "),
+            _ => (3, "\\ "),
+        };
+        shift_line.extend(repeat(" ", spaces));
+        shift_line.extend(repeat(edge, ncols - index - 1));
+
+        for (start, end) in &edges {
+            let (start, end) = (*start, *end);
+            if start == end + 1 {
+                set(&mut shift_line, 2 * end + 1, '/');
+            } else if start + 1 == end {
+                set(&mut shift_line, 2 * start + 1, '\\');
+            } else if start == end {
+                set(&mut shift_line, 2 * start, '|');
+            } else if 2 * end < node_line.len() {
+                node_line[2 * end] = '+';
+                let (low, high) = (start.min(end), start.max(end));
+                for c in &mut node_line[2 * low + 1..2 * high] {
+                    if *c != '+' {
+                        *c = '-';
+                    }
+                }
+            }
+        }
+
+        let mut lines = vec![node_line];
+        if add_padding_line {
+            let mut padding = repeat("| ", index);
+            padding.push(
+                if edges.contains(&(index, index.wrapping_sub(1)))
+                    || edges.contains(&(index, index))
+                {
+                    '|'
+                } else {
+                    ' '
+                },
+            );
+            padding.push(' ');
+            padding.extend(repeat("| ", ncols - index - 1));
+            lines.push(padding);
+        }
+        lines.push(shift_line);
+        while text.len() < lines.len() {
+            text.push("");
+        }
+        let remaining = (ncols as isize + column_change) as usize;
+        while lines.len() < text.len() {
+            lines.push(repeat("| ", remaining));
+        }
+
+        let width = 2 * ncols.max(remaining);
+        let mut out = String::new();
+        for (line, text) in lines.iter().zip(&text) {
+            let line: String = line.iter().collect();
+            let row = format!("{:width$} {}", line, text, width = width);
+            out.push_str(row.trim_end());
+            out.push('\n');
+        }
+        self.last_column_change = column_change;
+        self.last_index = index;
+        out
+    }
+
+    // The part of the node line right of the node. When consecutive nodes
+    // shift columns the same way, the tail leans with them.
+    fn node_line_tail(
+        &self,
+        index: usize,
+        ncols: usize,
+        column_change: isize,
+        fix_tail: bool,
+    ) -> Vec<char> {
+        let remaining = ncols - index - 1;
+        if fix_tail && column_change == self.last_column_change && column_change != 0 {
+            if column_change == -1 {
+                let start = (index + 1).max(self.last_index);
+                let mut tail = repeat("| ", start - index - 1);
+                tail.extend(repeat("/ ", ncols.saturating_sub(start)));
+                tail
+            } else {
+                repeat("\\ ", remaining)
+            }
+        } else {
+            repeat("| ", remaining)
+        }
+    }
+}
+
+fn repeat(pattern: &str, count: usize) -> Vec<char> {
+    pattern.repeat(count).chars().collect()
+}
+
+fn set(line: &mut [char], index: usize, c: char) {
+    if let Some(slot) = line.get_mut(index) {
+        *slot = c;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        // 3 merges 1 and 2, which both branch off 0; 4 is an unrelated root.
+        let mut graph = Graph::default();
+        let out: String = [
+            (4, vec![], '@'),
+            (3, vec![1, 2], 'o'),
+            (2, vec![0], 'o'),
+            (1, vec![0], 'o'),
+            (0, vec![], 'o'),
+        ]
+        .iter()
+        .map(|(rev, parents, symbol)| graph.render(*rev, parents, *symbol, &format!("{}\n", rev)))
+        .collect();
+        assert_eq!(
+            out,
+            "@  4\n\
+             \n\
+             o    3\n\
+             |\\\n\
+             | o  2\n\
+             | |\n\
+             o |  1\n\
+             |/\n\
+             o  0\n\
+             \n"
+        );
+    }
+}
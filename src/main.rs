@@ -1,45 +1,359 @@
+use std::path::PathBuf;
+
 use anyhow::{Context, Result};
-use clap::{self, value_t};
 
+use crate::config::Config;
+use crate::matcher::Patterns;
+
+mod bookmarks;
 mod changeset;
 mod command;
+mod config;
+mod dateutil;
+mod diff;
 mod dirstate;
+mod filemerge;
+mod fileset;
+mod formatter;
+mod fsmonitor;
+mod graph;
 mod manifest;
+mod matcher;
+mod mergestate;
+mod patch;
 mod record;
 mod repository;
 mod revlog;
+mod revset;
+mod simplemerge;
+mod status;
+mod tags;
+mod template;
+mod worker;
 
 fn main() -> Result<()> {
     let matches = clap::App::new("hg-rs")
         .author("mingyli")
+        .global_setting(clap::AppSettings::AllowNegativeNumbers)
+        .arg(
+            clap::Arg::with_name("config")
+                .long("config")
+                .help("Set a configuration value (section.key=value).")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true),
+        )
         .subcommand(clap::SubCommand::with_name("init").about("Initialize a Mercurial repository."))
         .subcommand(
-            clap::SubCommand::with_name("status").about("Display changes to the directory state"),
+            clap::SubCommand::with_name("config")
+                .about("Show or edit configuration values.")
+                .arg(clap::Arg::with_name("name"))
+                .arg(clap::Arg::with_name("edit").short("e").long("edit"))
+                .arg(clap::Arg::with_name("debug").long("debug")),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("status")
+                .about("Display changes to the directory state")
+                // `-0` is `--print0` rather than a number.
+                .unset_setting(clap::AppSettings::AllowNegativeNumbers)
+                .arg(clap::Arg::with_name("file").multiple(true))
+                .args(&pattern_args())
+                .arg(
+                    clap::Arg::with_name("rev")
+                        .long("rev")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    clap::Arg::with_name("change")
+                        .long("change")
+                        .takes_value(true),
+                )
+                .arg(clap::Arg::with_name("all").short("A").long("all"))
+                .arg(clap::Arg::with_name("modified").short("m").long("modified"))
+                .arg(clap::Arg::with_name("added").short("a").long("added"))
+                .arg(clap::Arg::with_name("removed").short("r").long("removed"))
+                .arg(clap::Arg::with_name("deleted").short("d").long("deleted"))
+                .arg(clap::Arg::with_name("unknown").short("u").long("unknown"))
+                .arg(clap::Arg::with_name("ignored").short("i").long("ignored"))
+                .arg(clap::Arg::with_name("clean").short("c").long("clean"))
+                .arg(
+                    clap::Arg::with_name("no-status")
+                        .short("n")
+                        .long("no-status"),
+                )
+                .arg(clap::Arg::with_name("print0").short("0").long("print0"))
+                .arg(
+                    clap::Arg::with_name("template")
+                        .short("T")
+                        .long("template")
+                        .takes_value(true),
+                ),
         )
         .subcommand(
             clap::SubCommand::with_name("add")
-                .about("Add a file to be tracked in the directory state.")
-                .arg(clap::Arg::with_name("file")),
+                .about("Add files to be tracked in the directory state.")
+                .arg(clap::Arg::with_name("file").multiple(true))
+                .args(&pattern_args()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("heads")
+                .about("Show open branch heads in the repository.")
+                .arg(
+                    clap::Arg::with_name("template")
+                        .short("T")
+                        .long("template")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("tip")
+                .about("Show the most recently added changeset.")
+                .arg(
+                    clap::Arg::with_name("template")
+                        .short("T")
+                        .long("template")
+                        .takes_value(true),
+                ),
         )
         .subcommand(
-            clap::SubCommand::with_name("heads").about("Show open branch heads in the repository."),
+            clap::SubCommand::with_name("annotate")
+                .alias("blame")
+                .about("Show the changeset that last changed each line of files.")
+                .arg(clap::Arg::with_name("file").required(true).multiple(true))
+                .arg(
+                    clap::Arg::with_name("rev")
+                        .short("r")
+                        .long("rev")
+                        .takes_value(true),
+                )
+                .arg(clap::Arg::with_name("user").short("u").long("user"))
+                .arg(clap::Arg::with_name("number").short("n").long("number"))
+                .arg(
+                    clap::Arg::with_name("changeset")
+                        .short("c")
+                        .long("changeset"),
+                )
+                .arg(clap::Arg::with_name("date").short("d").long("date"))
+                .arg(
+                    clap::Arg::with_name("line-number")
+                        .short("l")
+                        .long("line-number"),
+                )
+                .arg(
+                    clap::Arg::with_name("template")
+                        .short("T")
+                        .long("template")
+                        .takes_value(true),
+                ),
         )
         .subcommand(
-            clap::SubCommand::with_name("log").about("Display the history of the repository."),
+            clap::SubCommand::with_name("log")
+                .about("Display the history of the repository.")
+                .arg(clap::Arg::with_name("file").multiple(true))
+                .arg(
+                    clap::Arg::with_name("rev")
+                        .short("r")
+                        .long("rev")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    clap::Arg::with_name("limit")
+                        .short("l")
+                        .long("limit")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("user")
+                        .short("u")
+                        .long("user")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    clap::Arg::with_name("keyword")
+                        .short("k")
+                        .long("keyword")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    clap::Arg::with_name("date")
+                        .short("d")
+                        .long("date")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("branch")
+                        .short("b")
+                        .long("branch")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    clap::Arg::with_name("no-merges")
+                        .short("M")
+                        .long("no-merges"),
+                )
+                .arg(
+                    clap::Arg::with_name("only-merges")
+                        .short("m")
+                        .long("only-merges")
+                        .conflicts_with("no-merges"),
+                )
+                .arg(clap::Arg::with_name("patch").short("p").long("patch"))
+                .arg(clap::Arg::with_name("stat").long("stat"))
+                .arg(clap::Arg::with_name("verbose").short("v").long("verbose"))
+                .arg(clap::Arg::with_name("graph").short("G").long("graph"))
+                .arg(
+                    clap::Arg::with_name("template")
+                        .short("T")
+                        .long("template")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("diff")
+                .about("Show differences between revisions.")
+                .arg(clap::Arg::with_name("file").multiple(true))
+                .args(&pattern_args())
+                .arg(
+                    clap::Arg::with_name("rev")
+                        .short("r")
+                        .long("rev")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(clap::Arg::with_name("stat").long("stat"))
+                .arg(
+                    clap::Arg::with_name("template")
+                        .short("T")
+                        .long("template")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("files")
+                .about("List tracked files.")
+                .arg(clap::Arg::with_name("file").multiple(true))
+                .args(&pattern_args())
+                .arg(
+                    clap::Arg::with_name("rev")
+                        .short("r")
+                        .long("rev")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("template")
+                        .short("T")
+                        .long("template")
+                        .takes_value(true),
+                ),
         )
         .subcommand(
             clap::SubCommand::with_name("debugindex")
                 .arg(clap::Arg::with_name("file"))
                 .arg(clap::Arg::with_name("changelog").long("changelog"))
-                .arg(clap::Arg::with_name("manifest").long("manifest")),
+                .arg(clap::Arg::with_name("manifest").long("manifest"))
+                .arg(
+                    clap::Arg::with_name("template")
+                        .short("T")
+                        .long("template")
+                        .takes_value(true),
+                ),
         )
         .subcommand(
-            clap::SubCommand::with_name("commit").arg(
-                clap::Arg::with_name("message")
-                    .short("m")
-                    .required(true)
-                    .takes_value(true),
-            ),
+            clap::SubCommand::with_name("commit")
+                .about("Commit the specified files or all outstanding changes.")
+                .arg(clap::Arg::with_name("file").multiple(true))
+                .args(&pattern_args())
+                .arg(
+                    clap::Arg::with_name("message")
+                        .short("m")
+                        .long("message")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("logfile")
+                        .short("l")
+                        .long("logfile")
+                        .takes_value(true)
+                        .conflicts_with("message"),
+                )
+                .arg(
+                    clap::Arg::with_name("user")
+                        .short("u")
+                        .long("user")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("date")
+                        .short("d")
+                        .long("date")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("addremove")
+                        .short("A")
+                        .long("addremove"),
+                )
+                .arg(clap::Arg::with_name("close-branch").long("close-branch")),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("branch")
+                .about("Set or show the current branch name.")
+                .arg(clap::Arg::with_name("name"))
+                .arg(clap::Arg::with_name("force").short("f").long("force")),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("bookmark")
+                .about("Create a new bookmark or list existing bookmarks.")
+                .arg(clap::Arg::with_name("name").multiple(true))
+                .arg(
+                    clap::Arg::with_name("rev")
+                        .short("r")
+                        .long("rev")
+                        .takes_value(true),
+                )
+                .arg(clap::Arg::with_name("delete").short("d").long("delete"))
+                .arg(
+                    clap::Arg::with_name("rename")
+                        .short("m")
+                        .long("rename")
+                        .takes_value(true)
+                        .conflicts_with_all(&["delete", "rev"]),
+                )
+                .arg(clap::Arg::with_name("inactive").short("i").long("inactive"))
+                .arg(clap::Arg::with_name("force").short("f").long("force")),
+        )
+        .subcommand(clap::SubCommand::with_name("bookmarks").about("List existing bookmarks."))
+        .subcommand(
+            clap::SubCommand::with_name("tag")
+                .about("Add one or more tags for a revision.")
+                .arg(clap::Arg::with_name("name").required(true).multiple(true))
+                .arg(
+                    clap::Arg::with_name("rev")
+                        .short("r")
+                        .long("rev")
+                        .takes_value(true),
+                )
+                .arg(clap::Arg::with_name("local").short("l").long("local"))
+                .arg(clap::Arg::with_name("remove").long("remove"))
+                .arg(clap::Arg::with_name("force").short("f").long("force")),
+        )
+        .subcommand(clap::SubCommand::with_name("tags").about("List repository tags."))
+        .subcommand(
+            clap::SubCommand::with_name("branches")
+                .about("List repository named branches.")
+                .arg(clap::Arg::with_name("closed").short("c").long("closed")),
         )
         .subcommand(
             clap::SubCommand::with_name("snapshot")
@@ -52,43 +366,181 @@ fn main() -> Result<()> {
                 .arg(clap::Arg::with_name("changelog").long("changelog"))
                 .arg(clap::Arg::with_name("manifest").long("manifest")),
         )
-        .subcommand(clap::SubCommand::with_name("debugdirstate"))
+        .subcommand(
+            clap::SubCommand::with_name("debugdirstate").arg(
+                clap::Arg::with_name("template")
+                    .short("T")
+                    .long("template")
+                    .takes_value(true),
+            ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("fsmonitor")
+                .about("Watch the working directory for changes to speed up status.")
+                .arg(clap::Arg::with_name("daemon").short("d").long("daemon"))
+                .arg(
+                    clap::Arg::with_name("stop")
+                        .long("stop")
+                        .conflicts_with("daemon"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("recover")
+                .about("Recover from an interrupted write to the repository."),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("update")
+                .about("Update the working directory to a revision.")
+                .arg(clap::Arg::with_name("revision"))
+                .arg(
+                    clap::Arg::with_name("rev")
+                        .short("r")
+                        .long("rev")
+                        .takes_value(true)
+                        .conflicts_with("revision"),
+                )
+                .arg(clap::Arg::with_name("clean").short("C").long("clean")),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("merge")
+                .about("Merge another revision into the working directory.")
+                .arg(clap::Arg::with_name("revision").required(true))
+                .arg(
+                    clap::Arg::with_name("tool")
+                        .short("t")
+                        .long("tool")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("resolve")
+                .about("Redo merges or set the resolution status of merged files.")
+                .arg(clap::Arg::with_name("file").multiple(true))
+                .arg(clap::Arg::with_name("all").short("a").long("all"))
+                .arg(clap::Arg::with_name("list").short("l").long("list"))
+                .arg(clap::Arg::with_name("mark").short("m").long("mark"))
+                .arg(clap::Arg::with_name("unmark").short("u").long("unmark"))
+                .group(clap::ArgGroup::with_name("action").args(&["list", "mark", "unmark"]))
+                .arg(
+                    clap::Arg::with_name("tool")
+                        .short("t")
+                        .long("tool")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
+    if let (_, Some(matches)) = matches.subcommand() {
+        Config::set_overrides(values(matches, "config"))?;
+    }
     match matches.subcommand() {
         ("init", Some(_)) => command::init()?,
-        ("status", Some(_)) => command::status()?,
-        ("heads", Some(_)) => command::heads()?,
-        ("log", Some(_)) => command::log()?,
-        ("add", Some(matches)) => command::add(
-            matches
-                .value_of("file")
-                .context("Failed to get file name.")?,
+        ("config", Some(matches)) => command::config(
+            matches.value_of("name"),
+            matches.is_present("edit"),
+            matches.is_present("debug"),
         )?,
-        ("commit", Some(matches)) => command::commit(
-            matches
-                .value_of("message")
-                .context("Failed to get commit message.")?,
+        ("status", Some(matches)) => command::status(&command::StatusOptions {
+            revs: values(matches, "rev"),
+            change: matches.value_of("change").map(String::from),
+            all: matches.is_present("all"),
+            modified: matches.is_present("modified"),
+            added: matches.is_present("added"),
+            removed: matches.is_present("removed"),
+            deleted: matches.is_present("deleted"),
+            unknown: matches.is_present("unknown"),
+            ignored: matches.is_present("ignored"),
+            clean: matches.is_present("clean"),
+            no_status: matches.is_present("no-status"),
+            print0: matches.is_present("print0"),
+            template: matches.value_of("template").map(String::from),
+            patterns: patterns(matches),
+        })?,
+        ("heads", Some(matches)) => command::heads(matches.value_of("template"))?,
+        ("tip", Some(matches)) => command::tip(matches.value_of("template"))?,
+        ("annotate", Some(matches)) => command::annotate(
+            &values(matches, "file")
+                .into_iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>(),
+            &command::AnnotateOptions {
+                rev: matches.value_of("rev").map(String::from),
+                user: matches.is_present("user"),
+                number: matches.is_present("number"),
+                changeset: matches.is_present("changeset"),
+                date: matches.is_present("date"),
+                line_number: matches.is_present("line-number"),
+                template: matches.value_of("template").map(String::from),
+            },
+        )?,
+        ("log", Some(matches)) => command::log(&command::LogOptions {
+            revsets: values(matches, "rev"),
+            limit: matches
+                .value_of("limit")
+                .map(str::parse)
+                .transpose()
+                .context("Limit must be a positive number.")?,
+            users: values(matches, "user"),
+            keywords: values(matches, "keyword"),
+            date: matches.value_of("date").map(String::from),
+            branches: values(matches, "branch"),
+            no_merges: matches.is_present("no-merges"),
+            only_merges: matches.is_present("only-merges"),
+            patch: matches.is_present("patch"),
+            stat: matches.is_present("stat"),
+            verbose: matches.is_present("verbose"),
+            graph: matches.is_present("graph"),
+            template: matches.value_of("template").map(String::from),
+            files: Patterns::new(values(matches, "file")),
+        })?,
+        ("diff", Some(matches)) => command::diff(
+            &values(matches, "rev"),
+            &patterns(matches),
+            matches.is_present("stat"),
+            matches.value_of("template"),
         )?,
+        ("files", Some(matches)) => command::files(
+            matches.value_of("rev"),
+            &patterns(matches),
+            matches.value_of("template"),
+        )?,
+        ("add", Some(matches)) => command::add(&patterns(matches))?,
+        ("commit", Some(matches)) => command::commit(&command::CommitOptions {
+            message: matches.value_of("message").map(String::from),
+            logfile: matches.value_of("logfile").map(PathBuf::from),
+            user: matches.value_of("user").map(String::from),
+            date: matches.value_of("date").map(String::from),
+            addremove: matches.is_present("addremove"),
+            close_branch: matches.is_present("close-branch"),
+            patterns: patterns(matches),
+        })?,
+        ("branch", Some(matches)) => {
+            command::branch(matches.value_of("name"), matches.is_present("force"))?
+        }
+        ("branches", Some(matches)) => command::branches(matches.is_present("closed"))?,
         ("snapshot", Some(matches)) => command::snapshot(
             matches
                 .value_of("file")
                 .context("Failed to get file name.")?,
         )?,
         ("debugindex", Some(matches)) => {
+            let template = matches.value_of("template");
             if matches.is_present("manifest") {
-                command::debug_manifest_index()?
+                command::debug_manifest_index(template)?
             } else if matches.is_present("changelog") {
-                command::debug_changelog_index()?
+                command::debug_changelog_index(template)?
             } else {
                 command::debug_index(
                     matches
                         .value_of("file")
                         .context("Failed to get file name.")?,
+                    template,
                 )?
             }
         }
         ("debugdata", Some(matches)) => {
-            let rev = clap::value_t!(matches, "revision", u32)?;
+            let rev = matches
+                .value_of("revision")
+                .context("Failed to get revision.")?;
             if matches.is_present("manifest") {
                 command::debug_manifest_data(rev)?
             } else if matches.is_present("changelog") {
@@ -102,8 +554,121 @@ fn main() -> Result<()> {
                 )?
             }
         }
-        ("debugdirstate", Some(_matches)) => command::debug_dirstate()?,
+        ("debugdirstate", Some(matches)) => command::debug_dirstate(matches.value_of("template"))?,
+        ("fsmonitor", Some(matches)) => {
+            command::fsmonitor(matches.is_present("daemon"), matches.is_present("stop"))?
+        }
+        ("recover", Some(_)) => command::recover()?,
+        ("update", Some(matches)) => {
+            let rev = matches
+                .value_of("rev")
+                .or_else(|| matches.value_of("revision"));
+            command::update(rev, matches.is_present("clean"))?
+        }
+        ("bookmark", Some(matches)) => {
+            let names: Vec<String> = matches
+                .values_of("name")
+                .map(|names| names.map(String::from).collect())
+                .unwrap_or_default();
+            let force = matches.is_present("force");
+            let action = if matches.is_present("delete") {
+                command::BookmarkAction::Delete
+            } else if let Some(old) = matches.value_of("rename") {
+                command::BookmarkAction::Rename {
+                    old: old.to_string(),
+                    force,
+                }
+            } else {
+                command::BookmarkAction::Set {
+                    rev: matches.value_of("rev").map(String::from),
+                    inactive: matches.is_present("inactive"),
+                    force,
+                }
+            };
+            let list = ["delete", "rename", "inactive"]
+                .iter()
+                .all(|flag| !matches.is_present(flag));
+            if names.is_empty() && list {
+                command::bookmarks()?
+            } else {
+                command::bookmark(action, &names)?
+            }
+        }
+        ("bookmarks", Some(_)) => command::bookmarks()?,
+        ("merge", Some(matches)) => command::merge(
+            matches
+                .value_of("revision")
+                .context("Failed to get revision.")?,
+            matches.value_of("tool"),
+        )?,
+        ("tag", Some(matches)) => command::tag(
+            &matches
+                .values_of("name")
+                .context("Failed to get tag name.")?
+                .map(String::from)
+                .collect::<Vec<_>>(),
+            matches.value_of("rev"),
+            matches.is_present("local"),
+            matches.is_present("remove"),
+            matches.is_present("force"),
+        )?,
+        ("tags", Some(_)) => command::tags()?,
+        ("resolve", Some(matches)) => {
+            let action = if matches.is_present("list") {
+                command::ResolveAction::List
+            } else if matches.is_present("mark") {
+                command::ResolveAction::Mark
+            } else if matches.is_present("unmark") {
+                command::ResolveAction::Unmark
+            } else {
+                command::ResolveAction::Remerge
+            };
+            let files: Vec<PathBuf> = matches
+                .values_of("file")
+                .map(|files| files.map(PathBuf::from).collect())
+                .unwrap_or_default();
+            command::resolve(
+                action,
+                &files,
+                matches.is_present("all"),
+                matches.value_of("tool"),
+            )?
+        }
         _ => unreachable!(),
     }
     Ok(())
 }
+
+// `-I` and `-X`, for commands that take file patterns.
+fn pattern_args<'a, 'b>() -> [clap::Arg<'a, 'b>; 2] {
+    [
+        clap::Arg::with_name("include")
+            .short("I")
+            .long("include")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        clap::Arg::with_name("exclude")
+            .short("X")
+            .long("exclude")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
+
+fn patterns(matches: &clap::ArgMatches) -> Patterns {
+    Patterns {
+        patterns: values(matches, "file"),
+        include: values(matches, "include"),
+        exclude: values(matches, "exclude"),
+    }
+}
+
+// All values given for an argument, in order.
+fn values(matches: &clap::ArgMatches, name: &str) -> Vec<String> {
+    matches
+        .values_of(name)
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default()
+}
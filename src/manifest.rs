@@ -1,24 +1,369 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{btree_map, BTreeMap};
 use std::fmt;
-use std::path::PathBuf;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
 
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+/// How a file is checked out, beyond its contents.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Executable,
+    Symlink,
+}
+
+impl Flag {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Flag::Executable => "x",
+            Flag::Symlink => "l",
+        }
+    }
+
+    fn parse(text: &str) -> Result<Option<Flag>> {
+        Ok(match text {
+            "" => None,
+            "x" => Some(Flag::Executable),
+            "l" => Some(Flag::Symlink),
+            _ => bail!("Unknown manifest flag {:?}.", text),
+        })
+    }
+}
+
+/// The files of a revision with their nodeids, sorted by path. Files with a
+/// flag also appear in `flags`.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
 pub struct Manifest {
-    pub entries: HashMap<PathBuf, [u8; 20]>,
+    pub entries: BTreeMap<PathBuf, [u8; 20]>,
+    pub flags: BTreeMap<PathBuf, Flag>,
+}
+
+// The layout of manifests written before flags were recorded.
+#[derive(Deserialize)]
+struct ManifestV1 {
+    entries: BTreeMap<PathBuf, [u8; 20]>,
+}
+
+impl Manifest {
+    pub fn flag<P: AsRef<Path>>(&self, path: P) -> Option<Flag> {
+        self.flags.get(path.as_ref()).copied()
+    }
+
+    pub fn insert(&mut self, path: PathBuf, nodeid: [u8; 20], flag: Option<Flag>) {
+        match flag {
+            Some(flag) => self.flags.insert(path.clone(), flag),
+            None => self.flags.remove(&path),
+        };
+        self.entries.insert(path, nodeid);
+    }
+
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) {
+        self.entries.remove(path.as_ref());
+        self.flags.remove(path.as_ref());
+    }
+
+    /// The files that differ from `self` to `other`, sorted by path.
+    pub fn diff<'a>(&'a self, other: &'a Manifest) -> Diff<'a> {
+        Diff {
+            old: self,
+            new: other,
+            old_entries: self.entries.iter().peekable(),
+            new_entries: other.entries.iter().peekable(),
+        }
+    }
+
+    /// Serialize with bincode.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Deserialize bincode, including manifests written without flags.
+    pub fn decode(bytes: &[u8]) -> Result<Manifest> {
+        match bincode::deserialize(bytes) {
+            Ok(manifest) => Ok(manifest),
+            Err(_) => {
+                let old: ManifestV1 = bincode::deserialize(bytes)?;
+                Ok(Manifest {
+                    entries: old.entries,
+                    flags: BTreeMap::new(),
+                })
+            }
+        }
+    }
+
+    /// Serialize as a Mercurial manifest: one `path\0hex[flag]\n` line per
+    /// file, sorted by path.
+    pub fn to_text(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    /// Parse a Mercurial manifest written by `to_text`.
+    pub fn from_text(bytes: &[u8]) -> Result<Manifest> {
+        let text = std::str::from_utf8(bytes).context("Manifest is not UTF-8.")?;
+        let mut manifest = Manifest::default();
+        for line in text.lines() {
+            let (path, hash) = line
+                .split_once('\0')
+                .with_context(|| format!("Invalid manifest line {:?}.", line))?;
+            let mut nodeid = [0u8; 20];
+            hex::decode_to_slice(hash.get(..40).unwrap_or(hash), &mut nodeid)
+                .with_context(|| format!("Invalid nodeid for {} in manifest.", path))?;
+            let flag = Flag::parse(hash.get(40..).unwrap_or_default())?;
+            manifest.insert(PathBuf::from(path), nodeid, flag);
+        }
+        Ok(manifest)
+    }
 }
 
 impl fmt::Display for Manifest {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for (path, hash) in &self.entries {
+        // Paths are compared bytewise, as in Mercurial, rather than by component.
+        let mut entries: Vec<(String, &PathBuf, &[u8; 20])> = self
+            .entries
+            .iter()
+            .map(|(path, nodeid)| (path.display().to_string(), path, nodeid))
+            .collect();
+        entries.sort();
+        for (name, path, nodeid) in entries {
             writeln!(
                 f,
-                "{}\x00{}",
-                path.to_str().expect("Failed to convert path to string."),
-                hex::encode(hash)
+                "{}\x00{}{}",
+                name,
+                hex::encode(nodeid),
+                self.flag(path).map_or("", Flag::as_str)
             )?;
         }
-        write!(f, "")
+        Ok(())
+    }
+}
+
+/// A file's nodeid and flag in one manifest.
+pub type FileNode<'a> = (&'a [u8; 20], Option<Flag>);
+
+/// How a file differs between two manifests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change<'a> {
+    Added(FileNode<'a>),
+    Removed(FileNode<'a>),
+    /// The contents changed, and maybe the flag too.
+    Modified(FileNode<'a>, FileNode<'a>),
+    /// Only the flag changed.
+    FlagChanged(FileNode<'a>, FileNode<'a>),
+}
+
+impl<'a> Change<'a> {
+    /// The file in the old manifest, unless it was added.
+    pub fn before(&self) -> Option<FileNode<'a>> {
+        match *self {
+            Change::Added(_) => None,
+            Change::Removed(old) | Change::Modified(old, _) | Change::FlagChanged(old, _) => {
+                Some(old)
+            }
+        }
+    }
+
+    /// The file in the new manifest, unless it was removed.
+    pub fn after(&self) -> Option<FileNode<'a>> {
+        match *self {
+            Change::Removed(_) => None,
+            Change::Added(new) | Change::Modified(_, new) | Change::FlagChanged(_, new) => {
+                Some(new)
+            }
+        }
+    }
+}
+
+/// The iterator returned by `Manifest::diff`.
+pub struct Diff<'a> {
+    old: &'a Manifest,
+    new: &'a Manifest,
+    old_entries: Peekable<btree_map::Iter<'a, PathBuf, [u8; 20]>>,
+    new_entries: Peekable<btree_map::Iter<'a, PathBuf, [u8; 20]>>,
+}
+
+impl<'a> Iterator for Diff<'a> {
+    type Item = (&'a Path, Change<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let order = match (self.old_entries.peek(), self.new_entries.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((old_path, _)), Some((new_path, _))) => old_path.cmp(new_path),
+            };
+            match order {
+                Ordering::Less => {
+                    let (path, nodeid) = self.old_entries.next()?;
+                    return Some((path, Change::Removed((nodeid, self.old.flag(path)))));
+                }
+                Ordering::Greater => {
+                    let (path, nodeid) = self.new_entries.next()?;
+                    return Some((path, Change::Added((nodeid, self.new.flag(path)))));
+                }
+                Ordering::Equal => {
+                    let (path, old_nodeid) = self.old_entries.next()?;
+                    let (_, new_nodeid) = self.new_entries.next()?;
+                    let old = (old_nodeid, self.old.flag(path));
+                    let new = (new_nodeid, self.new.flag(path));
+                    if old_nodeid != new_nodeid {
+                        return Some((path, Change::Modified(old, new)));
+                    }
+                    if old.1 != new.1 {
+                        return Some((path, Change::FlagChanged(old, new)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// One directory of a tree manifest: the files directly inside it and the
+/// nodeids of its subdirectories' manifests, keyed by name.
+///
+/// The text format is a flat manifest's, with `t` flagging subdirectories.
+#[derive(Debug, Default, PartialEq)]
+pub struct DirManifest {
+    pub files: BTreeMap<String, ([u8; 20], Option<Flag>)>,
+    pub dirs: BTreeMap<String, [u8; 20]>,
+}
+
+impl DirManifest {
+    pub fn to_text(&self) -> Vec<u8> {
+        let mut lines: Vec<(&str, &[u8; 20], &str)> = self
+            .files
+            .iter()
+            .map(|(name, (nodeid, flag))| (name.as_str(), nodeid, flag.map_or("", Flag::as_str)))
+            .chain(
+                self.dirs
+                    .iter()
+                    .map(|(name, nodeid)| (name.as_str(), nodeid, "t")),
+            )
+            .collect();
+        lines.sort();
+        let mut text = Vec::new();
+        for (name, nodeid, flag) in lines {
+            text.extend_from_slice(
+                format!("{}\0{}{}\n", name, hex::encode(nodeid), flag).as_bytes(),
+            );
+        }
+        text
+    }
+
+    pub fn from_text(bytes: &[u8]) -> Result<DirManifest> {
+        let text = std::str::from_utf8(bytes).context("Manifest is not UTF-8.")?;
+        let mut dir = DirManifest::default();
+        for line in text.lines() {
+            let (name, hash) = line
+                .split_once('\0')
+                .with_context(|| format!("Invalid manifest line {:?}.", line))?;
+            let mut nodeid = [0u8; 20];
+            hex::decode_to_slice(hash.get(..40).unwrap_or(hash), &mut nodeid)
+                .with_context(|| format!("Invalid nodeid for {} in manifest.", name))?;
+            match hash.get(40..).unwrap_or_default() {
+                "t" => {
+                    dir.dirs.insert(name.to_string(), nodeid);
+                }
+                flag => {
+                    dir.files
+                        .insert(name.to_string(), (nodeid, Flag::parse(flag)?));
+                }
+            }
+        }
+        Ok(dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text() -> Result<()> {
+        let mut manifest = Manifest::default();
+        manifest.insert("b/c".into(), [0x11; 20], None);
+        manifest.insert("b.txt".into(), [0x33; 20], Some(Flag::Symlink));
+        manifest.insert("a".into(), [0x22; 20], Some(Flag::Executable));
+        let text = manifest.to_text();
+        assert_eq!(
+            String::from_utf8_lossy(&text),
+            format!(
+                "a\0{}x\nb.txt\0{}l\nb/c\0{}\n",
+                "22".repeat(20),
+                "33".repeat(20),
+                "11".repeat(20)
+            )
+        );
+        assert_eq!(Manifest::from_text(&text)?, manifest);
+        assert_eq!(Manifest::decode(&manifest.encode()?)?, manifest);
+
+        #[derive(Serialize)]
+        struct OldManifest {
+            entries: BTreeMap<PathBuf, [u8; 20]>,
+        }
+        let old = OldManifest {
+            entries: manifest.entries.clone(),
+        };
+        assert_eq!(
+            Manifest::decode(&bincode::serialize(&old)?)?.entries,
+            manifest.entries
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_manifest_text() -> Result<()> {
+        let mut dir = DirManifest::default();
+        dir.files
+            .insert("b".into(), ([0x11; 20], Some(Flag::Executable)));
+        dir.files.insert("d".into(), ([0x22; 20], None));
+        dir.dirs.insert("c".into(), [0x33; 20]);
+        let text = dir.to_text();
+        assert_eq!(
+            String::from_utf8_lossy(&text),
+            format!(
+                "b\0{}x\nc\0{}t\nd\0{}\n",
+                "11".repeat(20),
+                "33".repeat(20),
+                "22".repeat(20)
+            )
+        );
+        assert_eq!(DirManifest::from_text(&text)?, dir);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut old = Manifest::default();
+        old.insert("a".into(), [1; 20], None);
+        old.insert("b".into(), [2; 20], None);
+        old.insert("c".into(), [3; 20], None);
+        old.insert("d/e".into(), [4; 20], None);
+        let mut new = Manifest::default();
+        new.insert("a".into(), [1; 20], None);
+        new.insert("b".into(), [2; 20], Some(Flag::Executable));
+        new.insert("d/e".into(), [5; 20], Some(Flag::Symlink));
+        new.insert("f".into(), [6; 20], None);
+        let changes: Vec<(&Path, Change)> = old.diff(&new).collect();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    Path::new("b"),
+                    Change::FlagChanged((&[2; 20], None), (&[2; 20], Some(Flag::Executable)))
+                ),
+                (Path::new("c"), Change::Removed((&[3; 20], None))),
+                (
+                    Path::new("d/e"),
+                    Change::Modified((&[4; 20], None), (&[5; 20], Some(Flag::Symlink)))
+                ),
+                (Path::new("f"), Change::Added((&[6; 20], None))),
+            ]
+        );
+        assert_eq!(changes[2].1.before(), Some((&[4; 20], None)));
+        assert_eq!(changes[1].1.after(), None);
+        assert_eq!(new.diff(&new).count(), 0);
     }
 }
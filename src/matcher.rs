@@ -0,0 +1,355 @@
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+
+/// File patterns from the command line: positional arguments, which are
+/// `relpath:` patterns unless they name a kind, and `-I`/`-X` patterns,
+/// which default to `glob:`.
+#[derive(Debug, Default, Clone)]
+pub struct Patterns {
+    pub patterns: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl Patterns {
+    pub fn new(patterns: Vec<String>) -> Patterns {
+        Patterns {
+            patterns,
+            ..Patterns::default()
+        }
+    }
+}
+
+// One parsed pattern. Paths are relative to the repository root.
+#[derive(Debug)]
+enum Pattern {
+    /// `path:` and `relpath:`, a file or everything under a directory.
+    Path(PathBuf),
+    /// `rootfilesin:`, the files directly inside a directory.
+    RootFilesIn(PathBuf),
+    /// `glob:`, `relglob:` and `re:`.
+    Regex(Regex),
+    /// `set:`, the files a fileset selected.
+    Set(BTreeSet<PathBuf>),
+}
+
+impl Pattern {
+    fn matches(&self, path: &Path) -> bool {
+        match self {
+            Pattern::Path(prefix) => path.starts_with(prefix),
+            Pattern::RootFilesIn(dir) => path.parent() == Some(dir.as_path()),
+            Pattern::Regex(regex) => path.to_str().is_some_and(|path| regex.is_match(path)),
+            Pattern::Set(paths) => paths.contains(path),
+        }
+    }
+}
+
+/// Decides which repository paths a command applies to.
+///
+/// ```text
+/// hg-rs status 'glob:src/**.rs' -X 're:.*_test\.rs$'
+/// hg-rs commit path:docs rootfilesin:. -I '**.md'
+/// ```
+#[derive(Debug, Default)]
+pub struct Matcher {
+    patterns: Vec<Pattern>,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    files: Vec<PathBuf>,
+}
+
+impl Matcher {
+    /// Parse patterns given in `cwd`, a directory under the repository root.
+    /// `filesets` evaluates the expressions of `set:` patterns.
+    pub fn new(
+        root: &Path,
+        cwd: &Path,
+        patterns: &Patterns,
+        filesets: &Filesets,
+    ) -> Result<Matcher> {
+        let cwd = cwd.strip_prefix(root).unwrap_or(Path::new(""));
+        let parser = Parser {
+            root,
+            cwd,
+            filesets,
+        };
+        let mut matcher = Matcher::default();
+        for pattern in &patterns.patterns {
+            parser.parse(
+                pattern,
+                "relpath",
+                &mut matcher.patterns,
+                &mut matcher.files,
+            )?;
+        }
+        for pattern in &patterns.include {
+            parser.parse(pattern, "glob", &mut matcher.include, &mut Vec::new())?;
+        }
+        for pattern in &patterns.exclude {
+            parser.parse(pattern, "glob", &mut matcher.exclude, &mut Vec::new())?;
+        }
+        Ok(matcher)
+    }
+
+    /// Whether `path`, relative to the repository root, is selected. Without
+    /// patterns every path is, except for those excluded.
+    pub fn matches(&self, path: &Path) -> bool {
+        let any = |patterns: &[Pattern]| patterns.iter().any(|pattern| pattern.matches(path));
+        (self.patterns.is_empty() || any(&self.patterns))
+            && (self.include.is_empty() || any(&self.include))
+            && !any(&self.exclude)
+    }
+
+    /// Whether every path is selected.
+    pub fn always(&self) -> bool {
+        self.patterns.is_empty() && self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// The paths named by `path:` and `relpath:` patterns, which commands
+    /// may report when nothing matches them.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+}
+
+/// Evaluates a fileset to the paths it selects.
+pub type Filesets<'a> = dyn Fn(&str) -> Result<BTreeSet<PathBuf>> + 'a;
+
+struct Parser<'a> {
+    root: &'a Path,
+    cwd: &'a Path,
+    filesets: &'a Filesets<'a>,
+}
+
+impl Parser<'_> {
+    fn parse(
+        &self,
+        pattern: &str,
+        default_kind: &str,
+        patterns: &mut Vec<Pattern>,
+        files: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let (kind, text) = match pattern.split_once(':') {
+            Some((kind, text)) if KINDS.contains(&kind) => (kind, text),
+            _ => (default_kind, pattern),
+        };
+        let parsed = match kind {
+            "path" => Pattern::Path(normalize(Path::new(text))?),
+            "relpath" => Pattern::Path(normalize(&self.cwd.join(text))?),
+            "rootfilesin" => Pattern::RootFilesIn(normalize(Path::new(text))?),
+            "glob" => {
+                let rooted = normalize(&self.cwd.join(text))?;
+                let glob = rooted.to_str().context("Failed to get Unicode string.")?;
+                Pattern::Regex(Regex::new(&format!("^{}(?:/|$)", glob_to_regex(glob)))?)
+            }
+            "relglob" => {
+                let regex = format!("^(?:.*/)?{}(?:/|$)", glob_to_regex(text));
+                Pattern::Regex(Regex::new(&regex)?)
+            }
+            "re" => Pattern::Regex(
+                Regex::new(&format!("^(?:{})", text))
+                    .with_context(|| format!("Invalid regular expression {:?}.", text))?,
+            ),
+            "listfile" => {
+                let path = self.root.join(self.cwd).join(text);
+                let list = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read pattern file {}.", path.display()))?;
+                for line in list.lines().filter(|line| !line.trim().is_empty()) {
+                    self.parse(line, default_kind, patterns, files)?;
+                }
+                return Ok(());
+            }
+            "set" => Pattern::Set(
+                (self.filesets)(text).with_context(|| format!("Invalid fileset {:?}.", text))?,
+            ),
+            _ => bail!("Unsupported pattern kind {}:.", kind),
+        };
+        if let Pattern::Path(path) = &parsed {
+            files.push(path.clone());
+        }
+        patterns.push(parsed);
+        Ok(())
+    }
+}
+
+const KINDS: &[&str] = &[
+    "path",
+    "relpath",
+    "glob",
+    "relglob",
+    "re",
+    "rootfilesin",
+    "listfile",
+    "set",
+];
+
+// Resolve `.` and `..` in a path relative to the repository root.
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    bail!("{} is outside the repository.", path.display());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                bail!("{} is not relative to the repository.", path.display())
+            }
+        }
+    }
+    Ok(normalized)
+}
+
+// Translate a glob into a regular expression. `*` and `?` stay within a
+// directory, `**` crosses directories, and `{a,b}` is an alternation.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = glob.chars().peekable();
+    let mut groups = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let class: String = chars.by_ref().take_while(|c| *c != ']').collect();
+                let class = match class.strip_prefix('!') {
+                    Some(rest) => format!("^{}", rest),
+                    None => class,
+                };
+                regex.push_str(&format!("[{}]", class.replace('\\', "\\\\")));
+            }
+            '{' => {
+                groups += 1;
+                regex.push_str("(?:");
+            }
+            '}' if groups > 0 => {
+                groups -= 1;
+                regex.push(')');
+            }
+            ',' if groups > 0 => regex.push('|'),
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    regex.push_str(&regex::escape(&escaped.to_string()));
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(cwd: &str, patterns: &[&str], include: &[&str], exclude: &[&str]) -> Matcher {
+        let strings = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect();
+        let patterns = Patterns {
+            patterns: strings(patterns),
+            include: strings(include),
+            exclude: strings(exclude),
+        };
+        let root = Path::new("/repo");
+        Matcher::new(root, &root.join(cwd), &patterns, &filesets).unwrap()
+    }
+
+    fn filesets(expr: &str) -> Result<BTreeSet<PathBuf>> {
+        match expr {
+            "binary()" => Ok(vec![PathBuf::from("src/lib.c")].into_iter().collect()),
+            _ => bail!("Unknown fileset {}.", expr),
+        }
+    }
+
+    fn matched(matcher: &Matcher, paths: &[&str]) -> Vec<String> {
+        paths
+            .iter()
+            .filter(|path| matcher.matches(Path::new(path)))
+            .map(|path| path.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_kinds() {
+        let paths = [
+            "a.rs",
+            "src/main.rs",
+            "src/bin/tool.rs",
+            "src/lib.c",
+            "docs/a.md",
+        ];
+        assert_eq!(matched(&matcher("", &[], &[], &[]), &paths), paths);
+        assert_eq!(
+            matched(&matcher("", &["src"], &[], &[]), &paths),
+            ["src/main.rs", "src/bin/tool.rs", "src/lib.c"]
+        );
+        assert_eq!(
+            matched(&matcher("src", &["../docs", "lib.c"], &[], &[]), &paths),
+            ["src/lib.c", "docs/a.md"]
+        );
+        assert_eq!(
+            matched(&matcher("", &["glob:src/*.rs"], &[], &[]), &paths),
+            ["src/main.rs"]
+        );
+        assert_eq!(
+            matched(&matcher("", &["glob:**.rs"], &[], &[]), &paths),
+            ["a.rs", "src/main.rs", "src/bin/tool.rs"]
+        );
+        assert_eq!(
+            matched(&matcher("", &["relglob:*.{c,md}"], &[], &[]), &paths),
+            ["src/lib.c", "docs/a.md"]
+        );
+        assert_eq!(
+            matched(&matcher("", &["re:src/.*\\.rs$"], &[], &[]), &paths),
+            ["src/main.rs", "src/bin/tool.rs"]
+        );
+        assert_eq!(
+            matched(&matcher("", &["rootfilesin:src"], &[], &[]), &paths),
+            ["src/main.rs", "src/lib.c"]
+        );
+        assert_eq!(
+            matched(&matcher("", &["rootfilesin:."], &[], &[]), &paths),
+            ["a.rs"]
+        );
+        assert_eq!(
+            matched(&matcher("src", &[], &["*.rs"], &["bin"]), &paths),
+            ["src/main.rs"]
+        );
+        assert_eq!(
+            matched(&matcher("", &["set:binary()"], &[], &[]), &paths),
+            ["src/lib.c"]
+        );
+        assert_eq!(
+            matcher("src", &["main.rs"], &[], &[]).files(),
+            [PathBuf::from("src/main.rs")]
+        );
+        assert!(Matcher::new(
+            Path::new("/repo"),
+            Path::new("/repo"),
+            &Patterns::new(vec!["../x".into()]),
+            &filesets
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        assert_eq!(glob_to_regex("*.rs"), "[^/]*\\.rs");
+        assert_eq!(glob_to_regex("**/a?"), "(?:.*/)?a[^/]");
+        assert_eq!(glob_to_regex("[!a-c]{x,y}"), "[^a-c](?:x|y)");
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::repository::write_atomic;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FileState {
+    Unresolved,
+    Resolved,
+}
+
+/// The versions of a conflicted file that take part in the merge.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MergeEntry {
+    pub state: FileState,
+    pub local_nodeid: [u8; 20],
+    pub other_nodeid: [u8; 20],
+    pub base_nodeid: [u8; 20],
+}
+
+/// Files that needed a merge since the working copy gained a second parent.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct MergeState {
+    pub local: [u8; 20],
+    pub other: [u8; 20],
+    files: BTreeMap<PathBuf, MergeEntry>,
+}
+
+impl MergeState {
+    pub fn new(local: [u8; 20], other: [u8; 20]) -> MergeState {
+        MergeState {
+            local,
+            other,
+            files: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<MergeState> {
+        if path.as_ref().exists() {
+            let file = OpenOptions::new().read(true).open(path)?;
+            let mergestate: MergeState = bincode::deserialize_from(file)?;
+            Ok(mergestate)
+        } else {
+            Ok(MergeState::default())
+        }
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        write_atomic(path, bincode::serialize(&self)?)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.other != [0u8; 20]
+    }
+
+    pub fn add<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        local_nodeid: [u8; 20],
+        other_nodeid: [u8; 20],
+        base_nodeid: [u8; 20],
+    ) {
+        self.files.insert(
+            path.as_ref().into(),
+            MergeEntry {
+                state: FileState::Unresolved,
+                local_nodeid,
+                other_nodeid,
+                base_nodeid,
+            },
+        );
+    }
+
+    pub fn files(&self) -> &BTreeMap<PathBuf, MergeEntry> {
+        &self.files
+    }
+
+    /// Set the state of a recorded file, returning false if it was not part of the merge.
+    pub fn mark<P: AsRef<Path>>(&mut self, path: P, state: FileState) -> bool {
+        match self.files.get_mut(path.as_ref()) {
+            Some(entry) => {
+                entry.state = state;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn unresolved(&self) -> impl Iterator<Item = &PathBuf> {
+        self.files
+            .iter()
+            .filter(|(_path, entry)| entry.state == FileState::Unresolved)
+            .map(|(path, _entry)| path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let path = base_dir.path().join("state");
+        assert!(!MergeState::from_file(&path)?.is_active());
+
+        let mut mergestate = MergeState::new([1u8; 20], [2u8; 20]);
+        mergestate.add("a", [3u8; 20], [4u8; 20], [0u8; 20]);
+        mergestate.add("b", [5u8; 20], [6u8; 20], [7u8; 20]);
+        assert!(mergestate.mark("b", FileState::Resolved));
+        assert!(!mergestate.mark("c", FileState::Resolved));
+        mergestate.write_to_file(&path)?;
+
+        let mergestate = MergeState::from_file(&path)?;
+        assert!(mergestate.is_active());
+        assert_eq!(
+            mergestate.unresolved().collect::<Vec<_>>(),
+            vec![&PathBuf::from("a")]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::diff::{line_changes, unified_hunks};
+use crate::dirstate::{lexists, Status};
+use crate::repository::Repository;
+
+/// Where the contents of a file in a snapshot come from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Revision([u8; 20]),
+    WorkingCopy,
+}
+
+/// The files of a revision or of the working copy.
+pub type Snapshot = BTreeMap<PathBuf, Source>;
+
+pub fn revision_snapshot(repo: &Repository, rev: u32) -> Result<Snapshot> {
+    let manifest = repo.manifest(&repo.changeset(rev)?)?;
+    Ok(manifest
+        .entries
+        .into_iter()
+        .map(|(path, nodeid)| (path, Source::Revision(nodeid)))
+        .collect())
+}
+
+/// Snapshots of the files that differ between two revisions, leaving out
+/// files they share.
+pub fn revision_snapshots(repo: &Repository, old: u32, new: u32) -> Result<(Snapshot, Snapshot)> {
+    let changes = repo.manifest_changes(
+        &repo.changeset(old)?.manifest_nodeid,
+        &repo.changeset(new)?.manifest_nodeid,
+    )?;
+    let (mut old, mut new) = (Snapshot::new(), Snapshot::new());
+    for (path, (old_nodeid, new_nodeid)) in changes {
+        if let Some(nodeid) = old_nodeid {
+            old.insert(path.clone(), Source::Revision(nodeid));
+        }
+        if let Some(nodeid) = new_nodeid {
+            new.insert(path, Source::Revision(nodeid));
+        }
+    }
+    Ok((old, new))
+}
+
+/// Snapshot the working copy. Files that are unchanged since the first
+/// parent keep its nodeid so they compare equal without being read.
+pub fn working_snapshot(repo: &Repository) -> Result<Snapshot> {
+    let dirstate = repo.dirstate()?;
+    let parent = repo.manifest(&repo.changeset(repo.changelog_rev(&dirstate.parent1_hash)?)?)?;
+    let mut snapshot = Snapshot::new();
+    for (path, entry) in dirstate.entries() {
+        let file_path = repo.file_path(path);
+        if entry.status == Status::Removed || !lexists(&file_path) {
+            continue;
+        }
+        let source = match parent.entries.get(path) {
+            Some(nodeid) if entry.status == Status::Normal && entry.matches_file(&file_path) => {
+                Source::Revision(*nodeid)
+            }
+            _ => Source::WorkingCopy,
+        };
+        snapshot.insert(path.clone(), source);
+    }
+    Ok(snapshot)
+}
+
+fn read(repo: &Repository, path: &Path, source: &Source) -> Result<Vec<u8>> {
+    match source {
+        Source::Revision(nodeid) => repo.file_data(path, nodeid),
+        Source::WorkingCopy => repo.working_data(path),
+    }
+}
+
+/// A file that differs between two snapshots. A missing side means the file
+/// was added or removed.
+#[derive(Debug, PartialEq)]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub old: Option<Vec<u8>>,
+    pub new: Option<Vec<u8>>,
+}
+
+impl FileChange {
+    pub fn is_binary(&self) -> bool {
+        let binary = |data: &Option<Vec<u8>>| data.as_ref().is_some_and(|data| data.contains(&0));
+        binary(&self.old) || binary(&self.new)
+    }
+}
+
+/// The files that differ between `old` and `new`, restricted to paths
+/// accepted by `filter`.
+pub fn changes(
+    repo: &Repository,
+    old: &Snapshot,
+    new: &Snapshot,
+    filter: &dyn Fn(&Path) -> bool,
+) -> Result<Vec<FileChange>> {
+    let mut paths: Vec<&PathBuf> = old.keys().chain(new.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut changes = Vec::new();
+    for path in paths.into_iter().filter(|path| filter(path)) {
+        let (old_source, new_source) = (old.get(path), new.get(path));
+        if let (Some(Source::Revision(a)), Some(Source::Revision(b))) = (old_source, new_source) {
+            if a == b {
+                continue;
+            }
+        }
+        let old_data = old_source
+            .map(|source| read(repo, path, source))
+            .transpose()?;
+        let new_data = new_source
+            .map(|source| read(repo, path, source))
+            .transpose()?;
+        if old_data != new_data {
+            changes.push(FileChange {
+                path: path.clone(),
+                old: old_data,
+                new: new_data,
+            });
+        }
+    }
+    Ok(changes)
+}
+
+/// Format changes as a unified diff. `header` is the `diff -r ...` prefix
+/// printed before each file name.
+pub fn format_diff(changes: &[FileChange], header: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    for change in changes {
+        let path = change.path.display();
+        out.extend_from_slice(format!("{} {}\n", header, path).as_bytes());
+        if change.is_binary() {
+            out.extend_from_slice(format!("Binary file {} has changed\n", path).as_bytes());
+            continue;
+        }
+        let old_name = match change.old {
+            Some(_) => format!("a/{}", path),
+            None => "/dev/null".to_string(),
+        };
+        let new_name = match change.new {
+            Some(_) => format!("b/{}", path),
+            None => "/dev/null".to_string(),
+        };
+        out.extend_from_slice(format!("--- {}\n+++ {}\n", old_name, new_name).as_bytes());
+        out.extend_from_slice(&unified_hunks(
+            change.old.as_deref().unwrap_or_default(),
+            change.new.as_deref().unwrap_or_default(),
+            3,
+        ));
+    }
+    out
+}
+
+/// Lines added and removed in one changed file.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FileStat {
+    pub path: String,
+    pub additions: usize,
+    pub removals: usize,
+    pub binary: bool,
+}
+
+pub fn stats(changes: &[FileChange]) -> Vec<FileStat> {
+    changes
+        .iter()
+        .map(|change| {
+            let binary = change.is_binary();
+            let (additions, removals) = if binary {
+                (0, 0)
+            } else {
+                line_changes(
+                    change.old.as_deref().unwrap_or_default(),
+                    change.new.as_deref().unwrap_or_default(),
+                )
+            };
+            FileStat {
+                path: change.path.display().to_string(),
+                additions,
+                removals,
+                binary,
+            }
+        })
+        .collect()
+}
+
+/// Summarize changes as a histogram of lines added and removed per file,
+/// scaled to fit in 80 columns.
+pub fn format_stat(stats: &[FileStat]) -> String {
+    let total_added: usize = stats.iter().map(|stat| stat.additions).sum();
+    let total_removed: usize = stats.iter().map(|stat| stat.removals).sum();
+    let stats: Vec<(&str, Option<(usize, usize)>)> = stats
+        .iter()
+        .map(|stat| {
+            let counts = if stat.binary {
+                None
+            } else {
+                Some((stat.additions, stat.removals))
+            };
+            (stat.path.as_str(), counts)
+        })
+        .collect();
+    let name_width = stats
+        .iter()
+        .map(|(name, _counts)| name.len())
+        .max()
+        .unwrap_or(0);
+    let max_total = stats
+        .iter()
+        .filter_map(|(_name, counts)| counts.map(|(added, removed)| added + removed))
+        .max()
+        .unwrap_or(0);
+    let mut count_width = max_total.to_string().len();
+    if stats.iter().any(|(_name, counts)| counts.is_none()) {
+        count_width = count_width.max(3);
+    }
+    let graph_width = 80usize.saturating_sub(count_width + name_width + 6).max(10);
+    let scale = |count: usize| {
+        if max_total <= graph_width {
+            count
+        } else {
+            (count * graph_width / max_total).max(usize::from(count > 0))
+        }
+    };
+
+    let mut out = String::new();
+    for (name, counts) in &stats {
+        let (count, graph) = match counts {
+            Some((added, removed)) => (
+                (added + removed).to_string(),
+                format!(
+                    "{}{}",
+                    "+".repeat(scale(*added)),
+                    "-".repeat(scale(*removed))
+                ),
+            ),
+            None => ("Bin".to_string(), String::new()),
+        };
+        out.push_str(&format!(
+            " {:name_width$} |  {:>count_width$} {}\n",
+            name,
+            count,
+            graph,
+            name_width = name_width,
+            count_width = count_width
+        ));
+    }
+    out.push_str(&format!(
+        " {} files changed, {} insertions(+), {} deletions(-)\n",
+        stats.len(),
+        total_added,
+        total_removed
+    ));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_stat() {
+        let changes = vec![
+            FileChange {
+                path: PathBuf::from("a"),
+                old: Some(b"1\n2\n".to_vec()),
+                new: Some(b"1\nb\nc\n".to_vec()),
+            },
+            FileChange {
+                path: PathBuf::from("dir/long"),
+                old: None,
+                new: Some(b"x\n".repeat(200)),
+            },
+            FileChange {
+                path: PathBuf::from("image"),
+                old: Some(b"\0".to_vec()),
+                new: None,
+            },
+        ];
+        let stat = format_stat(&stats(&changes));
+        let lines: Vec<&str> = stat.lines().collect();
+        assert_eq!(lines[0], " a        |    3 +-");
+        assert!(lines[1].starts_with(" dir/long |  200 +++"));
+        assert_eq!(lines[1].len(), 80);
+        assert_eq!(lines[2], " image    |  Bin ");
+        assert_eq!(
+            lines[3],
+            " 3 files changed, 202 insertions(+), 1 deletions(-)"
+        );
+    }
+}
@@ -44,6 +44,7 @@ where
 }
 
 // TODO: Implement deltas.
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum Hunk {
     Snapshot(Vec<u8>),
@@ -1,10 +1,23 @@
-use std::fs::{create_dir, create_dir_all};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{create_dir, create_dir_all, read_to_string, remove_dir_all};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
-use crate::dirstate::Dirstate;
+use crate::bookmarks::Bookmarks;
+use crate::changeset::{Changeset, DEFAULT_BRANCH};
+use crate::config::Config;
+use crate::dirstate::{CachedDirectory, Dirstate, Entry};
+use crate::fsmonitor;
+use crate::manifest::{DirManifest, FileNode, Manifest};
+use crate::matcher::{Matcher, Patterns};
+use crate::mergestate::MergeState;
+use crate::record::NULLID_REVISION;
 use crate::revlog::RevLog;
+use crate::tags::{self, Tags, TAGS_FILE};
+use crate::worker;
 
 #[derive(Debug, PartialEq)]
 pub struct Repository {
@@ -14,6 +27,92 @@ pub struct Repository {
 
 const REPO_DIR: &str = ".hg-rs";
 
+/// The requirement for changelog and manifest entries in Mercurial's
+/// textual formats instead of bincode.
+pub const TEXT_ENTRIES_REQUIREMENT: &str = "text-entries";
+
+/// The requirement for tree manifests: one manifest revlog per directory,
+/// under `store/meta/`, listing its files and the nodeids of its
+/// subdirectories. Chosen at init with `format.treemanifest`.
+pub const TREE_MANIFEST_REQUIREMENT: &str = "treemanifest";
+
+/// The requirement for the v2 dirstate: a docket pointing at a data file
+/// with a node per directory, which updates append to. Chosen at init with
+/// `format.use-dirstate-v2`.
+pub const DIRSTATE_V2_REQUIREMENT: &str = "dirstate-v2";
+
+// Requirements this version understands. Repositories that list others
+// cannot be opened.
+const KNOWN_REQUIREMENTS: &[&str] = &[
+    TEXT_ENTRIES_REQUIREMENT,
+    TREE_MANIFEST_REQUIREMENT,
+    DIRSTATE_V2_REQUIREMENT,
+];
+
+/// Requirements of newly initialized repositories.
+pub const DEFAULT_REQUIREMENTS: &[&str] = &[TEXT_ENTRIES_REQUIREMENT];
+
+/// Files whose nodeids differ between two manifests, with the old and new
+/// nodeid. Files that only changed flags appear with equal nodeids.
+pub type ManifestChanges = BTreeMap<PathBuf, (Option<[u8; 20]>, Option<[u8; 20]>)>;
+
+/// The files found in the working copy by `Repository::walk`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Walk {
+    pub files: BTreeSet<PathBuf>,
+    pub ignored: BTreeSet<PathBuf>,
+    /// Directories whose listing the dirstate can cache.
+    pub directories: HashMap<PathBuf, CachedDirectory>,
+}
+
+// One directory's part of a `Walk`.
+#[derive(Default)]
+struct Listing {
+    directories: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+    ignored: Vec<PathBuf>,
+    cached: Option<CachedDirectory>,
+}
+
+const NULL_NODEID: [u8; 20] = [0u8; 20];
+
+/// How changelog and manifest entries are serialized.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryFormat {
+    Bincode,
+    Text,
+}
+
+impl EntryFormat {
+    pub fn encode_changeset(self, changeset: &Changeset) -> Result<Vec<u8>> {
+        match self {
+            EntryFormat::Bincode => changeset.encode(),
+            EntryFormat::Text => Ok(changeset.to_text()),
+        }
+    }
+
+    pub fn decode_changeset(self, bytes: &[u8]) -> Result<Changeset> {
+        match self {
+            EntryFormat::Bincode => Changeset::decode(bytes),
+            EntryFormat::Text => Changeset::from_text(bytes),
+        }
+    }
+
+    pub fn encode_manifest(self, manifest: &Manifest) -> Result<Vec<u8>> {
+        match self {
+            EntryFormat::Bincode => manifest.encode(),
+            EntryFormat::Text => Ok(manifest.to_text()),
+        }
+    }
+
+    pub fn decode_manifest(self, bytes: &[u8]) -> Result<Manifest> {
+        match self {
+            EntryFormat::Bincode => Manifest::decode(bytes),
+            EntryFormat::Text => Manifest::from_text(bytes),
+        }
+    }
+}
+
 impl Repository {
     pub fn new<P: AsRef<Path>>(worktree: P) -> Repository {
         Repository {
@@ -23,11 +122,50 @@ impl Repository {
     }
 
     pub fn from_cwd() -> Result<Repository> {
-        std::env::current_dir()?
+        let repo = std::env::current_dir()?
             .ancestors()
             .find(|path| path.join(REPO_DIR).exists())
             .map(Repository::new)
-            .context("Failed to find Mercurial repository.")
+            .context("Failed to find Mercurial repository.")?;
+        for requirement in repo.requirements()? {
+            if !KNOWN_REQUIREMENTS.contains(&requirement.as_str()) {
+                bail!(
+                    "Repository requires features unknown to this hg-rs: {}.",
+                    requirement
+                );
+            }
+        }
+        Ok(repo)
+    }
+
+    /// The features listed in `requires`, which readers must support.
+    pub fn requirements(&self) -> Result<BTreeSet<String>> {
+        let path = self.hg_dir.join("requires");
+        if !path.exists() {
+            return Ok(BTreeSet::new());
+        }
+        Ok(read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect())
+    }
+
+    pub fn entry_format(&self) -> Result<EntryFormat> {
+        Ok(if self.requirements()?.contains(TEXT_ENTRIES_REQUIREMENT) {
+            EntryFormat::Text
+        } else {
+            EntryFormat::Bincode
+        })
+    }
+
+    pub fn tree_manifests(&self) -> Result<bool> {
+        Ok(self.requirements()?.contains(TREE_MANIFEST_REQUIREMENT))
+    }
+
+    pub fn dirstate_v2(&self) -> Result<bool> {
+        Ok(self.requirements()?.contains(DIRSTATE_V2_REQUIREMENT))
     }
 
     pub fn repo_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
@@ -38,15 +176,24 @@ impl Repository {
         self.worktree.join(path)
     }
 
-    pub fn init(&self) -> Result<()> {
+    pub fn init(&self, requirements: &[&str]) -> Result<()> {
         create_dir(&self.hg_dir)?;
         create_dir(self.repo_path("cache"))?;
         create_dir_all(self.repo_path("store").join("data"))?;
+        let requirements: String = requirements
+            .iter()
+            .map(|requirement| format!("{}\n", requirement))
+            .collect();
+        write_atomic(self.repo_path("requires"), requirements)?;
         Ok(())
     }
 
     pub fn revlog<P: AsRef<Path>>(&self, path: P) -> Result<RevLog> {
-        RevLog::for_file(self.hg_dir.join("store").join("data").join(path))
+        let path = self.hg_dir.join("store").join("data").join(path);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        RevLog::for_file(path)
     }
 
     pub fn manifest_revlog(&self) -> Result<RevLog> {
@@ -56,6 +203,17 @@ impl Repository {
         )
     }
 
+    // The manifest revlog of a directory of a tree manifest. The root
+    // directory uses the flat manifest's revlog.
+    fn dir_manifest_revlog(&self, dir: &Path) -> Result<RevLog> {
+        if dir.as_os_str().is_empty() {
+            return self.manifest_revlog();
+        }
+        let path = self.hg_dir.join("store").join("meta").join(dir);
+        create_dir_all(&path)?;
+        RevLog::new(path.join("00manifest.i"), path.join("00manifest.d"))
+    }
+
     pub fn changelog_revlog(&self) -> Result<RevLog> {
         RevLog::new(
             self.hg_dir.join("store").join("00changelog.i"),
@@ -64,23 +222,754 @@ impl Repository {
     }
 
     pub fn dirstate(&self) -> Result<Dirstate> {
-        Dirstate::from_file(self.hg_dir.join("dirstate"))
+        if self.dirstate_v2()? {
+            Dirstate::from_v2_file(self.hg_dir.join("dirstate"))
+        } else {
+            Dirstate::from_file(self.hg_dir.join("dirstate"))
+        }
     }
 
     pub fn commit_dirstate(&self, dirstate: Dirstate) -> Result<()> {
-        dirstate.write_to_file(self.hg_dir.join("dirstate"))
+        self.backup("dirstate")?;
+        if self.dirstate_v2()? {
+            dirstate.write_to_v2_file(self.hg_dir.join("dirstate"))
+        } else {
+            dirstate.write_to_file(self.hg_dir.join("dirstate"))
+        }
+    }
+
+    // The branch that the next commit will be on.
+    pub fn branch(&self) -> Result<String> {
+        let path = self.hg_dir.join("branch");
+        if path.exists() {
+            Ok(read_to_string(path)?.trim().to_string())
+        } else {
+            Ok(DEFAULT_BRANCH.to_string())
+        }
+    }
+
+    pub fn set_branch(&self, branch: &str) -> Result<()> {
+        self.backup("branch")?;
+        write_atomic(self.hg_dir.join("branch"), format!("{}\n", branch))
+    }
+
+    // The heads of each named branch: changesets without a child on the same
+    // branch, in increasing order.
+    pub fn branch_heads(&self) -> Result<BTreeMap<String, Vec<u32>>> {
+        let mut changelog = self.changelog_revlog()?;
+        let format = self.entry_format()?;
+        let size = changelog.size()?;
+        let mut branches: Vec<String> = Vec::with_capacity(size as usize);
+        let mut is_head = vec![true; size as usize];
+        for rev in 0..size {
+            let changeset = format.decode_changeset(&changelog.get_hunk(rev)?)?;
+            let record = changelog.get_record(rev)?;
+            for parent in record
+                .parent1_revision
+                .iter()
+                .chain(&record.parent2_revision)
+            {
+                if branches[*parent as usize] == changeset.branch() {
+                    is_head[*parent as usize] = false;
+                }
+            }
+            branches.push(changeset.branch().to_string());
+        }
+        let mut heads: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+        for (rev, branch) in branches.into_iter().enumerate() {
+            if is_head[rev] {
+                heads.entry(branch).or_default().push(rev as u32);
+            }
+        }
+        Ok(heads)
+    }
+
+    pub fn bookmarks(&self) -> Result<Bookmarks> {
+        Bookmarks::from_file(
+            self.hg_dir.join("bookmarks"),
+            self.hg_dir.join("bookmarks.current"),
+        )
+    }
+
+    pub fn commit_bookmarks(&self, bookmarks: Bookmarks) -> Result<()> {
+        self.backup("bookmarks")?;
+        self.backup("bookmarks.current")?;
+        bookmarks.write_to_file(
+            self.hg_dir.join("bookmarks"),
+            self.hg_dir.join("bookmarks.current"),
+        )
+    }
+
+    // Global tags are read from `.hgtags` on every head, oldest head first, and
+    // cached until the tip changes.
+    pub fn tags(&self) -> Result<Tags> {
+        let mut changelog = self.changelog_revlog()?;
+        let tip = changelog.size()?.checked_sub(1).unwrap_or(NULLID_REVISION);
+        let tip_hash = changelog.get_record(tip)?.hash;
+        let cache_key = format!("{} {}", tip, hex::encode(tip_hash));
+        let cache_path = self.repo_path("cache").join("tags");
+
+        let mut global = BTreeMap::new();
+        let cached = if cache_path.exists() {
+            read_to_string(&cache_path)?
+        } else {
+            String::new()
+        };
+        match cached.split_once('\n') {
+            Some((key, cached_tags)) if key == cache_key => {
+                tags::parse_into(cached_tags, &mut global)
+            }
+            _ => {
+                for head in changelog.heads()? {
+                    let manifest = self.manifest(&self.changeset(head)?)?;
+                    if let Some(nodeid) = manifest.entries.get(Path::new(TAGS_FILE)) {
+                        let text = String::from_utf8(self.file_data(TAGS_FILE, nodeid)?)?;
+                        tags::parse_into(&text, &mut global);
+                    }
+                }
+                create_dir_all(self.repo_path("cache"))?;
+                write_atomic(
+                    &cache_path,
+                    format!("{}\n{}", cache_key, tags::format(&global)),
+                )?;
+            }
+        }
+        Ok(Tags {
+            global,
+            local: tags::read_file(self.hg_dir.join("localtags"))?,
+            tip: tip_hash,
+        })
+    }
+
+    pub fn commit_local_tags(&self, local: &BTreeMap<String, [u8; 20]>) -> Result<()> {
+        self.backup("localtags")?;
+        write_atomic(self.hg_dir.join("localtags"), tags::format(local))
+    }
+
+    pub fn config(&self) -> Result<Config> {
+        Config::layered(Some(&self.hg_dir.join("hgrc")))
+    }
+
+    pub fn mergestate(&self) -> Result<MergeState> {
+        MergeState::from_file(self.hg_dir.join("merge").join("state"))
+    }
+
+    pub fn commit_mergestate(&self, mergestate: MergeState) -> Result<()> {
+        create_dir_all(self.hg_dir.join("merge"))?;
+        self.backup("merge/state")?;
+        mergestate.write_to_file(self.hg_dir.join("merge").join("state"))
+    }
+
+    pub fn clear_mergestate(&self) -> Result<()> {
+        let merge_dir = self.hg_dir.join("merge");
+        if merge_dir.exists() {
+            remove_dir_all(merge_dir)?;
+        }
+        Ok(())
+    }
+
+    // Keep the current version of a file in the repository directory under
+    // `backup/`, for `recover`, before it is replaced. The file stays where
+    // it is, as the backup is a hard link.
+    fn backup(&self, name: &str) -> Result<()> {
+        let backup = self.repo_path("backup").join(name);
+        if backup.exists() {
+            std::fs::remove_file(&backup)?;
+        }
+        let path = self.repo_path(name);
+        if path.exists() {
+            create_dir_all(backup.parent().expect("Backups are in a directory."))?;
+            std::fs::hard_link(path, backup)?;
+        }
+        Ok(())
+    }
+
+    /// Remove temporary files left by interrupted writes, and restore the
+    /// state files that can't be read from their backups.
+    /// Returns the restored files.
+    pub fn recover(&self) -> Result<Vec<PathBuf>> {
+        let mut directories = vec![self.hg_dir.clone()];
+        while let Some(directory) = directories.pop() {
+            for dir_entry in std::fs::read_dir(directory)? {
+                let path = dir_entry?.path();
+                if path.is_dir() {
+                    if !path.ends_with("store") && !path.ends_with("backup") {
+                        directories.push(path);
+                    }
+                } else if path.extension().is_some_and(|extension| extension == "tmp") {
+                    std::fs::remove_file(path)?;
+                }
+            }
+        }
+
+        type Check = fn(&Repository) -> Result<()>;
+        let checks: [(&str, Check); 6] = [
+            ("requires", |repo| repo.requirements().map(drop)),
+            ("dirstate", |repo| repo.dirstate().map(drop)),
+            ("branch", |repo| {
+                if repo.branch()?.is_empty() {
+                    bail!("The branch name is empty.");
+                }
+                Ok(())
+            }),
+            ("bookmarks", |repo| repo.bookmarks().map(drop)),
+            ("localtags", |repo| {
+                tags::read_file(repo.repo_path("localtags")).map(drop)
+            }),
+            ("merge/state", |repo| repo.mergestate().map(drop)),
+        ];
+        let mut restored = Vec::new();
+        for (name, check) in checks {
+            let path = self.repo_path(name);
+            let backup = self.repo_path("backup").join(name);
+            if !path.exists() || !backup.exists() {
+                continue;
+            }
+            if check(self).is_err() {
+                std::fs::copy(&backup, temp_path(&path))?;
+                std::fs::rename(temp_path(&path), &path)?;
+                check(self).with_context(|| format!("Failed to recover {}.", name))?;
+                restored.push(PathBuf::from(name));
+            }
+        }
+        Ok(restored)
+    }
+
+    // Find the changelog revision of a changeset nodeid.
+    pub fn changelog_rev(&self, hash: &[u8; 20]) -> Result<u32> {
+        self.changelog_revlog()?
+            .rev_for_hash(hash)?
+            .with_context(|| format!("Unknown changeset {}.", hex::encode(hash)))
+    }
+
+    // Resolve a revision specifier to a changelog revision. In order of
+    // precedence, a specifier is one of
+    // - `null`, `tip` or `.` (the working copy parent),
+    // - a revision number, or a negative offset from the tip,
+    // - a full changeset hash,
+    // - a bookmark, tag or branch name, or
+    // - a unique prefix of a changeset hash.
+    pub fn lookup(&self, spec: &str) -> Result<u32> {
+        let mut changelog = self.changelog_revlog()?;
+        let size = changelog.size()?;
+        match spec {
+            "null" => return Ok(NULLID_REVISION),
+            "tip" => return Ok(size.checked_sub(1).unwrap_or(NULLID_REVISION)),
+            "." => return self.changelog_rev(&self.dirstate()?.parent1_hash),
+            _ => {}
+        }
+        if let Ok(number) = spec.parse::<i64>() {
+            if (0..size as i64).contains(&number) {
+                return Ok(number as u32);
+            }
+            if (-(size as i64)..0).contains(&number) {
+                return Ok((size as i64 + number) as u32);
+            }
+        }
+        if spec.len() == 40 {
+            let mut nodeid = [0u8; 20];
+            if hex::decode_to_slice(spec, &mut nodeid).is_ok() {
+                if let Some(rev) = changelog.rev_for_hash(&nodeid)? {
+                    return Ok(rev);
+                }
+            }
+        }
+        if let Some(nodeid) = self.bookmarks()?.get(spec) {
+            return self.changelog_rev(nodeid);
+        }
+        if let Some(nodeid) = self.tags()?.get(spec) {
+            return self.changelog_rev(nodeid);
+        }
+        if let Some(heads) = self.branch_heads()?.get(spec) {
+            let mut open_heads = Vec::new();
+            for rev in heads {
+                if !self.changeset(*rev)?.closes_branch() {
+                    open_heads.push(*rev);
+                }
+            }
+            return Ok(*open_heads
+                .last()
+                .or_else(|| heads.last())
+                .expect("Branches have heads."));
+        }
+        if !spec.is_empty() && spec.chars().all(|c| c.is_ascii_hexdigit()) {
+            let prefix = spec.to_ascii_lowercase();
+            let mut matches = Vec::new();
+            for rev in 0..size {
+                if hex::encode(changelog.get_record(rev)?.hash).starts_with(&prefix) {
+                    matches.push(rev);
+                }
+            }
+            match matches.as_slice() {
+                [rev] => return Ok(*rev),
+                [] => {}
+                _ => bail!("Ambiguous identifier {}.", spec),
+            }
+        }
+        bail!("Unknown revision {}.", spec)
+    }
+
+    // Read a changeset. The null revision has an empty changeset.
+    pub fn changeset(&self, rev: u32) -> Result<Changeset> {
+        if rev == NULLID_REVISION {
+            return Ok(Changeset::default());
+        }
+        let hunk = self.changelog_revlog()?.get_hunk(rev)?;
+        self.entry_format()?.decode_changeset(&hunk)
+    }
+
+    // Read the manifest that a changeset points to.
+    pub fn manifest(&self, changeset: &Changeset) -> Result<Manifest> {
+        self.read_manifest(&changeset.manifest_nodeid)
+    }
+
+    /// Read a manifest by nodeid, assembling tree manifests into one.
+    pub fn read_manifest(&self, nodeid: &[u8; 20]) -> Result<Manifest> {
+        let mut manifest = Manifest::default();
+        if *nodeid == NULL_NODEID {
+            return Ok(manifest);
+        }
+        if self.tree_manifests()? {
+            self.read_tree(Path::new(""), nodeid, &mut manifest)?;
+            return Ok(manifest);
+        }
+        let mut manifest_revlog = self.manifest_revlog()?;
+        let rev = manifest_revlog
+            .rev_for_hash(nodeid)?
+            .context("Failed to find manifest.")?;
+        self.entry_format()?
+            .decode_manifest(&manifest_revlog.get_hunk(rev)?)
+    }
+
+    fn read_tree(&self, dir: &Path, nodeid: &[u8; 20], manifest: &mut Manifest) -> Result<()> {
+        let node = self.dir_manifest(dir, nodeid)?;
+        for (name, (nodeid, flag)) in node.files {
+            manifest.insert(dir.join(name), nodeid, flag);
+        }
+        for (name, nodeid) in node.dirs {
+            self.read_tree(&dir.join(name), &nodeid, manifest)?;
+        }
+        Ok(())
+    }
+
+    // Read one directory of a tree manifest. The null nodeid is an empty
+    // directory.
+    fn dir_manifest(&self, dir: &Path, nodeid: &[u8; 20]) -> Result<DirManifest> {
+        if *nodeid == NULL_NODEID {
+            return Ok(DirManifest::default());
+        }
+        let mut revlog = self.dir_manifest_revlog(dir)?;
+        let rev = revlog
+            .rev_for_hash(nodeid)?
+            .with_context(|| format!("Failed to find manifest of {}/.", dir.display()))?;
+        DirManifest::from_text(&revlog.get_hunk(rev)?)
+    }
+
+    /// Store a manifest with the given parent manifests, returning its
+    /// nodeid. With tree manifests, directories that are unchanged from a
+    /// parent keep the parent's nodeid rather than being written again.
+    pub fn write_manifest(
+        &self,
+        manifest: &Manifest,
+        parents: [[u8; 20]; 2],
+        link_revision: u32,
+    ) -> Result<[u8; 20]> {
+        if self.tree_manifests()? {
+            return self.write_tree(Path::new(""), manifest, parents, link_revision);
+        }
+        let mut manifest_revlog = self.manifest_revlog()?;
+        let parent1 = manifest_revlog.rev_for_hash(&parents[0])?;
+        let parent2 = manifest_revlog.rev_for_hash(&parents[1])?;
+        let record = manifest_revlog.add_revision_with_parents(
+            &self.entry_format()?.encode_manifest(manifest)?,
+            parent1,
+            parent2,
+            link_revision,
+        )?;
+        Ok(record.hash)
+    }
+
+    fn write_tree(
+        &self,
+        dir: &Path,
+        manifest: &Manifest,
+        parents: [[u8; 20]; 2],
+        link_revision: u32,
+    ) -> Result<[u8; 20]> {
+        let old = [
+            self.dir_manifest(dir, &parents[0])?,
+            self.dir_manifest(dir, &parents[1])?,
+        ];
+        let mut node = DirManifest::default();
+        let mut subdirs = BTreeSet::new();
+        // Paths under a directory are contiguous, since paths sort by component.
+        let under_dir = manifest
+            .entries
+            .range::<Path, _>((Bound::Included(dir), Bound::Unbounded))
+            .map_while(|(path, nodeid)| Some((path, path.strip_prefix(dir).ok()?, nodeid)));
+        for (path, relative, nodeid) in under_dir {
+            let mut components = relative.iter();
+            let name = components
+                .next()
+                .context("Manifest has an empty path.")?
+                .to_str()
+                .context("Failed to get Unicode string.")?
+                .to_string();
+            if components.next().is_some() {
+                subdirs.insert(name);
+            } else {
+                node.files.insert(name, (*nodeid, manifest.flag(path)));
+            }
+        }
+        for name in subdirs {
+            let child_parents = [
+                old[0].dirs.get(&name).copied().unwrap_or(NULL_NODEID),
+                old[1].dirs.get(&name).copied().unwrap_or(NULL_NODEID),
+            ];
+            let nodeid =
+                self.write_tree(&dir.join(&name), manifest, child_parents, link_revision)?;
+            node.dirs.insert(name, nodeid);
+        }
+        for (parent, old) in parents.iter().zip(&old) {
+            if *parent != NULL_NODEID && *old == node {
+                return Ok(*parent);
+            }
+        }
+        let mut revlog = self.dir_manifest_revlog(dir)?;
+        let parent1 = revlog.rev_for_hash(&parents[0])?;
+        let parent2 = match parents[1] {
+            parent if parent == parents[0] => None,
+            parent => revlog.rev_for_hash(&parent)?,
+        };
+        let record =
+            revlog.add_revision_with_parents(&node.to_text(), parent1, parent2, link_revision)?;
+        Ok(record.hash)
+    }
+
+    /// The files that differ between two manifests. Directories of tree
+    /// manifests with equal nodeids are skipped without being read. When one
+    /// flat manifest is the only parent of the other, only the files its
+    /// changeset lists are compared.
+    pub fn manifest_changes(&self, old: &[u8; 20], new: &[u8; 20]) -> Result<ManifestChanges> {
+        let mut changes = ManifestChanges::new();
+        if self.tree_manifests()? {
+            self.tree_changes(Path::new(""), old, new, &mut changes)?;
+            return Ok(changes);
+        }
+        let candidates = match self.child_changed_files(old, new)? {
+            Some(files) => Some(files),
+            None => self.child_changed_files(new, old)?,
+        };
+        let (old, new) = (self.read_manifest(old)?, self.read_manifest(new)?);
+        let candidates = match candidates {
+            Some(files) => files,
+            None => return Ok(changes_between(&old, &new)),
+        };
+        for path in candidates {
+            let (old_nodeid, new_nodeid) = (old.entries.get(&path), new.entries.get(&path));
+            if old_nodeid != new_nodeid || old.flag(&path) != new.flag(&path) {
+                changes.insert(path, (old_nodeid.copied(), new_nodeid.copied()));
+            }
+        }
+        Ok(changes)
+    }
+
+    // The files listed by the changeset that wrote manifest `child`, if
+    // `parent` is its only parent. Manifests written without a changeset,
+    // like those of `snapshot`, link to some other changeset and give None.
+    fn child_changed_files(
+        &self,
+        parent: &[u8; 20],
+        child: &[u8; 20],
+    ) -> Result<Option<Vec<PathBuf>>> {
+        let mut manifest_revlog = self.manifest_revlog()?;
+        let rev = match manifest_revlog.rev_for_hash(child)? {
+            Some(rev) => rev,
+            None => return Ok(None),
+        };
+        let record = manifest_revlog.get_record(rev)?;
+        let parent_rev = record.parent1_revision.unwrap_or(NULLID_REVISION);
+        if record.parent2_revision.is_some()
+            || manifest_revlog.get_record(parent_rev)?.hash != *parent
+            || record.link_revision >= self.changelog_revlog()?.size()?
+        {
+            return Ok(None);
+        }
+        let changeset = self.changeset(record.link_revision)?;
+        if changeset.manifest_nodeid != *child {
+            return Ok(None);
+        }
+        Ok(Some(changeset.changed_files))
+    }
+
+    fn tree_changes(
+        &self,
+        dir: &Path,
+        old: &[u8; 20],
+        new: &[u8; 20],
+        changes: &mut ManifestChanges,
+    ) -> Result<()> {
+        if old == new {
+            return Ok(());
+        }
+        let (old, new) = (self.dir_manifest(dir, old)?, self.dir_manifest(dir, new)?);
+        for name in old.files.keys().chain(new.files.keys()) {
+            let (old_file, new_file) = (old.files.get(name), new.files.get(name));
+            if old_file != new_file {
+                changes.insert(
+                    dir.join(name),
+                    (old_file.map(|file| file.0), new_file.map(|file| file.0)),
+                );
+            }
+        }
+        let names: BTreeSet<&String> = old.dirs.keys().chain(new.dirs.keys()).collect();
+        for name in names {
+            let old_nodeid = old.dirs.get(name).unwrap_or(&NULL_NODEID);
+            let new_nodeid = new.dirs.get(name).unwrap_or(&NULL_NODEID);
+            self.tree_changes(&dir.join(name), old_nodeid, new_nodeid, changes)?;
+        }
+        Ok(())
+    }
+
+    // Read the contents of a file at the revision with the given nodeid.
+    pub fn file_data<P: AsRef<Path>>(&self, path: P, nodeid: &[u8; 20]) -> Result<Vec<u8>> {
+        let mut revlog = self.revlog(&path)?;
+        let rev = revlog.rev_for_hash(nodeid)?.ok_or_else(|| {
+            anyhow!(
+                "Failed to find revision {} of {}.",
+                hex::encode(nodeid),
+                path.as_ref().display()
+            )
+        })?;
+        revlog.get_hunk(rev)
     }
+
+    /// Read a file in the working copy. A symlink reads as its target, which
+    /// is how symlinks are stored.
+    pub fn working_data<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let file_path = self.file_path(path);
+        let metadata = std::fs::symlink_metadata(&file_path)
+            .with_context(|| format!("Failed to read {}.", file_path.display()))?;
+        if metadata.file_type().is_symlink() {
+            Ok(std::fs::read_link(&file_path)?
+                .as_os_str()
+                .as_bytes()
+                .to_vec())
+        } else {
+            Ok(std::fs::read(&file_path)?)
+        }
+    }
+
+    /// A matcher for patterns given in the current directory.
+    pub fn matcher(&self, patterns: &Patterns) -> Result<Matcher> {
+        Matcher::new(
+            &self.worktree,
+            &std::env::current_dir()?,
+            patterns,
+            &|expr: &str| crate::fileset::evaluate(self, expr),
+        )
+    }
+
+    /// List the files and symlinks in the working copy, relative to its root.
+    /// Dot-files are ignored, and hidden directories, such as the
+    /// repository's own, are not entered.
+    pub fn walk(&self) -> Result<Walk> {
+        self.walk_cached(&Dirstate::default())
+    }
+
+    /// Cache the directories of the working copy that match `dirstate`, so
+    /// that commands which rewrite the dirstate do not leave stale entries
+    /// behind. Only v2 dirstates store the cache.
+    pub fn refresh_cached_directories(&self, dirstate: &mut Dirstate) -> Result<()> {
+        if self.dirstate_v2()? {
+            let walk = self.walk_cached(dirstate)?;
+            dirstate.set_cached_directories(walk.directories);
+        }
+        Ok(())
+    }
+
+    /// Like `walk`, but directories that the dirstate caches and that are
+    /// unchanged are not read, so their tracked files are left out. The walk
+    /// returns the directories that can be cached now.
+    pub fn walk_cached(&self, dirstate: &Dirstate) -> Result<Walk> {
+        let workers = self.workers()?;
+        let children = dirstate.children();
+        let cached = dirstate.cached_directories();
+        let start = SystemTime::now();
+        let mut walk = Walk::default();
+        let mut directories = vec![PathBuf::new()];
+        // Each round lists the directories found by the previous one.
+        while !directories.is_empty() {
+            let listings = worker::map(workers, &directories, |directory| {
+                let mut listing = Listing::default();
+                let mtime = match std::fs::symlink_metadata(self.worktree.join(directory)) {
+                    Ok(metadata) => metadata.modified()?,
+                    // Only the dirstate's subdirectories can be missing.
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                        return Ok(listing)
+                    }
+                    Err(error) => return Err(error.into()),
+                };
+                let children = children.get(directory);
+                let fingerprint = children.map_or(0, |children| children.fingerprint());
+                if let (Some(children), Some(cached)) = (children, cached.get(directory)) {
+                    if cached.mtime == mtime && cached.fingerprint == fingerprint {
+                        listing.directories = children
+                            .directories
+                            .iter()
+                            .map(|name| directory.join(name))
+                            .collect();
+                        listing.cached = Some(cached.clone());
+                        return Ok(listing);
+                    }
+                }
+                // Directories can be cached when every child is known to the
+                // dirstate, and when they did not change just before the
+                // walk, as a change within their mtime's precision would go
+                // unnoticed.
+                let mut known = children.is_some()
+                    && start
+                        .duration_since(mtime)
+                        .is_ok_and(|age| age >= Duration::from_secs(1));
+                for dir_entry in std::fs::read_dir(self.worktree.join(directory))? {
+                    let dir_entry = dir_entry?;
+                    let name = dir_entry.file_name();
+                    let path = directory.join(&name);
+                    let hidden = name.to_string_lossy().starts_with('.');
+                    if dir_entry.file_type()?.is_dir() {
+                        if !hidden {
+                            known &= children.is_some_and(|c| c.directories.contains(&name));
+                            listing.directories.push(path);
+                        }
+                    } else if hidden {
+                        known = false;
+                        listing.ignored.push(path);
+                    } else {
+                        known &= children.is_some_and(|c| c.files.contains(&name));
+                        listing.files.push(path);
+                    }
+                }
+                if known {
+                    listing.cached = Some(CachedDirectory { mtime, fingerprint });
+                }
+                Ok(listing)
+            })?;
+            let mut subdirectories = Vec::new();
+            for (directory, listing) in directories.into_iter().zip(listings) {
+                subdirectories.extend(listing.directories);
+                walk.files.extend(listing.files);
+                walk.ignored.extend(listing.ignored);
+                if let Some(cached) = listing.cached {
+                    walk.directories.insert(directory, cached);
+                }
+            }
+            directories = subdirectories;
+        }
+        Ok(walk)
+    }
+
+    /// Like `walk`, but only for the given paths, such as those an fsmonitor
+    /// reported as changed. Paths that are missing or directories are left out.
+    pub fn walk_paths<'p>(&self, paths: impl IntoIterator<Item = &'p PathBuf>) -> Walk {
+        let mut walk = Walk::default();
+        for path in paths {
+            let hidden = |name: &std::ffi::OsStr| name.to_string_lossy().starts_with('.');
+            let in_hidden_dir = path.parent().is_some_and(|parent| {
+                parent
+                    .components()
+                    .any(|component| hidden(component.as_os_str()))
+            });
+            let is_file = std::fs::symlink_metadata(self.file_path(path))
+                .is_ok_and(|metadata| !metadata.is_dir());
+            if !is_file || in_hidden_dir {
+                continue;
+            }
+            if path.file_name().is_some_and(hidden) {
+                walk.ignored.insert(path.clone());
+            } else {
+                walk.files.insert(path.clone());
+            }
+        }
+        walk
+    }
+
+    /// The entries of `dirstate` with changes to commit, asking the fsmonitor
+    /// which files to stat if it is enabled.
+    pub fn committable_files<'d>(
+        &self,
+        dirstate: &'d mut Dirstate,
+    ) -> Result<Vec<(&'d PathBuf, &'d mut Entry)>> {
+        let monitor = fsmonitor::query(self, dirstate)?;
+        let candidates = monitor.as_ref().and_then(|monitor| monitor.candidates());
+        dirstate.committable_files(&self.worktree, self.workers()?, candidates)
+    }
+
+    /// The number of threads for walking and checking out the working copy:
+    /// `worker.numcpus`, or the number of CPUs.
+    pub fn workers(&self) -> Result<usize> {
+        match self.config()?.get("worker", "numcpus") {
+            Some(value) => match value.parse::<usize>() {
+                Ok(workers) if workers > 0 => Ok(workers),
+                _ => bail!("worker.numcpus is not a positive integer: {}.", value),
+            },
+            None => Ok(std::thread::available_parallelism().map_or(1, |cpus| cpus.get())),
+        }
+    }
+}
+
+/// Replace a file so that readers, and a crash at any point, see either its
+/// old or its new contents: the contents go to a temporary file in the same
+/// directory, which is synced and renamed over `path`, and then the directory
+/// is synced.
+pub fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> Result<()> {
+    use std::io::Write;
+
+    let path = path.as_ref();
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let temp = temp_path(path);
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(contents.as_ref())
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("Failed to write {}.", path.display()))?;
+    std::fs::rename(&temp, path)?;
+    std::fs::File::open(directory)?.sync_all()?;
+    Ok(())
+}
+
+// Where `write_atomic` writes before renaming.
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn changes_between(old: &Manifest, new: &Manifest) -> ManifestChanges {
+    old.diff(new)
+        .map(|(path, change)| {
+            let nodeid = |node: Option<FileNode>| node.map(|(nodeid, _flag)| *nodeid);
+            (
+                path.to_path_buf(),
+                (nodeid(change.before()), nodeid(change.after())),
+            )
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tempfile;
+    use crate::dirstate::Status;
+    use crate::manifest::Flag;
 
     #[test]
     fn test_repo_path() {
         let repo = Repository::new("f");
-        assert_eq!(repo.repo_path("g"), PathBuf::from("f/hg-rs/g"));
+        assert_eq!(repo.repo_path("g"), PathBuf::from("f/.hg-rs/g"));
     }
 
     #[test]
@@ -89,9 +978,308 @@ mod tests {
         let base_dir = base_dir.path();
         let repo = Repository::new(base_dir);
         assert!(!base_dir.join(REPO_DIR).exists());
-        repo.init()?;
+        repo.init(DEFAULT_REQUIREMENTS)?;
         assert!(base_dir.join(REPO_DIR).exists());
         assert!(base_dir.join(REPO_DIR).join("cache").exists());
+        assert_eq!(repo.entry_format()?, EntryFormat::Text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_manifest() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let repo = Repository::new(base_dir.path());
+        repo.init(&[TEXT_ENTRIES_REQUIREMENT, TREE_MANIFEST_REQUIREMENT])?;
+        let mut manifest = Manifest::default();
+        manifest.insert("a".into(), [1; 20], None);
+        manifest.insert("dir/b".into(), [2; 20], Some(Flag::Executable));
+        manifest.insert("dir/sub/c".into(), [3; 20], None);
+        manifest.insert("other/d".into(), [4; 20], None);
+        let first = repo.write_manifest(&manifest, [NULL_NODEID; 2], 0)?;
+        assert_eq!(repo.read_manifest(&first)?, manifest);
+        assert!(base_dir
+            .path()
+            .join(".hg-rs/store/meta/dir/sub/00manifest.i")
+            .exists());
+
+        // Only the changed directory and its ancestors get new revisions.
+        manifest.insert("other/d".into(), [5; 20], None);
+        manifest.remove("a");
+        let second = repo.write_manifest(&manifest, [first, NULL_NODEID], 1)?;
+        assert_eq!(repo.read_manifest(&second)?, manifest);
+        assert_eq!(repo.dir_manifest_revlog(Path::new("dir"))?.size()?, 1);
+        assert_eq!(repo.dir_manifest_revlog(Path::new("other"))?.size()?, 2);
+        assert_eq!(repo.write_manifest(&manifest, [second, first], 2)?, second);
+
+        let changes = repo.manifest_changes(&first, &second)?;
+        assert_eq!(
+            changes.into_iter().collect::<Vec<_>>(),
+            vec![
+                (PathBuf::from("a"), (Some([1; 20]), None)),
+                ("other/d".into(), (Some([4; 20]), Some([5; 20]))),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_changes() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let repo = Repository::new(base_dir.path());
+        repo.init(DEFAULT_REQUIREMENTS)?;
+        let mut manifest = Manifest::default();
+        manifest.insert("a".into(), [1; 20], None);
+        manifest.insert("b".into(), [2; 20], None);
+        let first = repo.write_manifest(&manifest, [NULL_NODEID; 2], 0)?;
+        manifest.insert("a".into(), [3; 20], None);
+        manifest.insert("b".into(), [2; 20], Some(Flag::Executable));
+        let second = repo.write_manifest(&manifest, [first, NULL_NODEID], 1)?;
+
+        let expected: ManifestChanges = vec![
+            (PathBuf::from("a"), (Some([1; 20]), Some([3; 20]))),
+            ("b".into(), (Some([2; 20]), Some([2; 20]))),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(repo.manifest_changes(&first, &second)?, expected);
+        let reversed: ManifestChanges = expected
+            .into_iter()
+            .map(|(path, (old, new))| (path, (new, old)))
+            .collect();
+        assert_eq!(repo.manifest_changes(&second, &first)?, reversed);
+
+        // Once a changeset links to the child manifest, only the files it
+        // lists are compared.
+        let mut changelog = repo.changelog_revlog()?;
+        let entry_format = repo.entry_format()?;
+        for (manifest_nodeid, changed_files) in [(first, vec![]), (second, vec!["a".into()])] {
+            let changeset = Changeset {
+                manifest_nodeid,
+                changed_files,
+                ..Changeset::default()
+            };
+            changelog.add_revision(&entry_format.encode_changeset(&changeset)?)?;
+        }
+        let expected: ManifestChanges = vec![(PathBuf::from("a"), (Some([1; 20]), Some([3; 20])))]
+            .into_iter()
+            .collect();
+        assert_eq!(repo.manifest_changes(&first, &second)?, expected);
+        assert_eq!(repo.manifest_changes(&second, &first)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let repo = Repository::new(base_dir.path());
+        repo.init(DEFAULT_REQUIREMENTS)?;
+        std::fs::create_dir_all(base_dir.path().join("d/e"))?;
+        std::fs::create_dir(base_dir.path().join(".git"))?;
+        for path in ["a", "d/b", "d/e/c", ".hgtags", "d/.swp", ".git/config"] {
+            std::fs::write(base_dir.path().join(path), path)?;
+        }
+        let walk = repo.walk()?;
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<BTreeSet<_>>();
+        assert_eq!(walk.files, paths(&["a", "d/b", "d/e/c"]));
+        assert_eq!(walk.ignored, paths(&[".hgtags", "d/.swp"]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dirstate_v2() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let repo = Repository::new(base_dir.path());
+        repo.init(&[TEXT_ENTRIES_REQUIREMENT, DIRSTATE_V2_REQUIREMENT])?;
+        std::fs::create_dir_all(base_dir.path().join("d/e"))?;
+        for path in ["a", "u", "d/b", "d/e/c"] {
+            std::fs::write(base_dir.path().join(path), path)?;
+        }
+        let mut dirstate = repo.dirstate()?;
+        for path in ["a", "d/b", "d/e/c"] {
+            let entry = Entry::from_file(base_dir.path().join(path), Status::Normal)?;
+            dirstate.mut_entries().insert(PathBuf::from(path), entry);
+        }
+        dirstate.parent1_hash = [1; 20];
+        repo.commit_dirstate(dirstate)?;
+        let data_files = || -> Result<Vec<PathBuf>> {
+            let mut paths = Vec::new();
+            for dir_entry in std::fs::read_dir(repo.repo_path(""))? {
+                let name = dir_entry?.file_name().to_string_lossy().into_owned();
+                if name.starts_with("dirstate.") {
+                    paths.push(repo.repo_path(name));
+                }
+            }
+            Ok(paths)
+        };
+        let data_file = data_files()?.pop().unwrap();
+        let size = std::fs::metadata(&data_file)?.len();
+
+        // Changing a file at the root only appends the root's node.
+        let mut dirstate = repo.dirstate()?;
+        assert_eq!(dirstate.parent1_hash, [1; 20]);
+        assert_eq!(dirstate.entries().len(), 3);
+        dirstate
+            .mut_entries()
+            .get_mut(Path::new("a"))
+            .unwrap()
+            .status = Status::Added;
+        repo.commit_dirstate(dirstate)?;
+        let appended = std::fs::metadata(&data_file)?.len() - size;
+        assert!(0 < appended && appended < size / 2);
+        let dirstate = repo.dirstate()?;
+        assert_eq!(dirstate.entries()[Path::new("a")].status, Status::Added);
+        assert_eq!(
+            dirstate.entries()[Path::new("d/e/c")].status,
+            Status::Normal
+        );
+
+        // Rewriting the whole tree eventually compacts into a new data file.
+        for i in 0..4 {
+            let mut dirstate = repo.dirstate()?;
+            dirstate
+                .mut_entries()
+                .get_mut(Path::new("d/e/c"))
+                .unwrap()
+                .size = i;
+            repo.commit_dirstate(dirstate)?;
+        }
+        // The old data file is kept for the backup of the previous docket.
+        let mut compacted = data_files()?;
+        compacted.retain(|path| *path != data_file);
+        assert_eq!(compacted.len(), 1);
+        assert!(data_file.exists());
+        assert_eq!(repo.dirstate()?.entries()[Path::new("d/e/c")].size, 3);
+
+        // Directories whose children are all tracked are cached and skipped
+        // until they change.
+        let old = SystemTime::now() - Duration::from_secs(10);
+        for directory in ["", "d", "d/e"] {
+            std::fs::File::open(base_dir.path().join(directory))?.set_modified(old)?;
+        }
+        let mut dirstate = repo.dirstate()?;
+        let walk = repo.walk_cached(&dirstate)?;
+        let mut cached: Vec<&PathBuf> = walk.directories.keys().collect();
+        cached.sort();
+        assert_eq!(cached, [Path::new("d"), Path::new("d/e")]);
+        dirstate.set_cached_directories(walk.directories);
+        repo.commit_dirstate(dirstate)?;
+        std::fs::write(base_dir.path().join("d/new"), "new")?;
+        let walk = repo.walk_cached(&repo.dirstate()?)?;
+        let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<BTreeSet<_>>();
+        assert_eq!(walk.files, paths(&["a", "u", "d/b", "d/new"]));
+
+        // Tracking the new file changes d's children, and a refresh caches
+        // d again under its new fingerprint.
+        std::fs::File::open(base_dir.path().join("d"))?.set_modified(old)?;
+        let mut dirstate = repo.dirstate()?;
+        let entry = Entry::from_file(base_dir.path().join("d/new"), Status::Normal)?;
+        dirstate.mut_entries().insert("d/new".into(), entry);
+        repo.refresh_cached_directories(&mut dirstate)?;
+        let fingerprint = dirstate.children()[Path::new("d")].fingerprint();
+        assert_eq!(
+            dirstate.cached_directories()[Path::new("d")].fingerprint,
+            fingerprint
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let repo = Repository::new(base_dir.path());
+        repo.init(&[TEXT_ENTRIES_REQUIREMENT])?;
+        repo.set_branch("first")?;
+        repo.set_branch("second")?;
+        let mut dirstate = repo.dirstate()?;
+        dirstate.parent1_hash = [1; 20];
+        repo.commit_dirstate(dirstate)?;
+        let mut dirstate = repo.dirstate()?;
+        dirstate.parent1_hash = [2; 20];
+        repo.commit_dirstate(dirstate)?;
+        assert!(repo.recover()?.is_empty());
+
+        // An empty branch, a corrupt dirstate and a leftover temporary file
+        // are restored from the previous versions and removed.
+        std::fs::write(repo.repo_path("branch"), "")?;
+        std::fs::write(repo.repo_path("dirstate"), "garbage")?;
+        std::fs::write(repo.repo_path("bookmarks.tmp"), "partial")?;
+        assert_eq!(
+            repo.recover()?,
+            [PathBuf::from("dirstate"), PathBuf::from("branch")]
+        );
+        assert_eq!(repo.branch()?, "first");
+        assert_eq!(repo.dirstate()?.parent1_hash, [1; 20]);
+        assert!(!repo.repo_path("bookmarks.tmp").exists());
+
+        // Files that are empty because their last entry was deleted are
+        // left alone.
+        let mut bookmarks = repo.bookmarks()?;
+        bookmarks.set("feature", [1; 20]);
+        repo.commit_bookmarks(bookmarks)?;
+        let mut bookmarks = repo.bookmarks()?;
+        bookmarks.remove("feature");
+        repo.commit_bookmarks(bookmarks)?;
+        assert!(repo.recover()?.is_empty());
+        assert_eq!(repo.bookmarks()?.iter().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_working_data() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let repo = Repository::new(base_dir.path());
+        std::fs::write(base_dir.path().join("a"), "contents")?;
+        std::os::unix::fs::symlink("missing", base_dir.path().join("l"))?;
+        assert_eq!(repo.working_data("a")?, b"contents");
+        assert_eq!(repo.working_data("l")?, b"missing");
+        assert!(repo.working_data("missing").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookup() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let repo = Repository::new(base_dir.path());
+        repo.init(DEFAULT_REQUIREMENTS)?;
+        assert_eq!(repo.lookup("tip")?, NULLID_REVISION);
+        assert_eq!(repo.lookup(".")?, NULLID_REVISION);
+
+        let mut changelog = repo.changelog_revlog()?;
+        let mut hashes = Vec::new();
+        for i in 0..20 {
+            let changeset = Changeset {
+                message: i.to_string(),
+                ..Changeset::default()
+            };
+            let entry = repo.entry_format()?.encode_changeset(&changeset)?;
+            hashes.push(hex::encode(changelog.add_revision(&entry)?.hash));
+        }
+        assert_eq!(repo.lookup("null")?, NULLID_REVISION);
+        assert_eq!(repo.lookup("tip")?, 19);
+        assert_eq!(repo.lookup("3")?, 3);
+        assert_eq!(repo.lookup("-1")?, 19);
+        assert_eq!(repo.lookup("-20")?, 0);
+        assert_eq!(repo.lookup(&hashes[7])?, 7);
+        assert_eq!(repo.lookup(&hashes[7][..12])?, 7);
+        assert_eq!(repo.lookup("default")?, 19);
+        assert!(repo.lookup("nonexistent").is_err());
+
+        // Numbers are revisions, so look for a shared leading letter.
+        let ambiguous = ["a", "b", "c", "d", "e", "f"]
+            .iter()
+            .find(|prefix| {
+                hashes
+                    .iter()
+                    .filter(|hash| hash.starts_with(*prefix))
+                    .count()
+                    > 1
+            })
+            .expect("Some hashes share a leading letter.");
+        assert!(repo
+            .lookup(ambiguous)
+            .unwrap_err()
+            .to_string()
+            .contains("Ambiguous"));
         Ok(())
     }
 }
@@ -1,13 +1,28 @@
-use std::convert::TryInto;
+use std::collections::BTreeSet;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
 use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
 use sha1::{Digest, Sha1};
 
+use crate::formatter::Format;
 use crate::record::{Record, NULLID_REVISION, RECORD_SIZE};
 
+/// A revision's index record, as shown by `debugindex`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct IndexEntry {
+    pub rev: u32,
+    pub offset: u64,
+    pub length: u32,
+    pub base: u32,
+    pub linkrev: u32,
+    pub node: String,
+    pub p1: String,
+    pub p2: String,
+}
+
 pub struct RevLog {
     index: File,
     data: File,
@@ -66,42 +81,41 @@ impl RevLog {
     }
 
     // Append a revision to the revlog and return the newly created Record.
+    // The new revision's parent is the previous revision.
     pub fn add_revision(&mut self, bytes: &[u8]) -> Result<Record> {
+        let size = self.size()?;
+        let parent1 = if size == 0 { None } else { Some(size - 1) };
+        self.add_revision_with_parents(bytes, parent1, None, 0)
+    }
+
+    // Append a revision with explicit parents and link revision.
+    pub fn add_revision_with_parents(
+        &mut self,
+        bytes: &[u8],
+        parent1: Option<u32>,
+        parent2: Option<u32>,
+        link_revision: u32,
+    ) -> Result<Record> {
+        let parent1 = parent1.filter(|rev| *rev != NULLID_REVISION);
+        let parent2 = parent2.filter(|rev| *rev != NULLID_REVISION);
         let new_rev = self.size()?;
-        let record = if new_rev == 0 {
-            let nodeid = {
-                let mut hasher = Sha1::new();
-                hasher.input([0u8; 20]);
-                hasher.input([0u8; 20]);
-                hasher.input(bytes);
-                hasher.result()
-            };
-            Record {
-                hunk_length: bytes.len() as u32,
-                base_revision: new_rev as u32,
-                hash: nodeid.try_into()?,
-                parent1_revision: None,
-                parent2_revision: None,
-                ..Record::default()
-            }
+        let hunk_offset = if new_rev == 0 {
+            0
         } else {
             let latest_record = self.get_record(new_rev - 1)?;
-            let nodeid = {
-                let mut hasher = Sha1::new();
-                hasher.input([0u8; 20]);
-                hasher.input(latest_record.hash);
-                hasher.input(bytes);
-                hasher.result()
-            };
-            Record {
-                hunk_length: bytes.len() as u32,
-                base_revision: new_rev as u32,
-                hunk_offset: latest_record.hunk_offset + latest_record.hunk_length as u64,
-                hash: nodeid.try_into()?,
-                parent1_revision: Some(new_rev as u32 - 1),
-                parent2_revision: None,
-                ..Record::default()
-            }
+            latest_record.hunk_offset + latest_record.hunk_length as u64
+        };
+        let parent1_hash = self.get_record(parent1.unwrap_or(NULLID_REVISION))?.hash;
+        let parent2_hash = self.get_record(parent2.unwrap_or(NULLID_REVISION))?.hash;
+        let record = Record {
+            hunk_offset,
+            hunk_length: bytes.len() as u32,
+            base_revision: new_rev,
+            link_revision,
+            hash: hash_revision(&parent1_hash, &parent2_hash, bytes),
+            parent1_revision: parent1,
+            parent2_revision: parent2,
+            ..Record::default()
         };
         self.index.write_all(&bincode::serialize(&record)?)?;
         self.data.write_all(bytes)?;
@@ -129,43 +143,112 @@ impl RevLog {
         Ok(buffer)
     }
 
-    pub fn get_last_hunk(&mut self) -> Result<Vec<u8>> {
-        if self.size()? == 0 {
-            Err(anyhow!("The size of the revlog is 0."))
-        } else {
-            self.get_hunk(self.size()? - 1)
+    // Find the revision with the given nodeid.
+    pub fn rev_for_hash(&mut self, hash: &[u8; 20]) -> Result<Option<u32>> {
+        if *hash == [0u8; 20] {
+            return Ok(Some(NULLID_REVISION));
+        }
+        for rev in (0..self.size()?).rev() {
+            if self.get_record(rev)?.hash == *hash {
+                return Ok(Some(rev));
+            }
         }
+        Ok(None)
     }
 
-    pub fn debug_index(&mut self) -> Result<()> {
-        println!("rev offset length base nodeid p1 p2");
+    // The set of ancestors of a revision, including the revision itself.
+    pub fn ancestors(&mut self, rev: u32) -> Result<BTreeSet<u32>> {
+        let mut ancestors = BTreeSet::new();
+        let mut stack = vec![rev];
+        while let Some(rev) = stack.pop() {
+            if rev == NULLID_REVISION || !ancestors.insert(rev) {
+                continue;
+            }
+            let record = self.get_record(rev)?;
+            stack.extend(record.parent1_revision);
+            stack.extend(record.parent2_revision);
+        }
+        Ok(ancestors)
+    }
+
+    // Revisions without children, in increasing order.
+    pub fn heads(&mut self) -> Result<Vec<u32>> {
+        let size = self.size()?;
+        let mut is_head = vec![true; size as usize];
+        for rev in 0..size {
+            let record = self.get_record(rev)?;
+            for parent in record
+                .parent1_revision
+                .iter()
+                .chain(&record.parent2_revision)
+            {
+                is_head[*parent as usize] = false;
+            }
+        }
+        Ok((0..size).filter(|rev| is_head[*rev as usize]).collect())
+    }
+
+    // The greatest common ancestor of two revisions, if any.
+    pub fn common_ancestor(&mut self, a: u32, b: u32) -> Result<Option<u32>> {
+        let ancestors_a = self.ancestors(a)?;
+        let ancestors_b = self.ancestors(b)?;
+        Ok(ancestors_a.intersection(&ancestors_b).max().copied())
+    }
+
+    // One entry per revision, describing its index record.
+    pub fn index_entries(&mut self) -> Result<Vec<IndexEntry>> {
+        let mut entries = Vec::new();
         for rev in 0..self.size()? {
             let record = self.get_record(rev)?;
+            let p1 = self.get_record(record.parent1_revision.unwrap_or(NULLID_REVISION))?;
+            let p2 = self.get_record(record.parent2_revision.unwrap_or(NULLID_REVISION))?;
+            entries.push(IndexEntry {
+                rev,
+                offset: record.hunk_offset,
+                length: record.hunk_length,
+                base: record.base_revision,
+                linkrev: record.link_revision,
+                node: hex::encode(record.hash),
+                p1: hex::encode(p1.hash),
+                p2: hex::encode(p2.hash),
+            });
+        }
+        Ok(entries)
+    }
+
+    pub fn debug_index(&mut self, format: Option<Format>) -> Result<()> {
+        let entries = self.index_entries()?;
+        if let Some(format) = format {
+            return format.write(&entries);
+        }
+        println!("rev offset length base nodeid p1 p2");
+        for entry in entries {
             println!(
                 "{}   {}   {}   {}   {:.12}   {:.12}   {:.12}",
-                rev,
-                record.hunk_offset,
-                record.hunk_length,
-                record.base_revision,
-                hex::encode(record.hash),
-                hex::encode(
-                    self.get_record(record.parent1_revision.unwrap_or(NULLID_REVISION))?
-                        .hash
-                ),
-                hex::encode(
-                    self.get_record(record.parent2_revision.unwrap_or(NULLID_REVISION))?
-                        .hash
-                )
+                entry.rev, entry.offset, entry.length, entry.base, entry.node, entry.p1, entry.p2
             );
         }
         Ok(())
     }
 }
 
+// A nodeid is the hash of the sorted parent nodeids followed by the contents.
+fn hash_revision(parent1: &[u8; 20], parent2: &[u8; 20], bytes: &[u8]) -> [u8; 20] {
+    let (first, second) = if parent1 <= parent2 {
+        (parent1, parent2)
+    } else {
+        (parent2, parent1)
+    };
+    let mut hasher = Sha1::new();
+    hasher.input(first);
+    hasher.input(second);
+    hasher.input(bytes);
+    hasher.result().into()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tempfile;
 
     #[test]
     fn test_revlog() -> Result<()> {
@@ -189,7 +272,7 @@ mod tests {
                 hasher.input(b"hello my bytes");
                 hasher.result()
             }
-            .try_into()?,
+            .into(),
             ..Record::default()
         };
         let expected1 = Record {
@@ -205,7 +288,7 @@ mod tests {
                 hasher.input(b"hello my other bytes");
                 hasher.result()
             }
-            .try_into()?,
+            .into(),
             ..Record::default()
         };
 
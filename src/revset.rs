@@ -0,0 +1,1009 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+
+use crate::changeset::Changeset;
+use crate::config::Config;
+use crate::dateutil::DateRange;
+use crate::record::NULLID_REVISION;
+use crate::repository::Repository;
+
+/// A parsed revset expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Symbol(String),
+    String(String),
+    /// `x:y`, revisions between x and y by number.
+    Range(Option<Box<Expr>>, Option<Box<Expr>>),
+    /// `x::y`, descendants of x that are ancestors of y.
+    DagRange(Option<Box<Expr>>, Option<Box<Expr>>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Minus(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Func(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Symbol(String),
+    String(String),
+    LParen,
+    RParen,
+    Comma,
+    Range,
+    DagRange,
+    And,
+    Or,
+    Not,
+    Minus,
+}
+
+fn is_symbol_start(c: char) -> bool {
+    c.is_alphanumeric() || "._@/$".contains(c) || c as u32 > 127
+}
+
+fn is_symbol_char(c: char) -> bool {
+    is_symbol_start(c) || c == '-'
+}
+
+// Split a revset into tokens. A symbol containing `-` is kept whole only if
+// `known` recognizes it, so `my-branch` can be a name while `x-y` is `x - y`.
+fn tokenize(text: &str, known: &dyn Fn(&str) -> bool) -> Result<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        match c {
+            _ if c.is_whitespace() => pos += 1,
+            ':' if chars.get(pos + 1) == Some(&':') => {
+                tokens.push(Token::DagRange);
+                pos += 2;
+            }
+            ':' => {
+                tokens.push(Token::Range);
+                pos += 1;
+            }
+            '(' | ')' | ',' | '&' | '|' | '+' | '!' | '-' => {
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    ',' => Token::Comma,
+                    '&' => Token::And,
+                    '|' | '+' => Token::Or,
+                    '!' => Token::Not,
+                    _ => Token::Minus,
+                });
+                pos += 1;
+            }
+            '\'' | '"' => {
+                let mut value = String::new();
+                pos += 1;
+                loop {
+                    match chars.get(pos) {
+                        None => bail!("Unterminated string in revset {:?}.", text),
+                        Some('\\') if pos + 1 < chars.len() => {
+                            value.push(chars[pos + 1]);
+                            pos += 2;
+                        }
+                        Some(quote) if *quote == c => {
+                            pos += 1;
+                            break;
+                        }
+                        Some(other) => {
+                            value.push(*other);
+                            pos += 1;
+                        }
+                    }
+                }
+                tokens.push(Token::String(value));
+            }
+            _ if is_symbol_start(c) => {
+                let start = pos;
+                while pos < chars.len() && is_symbol_char(chars[pos]) {
+                    pos += 1;
+                }
+                let symbol: String = chars[start..pos].iter().collect();
+                match symbol.as_str() {
+                    "and" => tokens.push(Token::And),
+                    "or" => tokens.push(Token::Or),
+                    "not" => tokens.push(Token::Not),
+                    _ if symbol.contains('-') && !known(&symbol) => {
+                        for (i, part) in symbol.split('-').enumerate() {
+                            if i > 0 {
+                                tokens.push(Token::Minus);
+                            }
+                            if !part.is_empty() {
+                                tokens.push(Token::Symbol(part.to_string()));
+                            }
+                        }
+                    }
+                    _ => tokens.push(Token::Symbol(symbol)),
+                }
+            }
+            _ => bail!("Syntax error in revset {:?} at {:?}.", text, c),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            token => Err(anyhow!("Expected {:?} but found {:?}.", expected, token)),
+        }
+    }
+
+    fn starts_primary(&self) -> bool {
+        match self.peek() {
+            Some(Token::LParen) | Some(Token::Symbol(_)) | Some(Token::String(_)) => true,
+            Some(Token::Minus) => matches!(
+                self.tokens.get(self.pos + 1),
+                Some(Token::Symbol(symbol)) if symbol.parse::<u32>().is_ok()
+            ),
+            _ => false,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_not()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                    expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+                }
+                Some(Token::Minus) => {
+                    self.next();
+                    expr = Expr::Minus(Box::new(expr), Box::new(self.parse_not()?));
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_range()
+    }
+
+    fn parse_range(&mut self) -> Result<Expr> {
+        let start = match self.peek() {
+            Some(Token::Range) | Some(Token::DagRange) => None,
+            _ => Some(Box::new(self.parse_primary()?)),
+        };
+        let dag = match self.peek() {
+            Some(Token::Range) => false,
+            Some(Token::DagRange) => true,
+            _ => return Ok(*start.expect("A primary was parsed.")),
+        };
+        self.next();
+        let end = if self.starts_primary() {
+            Some(Box::new(self.parse_primary()?))
+        } else {
+            None
+        };
+        Ok(if dag {
+            Expr::DagRange(start, end)
+        } else {
+            Expr::Range(start, end)
+        })
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::Symbol(name)) if self.peek() == Some(&Token::LParen) => {
+                self.next();
+                let mut args = Vec::new();
+                if self.peek() == Some(&Token::RParen) {
+                    self.next();
+                    return Ok(Expr::Func(name, args));
+                }
+                loop {
+                    args.push(self.parse_or()?);
+                    match self.next() {
+                        Some(Token::Comma) => continue,
+                        Some(Token::RParen) => return Ok(Expr::Func(name, args)),
+                        token => bail!("Expected ',' or ')' but found {:?}.", token),
+                    }
+                }
+            }
+            Some(Token::Symbol(symbol)) => Ok(Expr::Symbol(symbol)),
+            Some(Token::String(string)) => Ok(Expr::String(string)),
+            Some(Token::Minus) => match self.next() {
+                Some(Token::Symbol(number)) if number.parse::<u32>().is_ok() => {
+                    Ok(Expr::Symbol(format!("-{}", number)))
+                }
+                token => bail!("Unexpected {:?} after '-'.", token),
+            },
+            token => bail!("Unexpected {:?} in revset.", token),
+        }
+    }
+}
+
+/// Parse a revset. `known` tells whether a symbol containing `-` is a name.
+pub fn parse(text: &str, known: &dyn Fn(&str) -> bool) -> Result<Expr> {
+    let mut parser = Parser {
+        tokens: tokenize(text, known)?,
+        pos: 0,
+    };
+    if parser.peek().is_none() {
+        bail!("Empty revset.");
+    }
+    let expr = parser.parse_or()?;
+    if let Some(token) = parser.peek() {
+        bail!("Unexpected {:?} in revset {:?}.", token, text);
+    }
+    Ok(expr)
+}
+
+/// A user-defined alias from the `[revsetalias]` config section, such as
+/// `mine = author(alice)` or `since($1) = $1::`.
+#[derive(Debug, PartialEq)]
+pub struct Alias {
+    params: Vec<String>,
+    definition: Expr,
+}
+
+pub fn aliases(config: &Config) -> Result<BTreeMap<String, Alias>> {
+    let mut aliases = BTreeMap::new();
+    for (declaration, definition) in config.items("revsetalias") {
+        let (name, params) = match declaration.split_once('(') {
+            Some((name, params)) => (
+                name.trim(),
+                params
+                    .trim_end_matches(')')
+                    .split(',')
+                    .map(|param| param.trim().to_string())
+                    .filter(|param| !param.is_empty())
+                    .collect(),
+            ),
+            None => (declaration.trim(), Vec::new()),
+        };
+        let definition = parse(definition, &|_symbol| false)
+            .with_context(|| format!("Failed to parse revset alias {}.", name))?;
+        aliases.insert(name.to_string(), Alias { params, definition });
+    }
+    Ok(aliases)
+}
+
+// Replace aliases in an expression with their definitions.
+fn expand(
+    expr: Expr,
+    aliases: &BTreeMap<String, Alias>,
+    expanding: &mut Vec<String>,
+) -> Result<Expr> {
+    let expand_box =
+        |expr: Option<Box<Expr>>, expanding: &mut Vec<String>| -> Result<Option<Box<Expr>>> {
+            expr.map(|expr| expand(*expr, aliases, expanding).map(Box::new))
+                .transpose()
+        };
+    let (name, args) = match expr {
+        Expr::Symbol(name)
+            if aliases
+                .get(&name)
+                .is_some_and(|alias| alias.params.is_empty()) =>
+        {
+            (name, Vec::new())
+        }
+        Expr::Func(name, args) if aliases.contains_key(&name) => (name, args),
+        Expr::Func(name, args) => {
+            let args = args
+                .into_iter()
+                .map(|arg| expand(arg, aliases, expanding))
+                .collect::<Result<_>>()?;
+            return Ok(Expr::Func(name, args));
+        }
+        Expr::Range(start, end) => {
+            return Ok(Expr::Range(
+                expand_box(start, expanding)?,
+                expand_box(end, expanding)?,
+            ))
+        }
+        Expr::DagRange(start, end) => {
+            return Ok(Expr::DagRange(
+                expand_box(start, expanding)?,
+                expand_box(end, expanding)?,
+            ))
+        }
+        Expr::And(a, b) => {
+            return Ok(Expr::And(
+                Box::new(expand(*a, aliases, expanding)?),
+                Box::new(expand(*b, aliases, expanding)?),
+            ))
+        }
+        Expr::Or(a, b) => {
+            return Ok(Expr::Or(
+                Box::new(expand(*a, aliases, expanding)?),
+                Box::new(expand(*b, aliases, expanding)?),
+            ))
+        }
+        Expr::Minus(a, b) => {
+            return Ok(Expr::Minus(
+                Box::new(expand(*a, aliases, expanding)?),
+                Box::new(expand(*b, aliases, expanding)?),
+            ))
+        }
+        Expr::Not(a) => return Ok(Expr::Not(Box::new(expand(*a, aliases, expanding)?))),
+        expr => return Ok(expr),
+    };
+
+    let alias = &aliases[&name];
+    if args.len() != alias.params.len() {
+        bail!(
+            "Revset alias {} takes {} arguments but was given {}.",
+            name,
+            alias.params.len(),
+            args.len()
+        );
+    }
+    if expanding.contains(&name) {
+        bail!("Infinite expansion of revset alias {}.", name);
+    }
+    expanding.push(name);
+    let args = args
+        .into_iter()
+        .map(|arg| expand(arg, aliases, expanding))
+        .collect::<Result<Vec<_>>>()?;
+    let expanded = expand(
+        substitute(alias.definition.clone(), &alias.params, &args),
+        aliases,
+        expanding,
+    )?;
+    expanding.pop();
+    Ok(expanded)
+}
+
+// Replace alias parameters like `$1` with their arguments.
+fn substitute(expr: Expr, params: &[String], args: &[Expr]) -> Expr {
+    let sub = |expr: Box<Expr>| Box::new(substitute(*expr, params, args));
+    match expr {
+        Expr::Symbol(symbol) => match params.iter().position(|param| *param == symbol) {
+            Some(index) => args[index].clone(),
+            None => Expr::Symbol(symbol),
+        },
+        Expr::Range(start, end) => Expr::Range(start.map(sub), end.map(sub)),
+        Expr::DagRange(start, end) => Expr::DagRange(start.map(sub), end.map(sub)),
+        Expr::And(a, b) => Expr::And(sub(a), sub(b)),
+        Expr::Or(a, b) => Expr::Or(sub(a), sub(b)),
+        Expr::Minus(a, b) => Expr::Minus(sub(a), sub(b)),
+        Expr::Not(a) => Expr::Not(sub(a)),
+        Expr::Func(name, func_args) => Expr::Func(
+            name,
+            func_args
+                .into_iter()
+                .map(|arg| substitute(arg, params, args))
+                .collect(),
+        ),
+        expr => expr,
+    }
+}
+
+/// Evaluate a revset against the repository, returning revisions in the
+/// order the expression produces them.
+pub fn revs(repo: &Repository, spec: &str) -> Result<Vec<u32>> {
+    let expr = parse(spec, &|symbol| repo.lookup(symbol).is_ok())?;
+    let expr = expand(expr, &aliases(&repo.config()?)?, &mut Vec::new())?;
+    Evaluator::new(repo)?.eval(&expr)
+}
+
+/// Evaluate a single revision specifier or revset, taking its last revision.
+pub fn single(repo: &Repository, spec: &str) -> Result<u32> {
+    revs(repo, spec)?
+        .last()
+        .copied()
+        .with_context(|| format!("Empty revision set {}.", spec))
+}
+
+struct Evaluator<'a> {
+    repo: &'a Repository,
+    changesets: Vec<Changeset>,
+    parents: Vec<Vec<u32>>,
+    children: Vec<Vec<u32>>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(repo: &'a Repository) -> Result<Evaluator<'a>> {
+        let mut changelog = repo.changelog_revlog()?;
+        let format = repo.entry_format()?;
+        let size = changelog.size()?;
+        let mut changesets = Vec::with_capacity(size as usize);
+        let mut parents = Vec::with_capacity(size as usize);
+        let mut children = vec![Vec::new(); size as usize];
+        for rev in 0..size {
+            changesets.push(format.decode_changeset(&changelog.get_hunk(rev)?)?);
+            let record = changelog.get_record(rev)?;
+            let rev_parents: Vec<u32> = record
+                .parent1_revision
+                .into_iter()
+                .chain(record.parent2_revision)
+                .collect();
+            for parent in &rev_parents {
+                children[*parent as usize].push(rev);
+            }
+            parents.push(rev_parents);
+        }
+        Ok(Evaluator {
+            repo,
+            changesets,
+            parents,
+            children,
+        })
+    }
+
+    fn size(&self) -> u32 {
+        self.changesets.len() as u32
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<Vec<u32>> {
+        match expr {
+            Expr::Symbol(symbol) | Expr::String(symbol) => Ok(vec![self.repo.lookup(symbol)?]),
+            Expr::Range(start, end) => {
+                let start = match start {
+                    Some(start) => self.eval_set(start)?.first().copied(),
+                    None => Some(0),
+                };
+                let end = match end {
+                    Some(end) => self.eval_set(end)?.last().copied(),
+                    None => self.size().checked_sub(1),
+                };
+                Ok(match (start, end) {
+                    (Some(start), Some(end)) if start <= end => (start..=end).collect(),
+                    (Some(start), Some(end)) => (end..=start).rev().collect(),
+                    _ => Vec::new(),
+                })
+            }
+            Expr::DagRange(start, end) => {
+                let descendants = match start {
+                    Some(start) => self.descendants(&self.eval_set(start)?),
+                    None => (0..self.size()).collect(),
+                };
+                let ancestors = match end {
+                    Some(end) => self.ancestors(&self.eval_set(end)?),
+                    None => (0..self.size()).collect(),
+                };
+                Ok(descendants.intersection(&ancestors).copied().collect())
+            }
+            Expr::And(a, b) => {
+                let b: HashSet<u32> = self.eval(b)?.into_iter().collect();
+                Ok(self
+                    .eval(a)?
+                    .into_iter()
+                    .filter(|rev| b.contains(rev))
+                    .collect())
+            }
+            Expr::Minus(a, b) => {
+                let b: HashSet<u32> = self.eval(b)?.into_iter().collect();
+                Ok(self
+                    .eval(a)?
+                    .into_iter()
+                    .filter(|rev| !b.contains(rev))
+                    .collect())
+            }
+            Expr::Or(a, b) => {
+                let mut revs = self.eval(a)?;
+                let mut seen: HashSet<u32> = revs.iter().copied().collect();
+                revs.extend(self.eval(b)?.into_iter().filter(|rev| seen.insert(*rev)));
+                Ok(revs)
+            }
+            Expr::Not(a) => {
+                let a: HashSet<u32> = self.eval(a)?.into_iter().collect();
+                Ok((0..self.size()).filter(|rev| !a.contains(rev)).collect())
+            }
+            Expr::Func(name, args) => self.func(name, args),
+        }
+    }
+
+    // Evaluate an expression, dropping the null revision.
+    fn eval_set(&self, expr: &Expr) -> Result<Vec<u32>> {
+        Ok(self
+            .eval(expr)?
+            .into_iter()
+            .filter(|rev| *rev != NULLID_REVISION)
+            .collect())
+    }
+
+    fn ancestors(&self, revs: &[u32]) -> BTreeSet<u32> {
+        let mut ancestors = BTreeSet::new();
+        let mut stack: Vec<u32> = revs.to_vec();
+        while let Some(rev) = stack.pop() {
+            if ancestors.insert(rev) {
+                stack.extend(&self.parents[rev as usize]);
+            }
+        }
+        ancestors
+    }
+
+    fn descendants(&self, revs: &[u32]) -> BTreeSet<u32> {
+        let mut descendants: BTreeSet<u32> = revs.iter().copied().collect();
+        if let Some(first) = revs.iter().min() {
+            for rev in *first..self.size() {
+                if self.parents[rev as usize]
+                    .iter()
+                    .any(|parent| descendants.contains(parent))
+                {
+                    descendants.insert(rev);
+                }
+            }
+        }
+        descendants
+    }
+
+    fn filter<F: Fn(&Changeset) -> bool>(&self, predicate: F) -> Vec<u32> {
+        (0..self.size())
+            .filter(|rev| predicate(&self.changesets[*rev as usize]))
+            .collect()
+    }
+
+    fn func(&self, name: &str, args: &[Expr]) -> Result<Vec<u32>> {
+        let arity = |min: usize, max: usize| -> Result<()> {
+            if args.len() < min || args.len() > max {
+                bail!("{}() takes {} to {} arguments.", name, min, max);
+            }
+            Ok(())
+        };
+        let string_arg = |index: usize| -> Result<&str> {
+            match args.get(index) {
+                Some(Expr::Symbol(value)) | Some(Expr::String(value)) => Ok(value),
+                _ => bail!("{}() expects a string argument.", name),
+            }
+        };
+        let number_arg = |index: usize, default: usize| -> Result<usize> {
+            match args.get(index) {
+                None => Ok(default),
+                Some(_) => string_arg(index)?
+                    .parse()
+                    .with_context(|| format!("{}() expects a number.", name)),
+            }
+        };
+        match name {
+            "all" => {
+                arity(0, 0)?;
+                Ok((0..self.size()).collect())
+            }
+            "ancestors" | "descendants" => {
+                arity(1, 1)?;
+                let revs = self.eval_set(&args[0])?;
+                let set = if name == "ancestors" {
+                    self.ancestors(&revs)
+                } else {
+                    self.descendants(&revs)
+                };
+                Ok(set.into_iter().collect())
+            }
+            "heads" | "roots" => {
+                arity(1, 1)?;
+                let revs = self.eval_set(&args[0])?;
+                let set: HashSet<u32> = revs.iter().copied().collect();
+                let relatives = if name == "heads" {
+                    &self.children
+                } else {
+                    &self.parents
+                };
+                let mut result: Vec<u32> = revs
+                    .into_iter()
+                    .filter(|rev| !relatives[*rev as usize].iter().any(|r| set.contains(r)))
+                    .collect();
+                result.sort_unstable();
+                Ok(result)
+            }
+            "parents" | "children" => {
+                arity(1, 1)?;
+                let relatives = if name == "parents" {
+                    &self.parents
+                } else {
+                    &self.children
+                };
+                let set: BTreeSet<u32> = self
+                    .eval_set(&args[0])?
+                    .iter()
+                    .flat_map(|rev| relatives[*rev as usize].iter().copied())
+                    .collect();
+                Ok(set.into_iter().collect())
+            }
+            "head" => {
+                arity(0, 0)?;
+                let mut heads: Vec<u32> =
+                    self.repo.branch_heads()?.into_values().flatten().collect();
+                heads.sort_unstable();
+                Ok(heads)
+            }
+            "merge" => {
+                arity(0, 0)?;
+                Ok((0..self.size())
+                    .filter(|rev| self.parents[*rev as usize].len() == 2)
+                    .collect())
+            }
+            "author" | "user" => {
+                arity(1, 1)?;
+                let matcher = StringMatcher::new(string_arg(0)?)?;
+                Ok(self.filter(|changeset| matcher.matches(&changeset.committer)))
+            }
+            "date" => {
+                arity(1, 1)?;
+                let range = DateRange::parse(string_arg(0)?)?;
+                Ok(self
+                    .filter(|changeset| changeset.time.is_some_and(|time| range.contains(&time))))
+            }
+            "keyword" => {
+                arity(1, 1)?;
+                let keyword = string_arg(0)?.to_lowercase();
+                Ok(self.filter(|changeset| {
+                    changeset.message.to_lowercase().contains(&keyword)
+                        || changeset.committer.to_lowercase().contains(&keyword)
+                        || changeset
+                            .changed_files
+                            .iter()
+                            .any(|path| path.to_string_lossy().to_lowercase().contains(&keyword))
+                }))
+            }
+            "file" => {
+                arity(1, 1)?;
+                let pattern = string_arg(0)?;
+                let matcher = file_matcher(pattern)?;
+                Ok(self
+                    .filter(|changeset| changeset.changed_files.iter().any(|path| matcher(path))))
+            }
+            "branch" => {
+                arity(1, 1)?;
+                let branches: HashSet<String> = match &args[0] {
+                    Expr::String(branch) => vec![branch.clone()].into_iter().collect(),
+                    Expr::Symbol(branch) if self.repo.branch_heads()?.contains_key(branch) => {
+                        vec![branch.clone()].into_iter().collect()
+                    }
+                    expr => self
+                        .eval_set(expr)?
+                        .iter()
+                        .map(|rev| self.changesets[*rev as usize].branch().to_string())
+                        .collect(),
+                };
+                Ok(self.filter(|changeset| branches.contains(changeset.branch())))
+            }
+            "only" => {
+                arity(1, 2)?;
+                let revs = self.eval_set(&args[0])?;
+                let excluded = match args.get(1) {
+                    Some(expr) => self.eval_set(expr)?,
+                    None => {
+                        let revs: HashSet<u32> = revs.iter().copied().collect();
+                        self.func("head", &[])?
+                            .into_iter()
+                            .filter(|rev| !revs.contains(rev))
+                            .collect()
+                    }
+                };
+                let excluded = self.ancestors(&excluded);
+                Ok(self
+                    .ancestors(&revs)
+                    .into_iter()
+                    .filter(|rev| !excluded.contains(rev))
+                    .collect())
+            }
+            "limit" | "first" => {
+                arity(1, 3)?;
+                let count = number_arg(1, 1)?;
+                let offset = number_arg(2, 0)?;
+                Ok(self
+                    .eval(&args[0])?
+                    .into_iter()
+                    .skip(offset)
+                    .take(count)
+                    .collect())
+            }
+            "last" => {
+                arity(1, 2)?;
+                let count = number_arg(1, 1)?;
+                let revs = self.eval(&args[0])?;
+                Ok(revs[revs.len().saturating_sub(count)..].to_vec())
+            }
+            "min" | "max" => {
+                arity(1, 1)?;
+                let revs = self.eval_set(&args[0])?;
+                let rev = if name == "min" {
+                    revs.into_iter().min()
+                } else {
+                    revs.into_iter().max()
+                };
+                Ok(rev.into_iter().collect())
+            }
+            "reverse" => {
+                arity(1, 1)?;
+                let mut revs = self.eval(&args[0])?;
+                revs.reverse();
+                Ok(revs)
+            }
+            "sort" => {
+                arity(1, 2)?;
+                let keys = if args.len() == 2 {
+                    string_arg(1)?
+                } else {
+                    "rev"
+                };
+                let keys = keys
+                    .split_whitespace()
+                    .map(|key| match key.strip_prefix('-') {
+                        Some(key) => (true, key),
+                        None => (false, key),
+                    })
+                    .collect::<Vec<_>>();
+                if let Some((_descending, key)) = keys.iter().find(|(_descending, key)| {
+                    !["rev", "branch", "desc", "user", "author", "date"].contains(key)
+                }) {
+                    bail!("Unknown sort key {}.", key);
+                }
+                // Compare by each key in turn, ties falling through to the
+                // next key and finally to the original order.
+                let changeset = |rev: u32| &self.changesets[rev as usize];
+                let mut revs = self.eval_set(&args[0])?;
+                revs.sort_by(|&a, &b| {
+                    keys.iter()
+                        .map(|&(descending, key)| {
+                            let (a, b) = if descending { (b, a) } else { (a, b) };
+                            match key {
+                                "rev" => a.cmp(&b),
+                                "branch" => changeset(a).branch().cmp(changeset(b).branch()),
+                                "desc" => changeset(a).message.cmp(&changeset(b).message),
+                                "user" | "author" => {
+                                    changeset(a).committer.cmp(&changeset(b).committer)
+                                }
+                                "date" => changeset(a).time.cmp(&changeset(b).time),
+                                _ => unreachable!("Sort keys are checked above."),
+                            }
+                        })
+                        .find(|ordering| ordering.is_ne())
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+                Ok(revs)
+            }
+            "bookmark" | "tag" => {
+                arity(0, 1)?;
+                let names: Vec<(String, [u8; 20])> = if name == "bookmark" {
+                    self.repo
+                        .bookmarks()?
+                        .iter()
+                        .map(|(name, nodeid)| (name.clone(), *nodeid))
+                        .collect()
+                } else {
+                    let tags = self.repo.tags()?;
+                    tags.global
+                        .into_iter()
+                        .chain(tags.local)
+                        .chain(vec![("tip".to_string(), tags.tip)])
+                        .collect()
+                };
+                let wanted = if args.is_empty() {
+                    None
+                } else {
+                    Some(string_arg(0)?)
+                };
+                let mut revs = BTreeSet::new();
+                for (name, nodeid) in names {
+                    if wanted.is_none_or(|wanted| wanted == name) && nodeid != [0u8; 20] {
+                        revs.insert(self.repo.changelog_rev(&nodeid)?);
+                    }
+                }
+                if let (Some(wanted), true) = (wanted, revs.is_empty()) {
+                    bail!("No {} named {}.", name, wanted);
+                }
+                Ok(revs.into_iter().collect())
+            }
+            _ => bail!("Unknown revset function {}.", name),
+        }
+    }
+}
+
+// A case-insensitive substring, or a regular expression if prefixed with `re:`.
+enum StringMatcher {
+    Substring(String),
+    Regex(Regex),
+}
+
+impl StringMatcher {
+    fn new(pattern: &str) -> Result<StringMatcher> {
+        Ok(match pattern.strip_prefix("re:") {
+            Some(regex) => StringMatcher::Regex(Regex::new(regex)?),
+            None => StringMatcher::Substring(pattern.to_lowercase()),
+        })
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            StringMatcher::Substring(substring) => text.to_lowercase().contains(substring),
+            StringMatcher::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+type PathMatcher = Box<dyn Fn(&Path) -> bool>;
+
+// Match paths by glob when the pattern has wildcards, and otherwise by the
+// path itself or a directory containing it.
+fn file_matcher(pattern: &str) -> Result<PathMatcher> {
+    if pattern.contains(['*', '?', '[']) {
+        let mut regex = String::from("^");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    regex.push_str(".*");
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                '[' | ']' => regex.push(c),
+                _ => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        let regex = Regex::new(&regex)?;
+        Ok(Box::new(move |path: &Path| {
+            regex.is_match(&path.to_string_lossy())
+        }))
+    } else {
+        let prefix = Path::new(pattern).to_path_buf();
+        Ok(Box::new(move |path: &Path| path.starts_with(&prefix)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::DEFAULT_REQUIREMENTS;
+
+    fn symbol(name: &str) -> Box<Expr> {
+        Box::new(Expr::Symbol(name.to_string()))
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let unknown = |_symbol: &str| false;
+        assert_eq!(
+            parse("a or b and not c", &unknown)?,
+            Expr::Or(
+                symbol("a"),
+                Box::new(Expr::And(symbol("b"), Box::new(Expr::Not(symbol("c")))))
+            )
+        );
+        assert_eq!(
+            parse("x-y", &unknown)?,
+            Expr::Minus(symbol("x"), symbol("y"))
+        );
+        assert_eq!(parse("x-y", &|symbol| symbol == "x-y")?, *symbol("x-y"));
+        assert_eq!(
+            parse("0:-1", &unknown)?,
+            Expr::Range(Some(symbol("0")), Some(symbol("-1")))
+        );
+        assert_eq!(
+            parse("::tip", &unknown)?,
+            Expr::DagRange(None, Some(symbol("tip")))
+        );
+        assert_eq!(
+            parse("limit(author('ming li'), 2)", &unknown)?,
+            Expr::Func(
+                "limit".to_string(),
+                vec![
+                    Expr::Func(
+                        "author".to_string(),
+                        vec![Expr::String("ming li".to_string())]
+                    ),
+                    Expr::Symbol("2".to_string()),
+                ]
+            )
+        );
+        assert!(parse("(a", &unknown).is_err());
+        assert!(parse("a b", &unknown).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_aliases() -> Result<()> {
+        let config =
+            Config::parse("[revsetalias]\nmine = author(me)\nsince($1) = $1::\nloop = loop\n")?;
+        let aliases = aliases(&config)?;
+        let expand = |text: &str| expand(parse(text, &|_symbol| false)?, &aliases, &mut Vec::new());
+        assert_eq!(
+            expand("since(mine)")?,
+            Expr::DagRange(
+                Some(Box::new(Expr::Func(
+                    "author".to_string(),
+                    vec![Expr::Symbol("me".to_string())]
+                ))),
+                None
+            )
+        );
+        assert!(expand("loop").is_err());
+        assert!(expand("since()").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_revs() -> Result<()> {
+        let base_dir = tempfile::tempdir()?;
+        let repo = Repository::new(base_dir.path());
+        repo.init(DEFAULT_REQUIREMENTS)?;
+        let mut changelog = repo.changelog_revlog()?;
+        // 0 - 1 - 2
+        //   \
+        //     3 - 4 (merges 2)
+        let commits: [(&str, Option<u32>, Option<u32>); 5] = [
+            ("alice", None, None),
+            ("bob", Some(0), None),
+            ("alice", Some(1), None),
+            ("bob", Some(0), None),
+            ("carol", Some(3), Some(2)),
+        ];
+        for (i, (committer, parent1, parent2)) in commits.iter().enumerate() {
+            let changeset = Changeset {
+                committer: committer.to_string(),
+                message: format!("commit {}", i),
+                ..Changeset::default()
+            };
+            changelog.add_revision_with_parents(
+                &repo.entry_format()?.encode_changeset(&changeset)?,
+                *parent1,
+                *parent2,
+                i as u32,
+            )?;
+        }
+        assert_eq!(revs(&repo, "3:1")?, vec![3, 2, 1]);
+        assert_eq!(revs(&repo, "1::4")?, vec![1, 2, 4]);
+        assert_eq!(revs(&repo, "ancestors(3)")?, vec![0, 3]);
+        assert_eq!(revs(&repo, "descendants(2)")?, vec![2, 4]);
+        assert_eq!(revs(&repo, "heads(all())")?, vec![4]);
+        assert_eq!(revs(&repo, "roots(1::)")?, vec![1]);
+        assert_eq!(revs(&repo, "children(0)")?, vec![1, 3]);
+        assert_eq!(revs(&repo, "parents(4)")?, vec![2, 3]);
+        assert_eq!(revs(&repo, "author(ALICE)")?, vec![0, 2]);
+        assert_eq!(revs(&repo, "keyword('commit 3') or merge()")?, vec![3, 4]);
+        assert_eq!(revs(&repo, "only(2, 3)")?, vec![1, 2]);
+        assert_eq!(revs(&repo, "not author(bob) - 0")?, vec![2, 4]);
+        assert_eq!(revs(&repo, "limit(sort(all(), '-rev'), 2)")?, vec![4, 3]);
+        assert_eq!(
+            revs(&repo, "sort(all(), 'user -rev')")?,
+            vec![2, 0, 3, 1, 4]
+        );
+        assert_eq!(
+            revs(&repo, "sort(all(), '-user rev')")?,
+            vec![4, 1, 3, 0, 2]
+        );
+        assert_eq!(single(&repo, "tip")?, 4);
+        assert!(revs(&repo, "bogus()").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use crate::diff::{matching_blocks, split_lines};
+
+/// How conflicting regions are written to the merge result.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    /// Conflict markers around the local and other versions.
+    Merge,
+    /// Conflict markers that also include the base version.
+    Merge3,
+    /// Both versions without markers, local first.
+    Union,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MergeResult {
+    pub text: Vec<u8>,
+    pub conflicts: bool,
+}
+
+// A region of the merge, given as line ranges into the base, local and
+// other versions.
+#[derive(Debug, PartialEq)]
+enum Region {
+    Unchanged(usize, usize),
+    Same(usize, usize),
+    Local(usize, usize),
+    Other(usize, usize),
+    Conflict {
+        base: (usize, usize),
+        local: (usize, usize),
+        other: (usize, usize),
+    },
+}
+
+// Ranges where base, local and other all agree:
+// (base_start, base_end, local_start, local_end, other_start, other_end).
+type SyncRegion = (usize, usize, usize, usize, usize, usize);
+
+/// Three-way merge `local` and `other` against their common ancestor `base`.
+pub fn merge(base: &[u8], local: &[u8], other: &[u8], style: Style) -> MergeResult {
+    let base = split_lines(base);
+    let local = split_lines(local);
+    let other = split_lines(other);
+
+    let mut text = Vec::new();
+    let mut conflicts = false;
+    let extend = |text: &mut Vec<u8>, lines: &[&[u8]]| {
+        for line in lines {
+            text.extend_from_slice(line);
+        }
+    };
+    let marker = |text: &mut Vec<u8>, marker: &str| {
+        if !text.is_empty() && !text.ends_with(b"\n") {
+            text.push(b'\n');
+        }
+        text.extend_from_slice(marker.as_bytes());
+        text.push(b'\n');
+    };
+    for region in merge_regions(&base, &local, &other) {
+        match region {
+            Region::Unchanged(start, end) => extend(&mut text, &base[start..end]),
+            Region::Same(start, end) | Region::Local(start, end) => {
+                extend(&mut text, &local[start..end])
+            }
+            Region::Other(start, end) => extend(&mut text, &other[start..end]),
+            Region::Conflict {
+                base: (base_start, base_end),
+                local: (local_start, local_end),
+                other: (other_start, other_end),
+            } => match style {
+                Style::Union => {
+                    extend(&mut text, &local[local_start..local_end]);
+                    extend(&mut text, &other[other_start..other_end]);
+                }
+                Style::Merge | Style::Merge3 => {
+                    conflicts = true;
+                    marker(&mut text, "<<<<<<< local");
+                    extend(&mut text, &local[local_start..local_end]);
+                    if style == Style::Merge3 {
+                        marker(&mut text, "||||||| base");
+                        extend(&mut text, &base[base_start..base_end]);
+                    }
+                    marker(&mut text, "=======");
+                    extend(&mut text, &other[other_start..other_end]);
+                    marker(&mut text, ">>>>>>> other");
+                }
+            },
+        }
+    }
+    MergeResult { text, conflicts }
+}
+
+fn sync_regions(base: &[&[u8]], local: &[&[u8]], other: &[&[u8]]) -> Vec<SyncRegion> {
+    let local_matches = matching_blocks(base, local);
+    let other_matches = matching_blocks(base, other);
+    let mut regions = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < local_matches.len() && j < other_matches.len() {
+        let l = local_matches[i];
+        let o = other_matches[j];
+        let start = l.a_start.max(o.a_start);
+        let end = (l.a_start + l.length).min(o.a_start + o.length);
+        if start < end {
+            let local_start = l.b_start + (start - l.a_start);
+            let other_start = o.b_start + (start - o.a_start);
+            regions.push((
+                start,
+                end,
+                local_start,
+                local_start + (end - start),
+                other_start,
+                other_start + (end - start),
+            ));
+        }
+        if l.a_start + l.length < o.a_start + o.length {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    regions.push((
+        base.len(),
+        base.len(),
+        local.len(),
+        local.len(),
+        other.len(),
+        other.len(),
+    ));
+    regions
+}
+
+fn merge_regions(base: &[&[u8]], local: &[&[u8]], other: &[&[u8]]) -> Vec<Region> {
+    let mut regions = Vec::new();
+    let (mut base_pos, mut local_pos, mut other_pos) = (0, 0, 0);
+    for (base_start, base_end, local_start, local_end, other_start, other_end) in
+        sync_regions(base, local, other)
+    {
+        if local_start > local_pos || other_start > other_pos {
+            let base_lines = &base[base_pos..base_start];
+            let local_lines = &local[local_pos..local_start];
+            let other_lines = &other[other_pos..other_start];
+            let local_unchanged = local_lines == base_lines;
+            let other_unchanged = other_lines == base_lines;
+            regions.push(if local_lines == other_lines {
+                Region::Same(local_pos, local_start)
+            } else if local_unchanged {
+                Region::Other(other_pos, other_start)
+            } else if other_unchanged {
+                Region::Local(local_pos, local_start)
+            } else {
+                Region::Conflict {
+                    base: (base_pos, base_start),
+                    local: (local_pos, local_start),
+                    other: (other_pos, other_start),
+                }
+            });
+        }
+        if base_end > base_start {
+            regions.push(Region::Unchanged(base_start, base_end));
+        }
+        base_pos = base_end;
+        local_pos = local_end;
+        other_pos = other_end;
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_merge() {
+        let result = merge(b"a\nb\nc\n", b"A\nb\nc\n", b"a\nb\nC\n", Style::Merge);
+        assert_eq!(
+            result,
+            MergeResult {
+                text: b"A\nb\nC\n".to_vec(),
+                conflicts: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_conflict() {
+        let result = merge(b"a\nb\n", b"x\nb\n", b"y\nb\n", Style::Merge3);
+        assert!(result.conflicts);
+        assert_eq!(
+            String::from_utf8(result.text).unwrap(),
+            "<<<<<<< local\nx\n||||||| base\na\n=======\ny\n>>>>>>> other\nb\n"
+        );
+
+        let result = merge(b"a\nb\n", b"x\nb\n", b"y\nb\n", Style::Union);
+        assert!(!result.conflicts);
+        assert_eq!(result.text, b"x\ny\nb\n".to_vec());
+    }
+}
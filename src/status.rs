@@ -0,0 +1,127 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::dirstate::{lexists, Entry, Status};
+use crate::fsmonitor;
+use crate::manifest::{Flag, Manifest};
+use crate::repository::Repository;
+use crate::worker;
+
+/// The status of each file that differs between two revisions, and of the
+/// files they share if `clean` is set.
+pub fn revision_status(
+    repo: &Repository,
+    old_rev: u32,
+    new_rev: u32,
+    clean: bool,
+) -> Result<Vec<(&'static str, PathBuf)>> {
+    let new_changeset = repo.changeset(new_rev)?;
+    let changes = repo.manifest_changes(
+        &repo.changeset(old_rev)?.manifest_nodeid,
+        &new_changeset.manifest_nodeid,
+    )?;
+    let mut statuses = Vec::new();
+    for (path, nodeids) in &changes {
+        let status = match nodeids {
+            (None, _) => "A",
+            (_, None) => "R",
+            _ => "M",
+        };
+        statuses.push((status, path.clone()));
+    }
+    if clean {
+        for path in repo.manifest(&new_changeset)?.entries.into_keys() {
+            if !changes.contains_key(&path) {
+                statuses.push(("C", path));
+            }
+        }
+    }
+    Ok(statuses)
+}
+
+/// The status of each file in the working copy against revision `base_rev`,
+/// which is usually the working copy's first parent.
+pub fn working_status(repo: &Repository, base_rev: u32) -> Result<Vec<(&'static str, PathBuf)>> {
+    let dirstate = repo.dirstate()?;
+    let parent_rev = repo.changelog_rev(&dirstate.parent1_hash)?;
+    let parent = repo.manifest(&repo.changeset(parent_rev)?)?;
+    let other_base = if base_rev == parent_rev {
+        None
+    } else {
+        Some(repo.manifest(&repo.changeset(base_rev)?)?)
+    };
+    let base = other_base.as_ref().unwrap_or(&parent);
+    let entries = dirstate.entries();
+
+    // Only files the fsmonitor reports may have changed need to be examined.
+    let monitor = match other_base {
+        None => fsmonitor::query(repo, &dirstate)?,
+        Some(_) => None,
+    };
+    let candidates = monitor.as_ref().and_then(|monitor| monitor.candidates());
+
+    let mut statuses = Vec::new();
+    let walk = match candidates {
+        Some(candidates) => repo.walk_paths(candidates),
+        None => repo.walk_cached(&dirstate)?,
+    };
+    for (status, paths) in [("?", &walk.files), ("I", &walk.ignored)] {
+        for path in paths {
+            if !entries.contains_key(path) {
+                statuses.push((status, path.clone()));
+            }
+        }
+    }
+    // Stat and compare tracked files on worker threads.
+    let tracked: Vec<(&PathBuf, &Entry)> = entries.iter().collect();
+    let tracked_statuses = worker::map(repo.workers()?, &tracked, |(path, entry)| {
+        let status = match base.entries.get(*path) {
+            None if entry.status == Status::Removed => return Ok(None),
+            Some(_) if entry.status == Status::Removed => "R",
+            Some(_)
+                if entry.status == Status::Normal
+                    && candidates.is_some_and(|candidates| !candidates.contains(*path)) =>
+            {
+                "C"
+            }
+            _ if !lexists(repo.file_path(path)) => "!",
+            None => "A",
+            Some(_) if entry.status == Status::Merged && other_base.is_none() => "M",
+            Some(nodeid) if unchanged(repo, path, entry, &parent, (nodeid, base.flag(path)))? => {
+                "C"
+            }
+            Some(_) => "M",
+        };
+        Ok(Some((status, (*path).clone())))
+    })?;
+    statuses.extend(tracked_statuses.into_iter().flatten());
+    for path in base.entries.keys() {
+        if !entries.contains_key(path) {
+            statuses.push(("R", path.clone()));
+        }
+    }
+    if let Some(monitor) = monitor {
+        let notable = statuses.iter().filter(|(status, _path)| *status != "C");
+        monitor.save(repo, notable.map(|(_status, path)| path))?;
+    }
+    Ok(statuses)
+}
+
+// Whether a tracked file in the working copy has the given nodeid's contents
+// and flag. A file whose dirstate metadata still matches it is the parent's
+// revision, so only the nodeids are compared; others are read.
+fn unchanged(
+    repo: &Repository,
+    path: &Path,
+    entry: &Entry,
+    parent: &Manifest,
+    (nodeid, flag): (&[u8; 20], Option<Flag>),
+) -> Result<bool> {
+    let file_path = repo.file_path(path);
+    if entry.status == Status::Normal && entry.matches_file(&file_path) {
+        return Ok(parent.entries.get(path) == Some(nodeid) && parent.flag(path) == flag);
+    }
+    Ok(Entry::from_file(&file_path, Status::Normal)?.flag() == flag
+        && repo.working_data(path)? == repo.file_data(path, nodeid)?)
+}
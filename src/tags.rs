@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+
+/// The tracked file holding global tags.
+pub const TAGS_FILE: &str = ".hgtags";
+
+/// Tag names mapped to changeset nodeids. Global tags come from `.hgtags` on
+/// the repository heads, local tags from `.hg-rs/localtags`, and the `tip`
+/// tag always points at the newest changeset.
+#[derive(Debug, Default, PartialEq)]
+pub struct Tags {
+    pub global: BTreeMap<String, [u8; 20]>,
+    pub local: BTreeMap<String, [u8; 20]>,
+    pub tip: [u8; 20],
+}
+
+impl Tags {
+    /// Look up a tag. Local tags take precedence over global ones.
+    pub fn get(&self, name: &str) -> Option<&[u8; 20]> {
+        if name == "tip" {
+            return Some(&self.tip);
+        }
+        self.local.get(name).or_else(|| self.global.get(name))
+    }
+
+    /// Names of the tags that point at a changeset, `tip` first.
+    pub fn names_for(&self, nodeid: &[u8; 20]) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .global
+            .iter()
+            .chain(&self.local)
+            .filter(|(_name, tag)| *tag == nodeid)
+            .map(|(name, _tag)| name.as_str())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        if *nodeid == self.tip && self.tip != [0u8; 20] {
+            names.insert(0, "tip");
+        }
+        names
+    }
+}
+
+/// Apply lines of the form `<hex nodeid> <name>` to `tags`. Later lines win,
+/// and a null nodeid removes the tag.
+pub fn parse_into(text: &str, tags: &mut BTreeMap<String, [u8; 20]>) {
+    for line in text.lines() {
+        let (hash, name) = match line.split_once(' ') {
+            Some((hash, name)) => (hash, name.trim()),
+            None => continue,
+        };
+        let mut nodeid = [0u8; 20];
+        if name.is_empty() || hex::decode_to_slice(hash, &mut nodeid).is_err() {
+            continue;
+        }
+        if nodeid == [0u8; 20] {
+            tags.remove(name);
+        } else {
+            tags.insert(name.to_string(), nodeid);
+        }
+    }
+}
+
+pub fn format(tags: &BTreeMap<String, [u8; 20]>) -> String {
+    tags.iter()
+        .map(|(name, nodeid)| format!("{} {}\n", hex::encode(nodeid), name))
+        .collect()
+}
+
+pub fn read_file<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, [u8; 20]>> {
+    let mut tags = BTreeMap::new();
+    if path.as_ref().exists() {
+        parse_into(&fs::read_to_string(path)?, &mut tags);
+    }
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_into() {
+        let mut tags = BTreeMap::new();
+        let one = hex::encode([1u8; 20]);
+        let two = hex::encode([2u8; 20]);
+        let null = hex::encode([0u8; 20]);
+        parse_into(
+            &format!("{} v1\n{} v2\ngarbage\n{} v1\n{} v2\n", one, one, two, null),
+            &mut tags,
+        );
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags.get("v1"), Some(&[2u8; 20]));
+        assert_eq!(format(&tags), format!("{} v1\n", two));
+    }
+}
@@ -0,0 +1,519 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, FixedOffset};
+
+use crate::config::Config;
+
+/// A value a template keyword or expression can produce.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Date(DateTime<FixedOffset>),
+    /// A list of values. Each item is available to a `%` subtemplate under
+    /// the keyword `name`, as in `{files % "{file}\n"}`.
+    List {
+        name: String,
+        items: Vec<Value>,
+    },
+}
+
+impl Value {
+    pub fn text<S: Into<String>>(text: S) -> Value {
+        Value::Text(text.into())
+    }
+
+    pub fn list<S: Into<String>>(name: &str, items: impl IntoIterator<Item = S>) -> Value {
+        Value::List {
+            name: name.to_string(),
+            items: items
+                .into_iter()
+                .map(|item| Value::Text(item.into()))
+                .collect(),
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(text) => !text.is_empty(),
+            Value::Date(_) => true,
+            Value::List { items, .. } => !items.is_empty(),
+        }
+    }
+
+    fn render(&self) -> String {
+        match self {
+            Value::Text(text) => text.clone(),
+            Value::Date(time) => time.to_string(),
+            Value::List { items, .. } => items
+                .iter()
+                .map(Value::render)
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+}
+
+/// The keywords available while rendering one item, such as a changeset.
+pub type Keywords = BTreeMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Keyword(String),
+    /// A quoted string, which is itself a template.
+    Template(Vec<Segment>),
+    Integer(i64),
+    Filter(Box<Expr>, String),
+    Func(String, Vec<Expr>),
+    /// `list % template`, rendering the template once per list item.
+    Map(Box<Expr>, Box<Expr>),
+}
+
+/// A parsed output template, like `{rev}:{node|short} {desc|firstline}\n`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Template> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut pos = 0;
+        let segments = parse_segments(&chars, &mut pos, None)?;
+        Ok(Template { segments })
+    }
+
+    /// Resolve a `-T` argument: the name of a style from the `[templates]`
+    /// config section, or else a literal template.
+    pub fn from_spec(spec: &str, config: &Config) -> Result<Template> {
+        match config.get("templates", spec) {
+            Some(style) => Template::parse(&unquote(style))
+                .with_context(|| format!("Failed to parse template style {}.", spec)),
+            None => Template::parse(spec),
+        }
+    }
+
+    pub fn render(&self, keywords: &Keywords) -> Result<String> {
+        render_segments(&self.segments, keywords)
+    }
+}
+
+// Strip the quotes around a configured template, processing escapes inside
+// them. Unquoted templates are used as they are.
+fn unquote(text: &str) -> String {
+    let text = text.trim();
+    let quoted = text.len() >= 2
+        && ['"', '\'']
+            .iter()
+            .any(|quote| text.starts_with(*quote) && text.ends_with(*quote));
+    if !quoted {
+        return text.to_string();
+    }
+    let mut unquoted = String::new();
+    let mut chars = text[1..text.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.push(chars.next().map_or('\\', escape)),
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+fn escape(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        c => c,
+    }
+}
+
+// Parse literal text and `{...}` expressions until the end of input, or
+// until the closing `quote` when parsing a quoted string.
+fn parse_segments(chars: &[char], pos: &mut usize, quote: Option<char>) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    loop {
+        let c = match chars.get(*pos) {
+            Some(c) => *c,
+            None if quote.is_some() => bail!("Unterminated string in template."),
+            None => break,
+        };
+        *pos += 1;
+        match c {
+            _ if Some(c) == quote => break,
+            '\\' => {
+                let escaped = chars.get(*pos).context("Template ends with a backslash.")?;
+                literal.push(escape(*escaped));
+                *pos += 1;
+            }
+            '{' => {
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                let expr = parse_expr(chars, pos)?;
+                skip_whitespace(chars, pos);
+                if chars.get(*pos) != Some(&'}') {
+                    bail!("Expected '}}' in template at position {}.", *pos);
+                }
+                *pos += 1;
+                segments.push(Segment::Expr(expr));
+            }
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+fn skip_whitespace(chars: &[char], pos: &mut usize) {
+    while chars.get(*pos).is_some_and(|c| c.is_whitespace()) {
+        *pos += 1;
+    }
+}
+
+fn parse_identifier(chars: &[char], pos: &mut usize) -> String {
+    let start = *pos;
+    while chars
+        .get(*pos)
+        .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+    {
+        *pos += 1;
+    }
+    chars[start..*pos].iter().collect()
+}
+
+// expr := term ('%' term)*
+// term := primary ('|' filter)*
+fn parse_expr(chars: &[char], pos: &mut usize) -> Result<Expr> {
+    let mut expr = parse_term(chars, pos)?;
+    loop {
+        skip_whitespace(chars, pos);
+        if chars.get(*pos) != Some(&'%') {
+            return Ok(expr);
+        }
+        *pos += 1;
+        expr = Expr::Map(Box::new(expr), Box::new(parse_term(chars, pos)?));
+    }
+}
+
+fn parse_term(chars: &[char], pos: &mut usize) -> Result<Expr> {
+    let mut expr = parse_primary(chars, pos)?;
+    loop {
+        skip_whitespace(chars, pos);
+        if chars.get(*pos) != Some(&'|') {
+            return Ok(expr);
+        }
+        *pos += 1;
+        skip_whitespace(chars, pos);
+        let filter = parse_identifier(chars, pos);
+        if filter.is_empty() {
+            bail!("Expected a filter name at position {}.", *pos);
+        }
+        expr = Expr::Filter(Box::new(expr), filter);
+    }
+}
+
+fn parse_primary(chars: &[char], pos: &mut usize) -> Result<Expr> {
+    skip_whitespace(chars, pos);
+    match chars.get(*pos) {
+        Some(quote) if *quote == '"' || *quote == '\'' => {
+            *pos += 1;
+            Ok(Expr::Template(parse_segments(chars, pos, Some(*quote))?))
+        }
+        Some('(') => {
+            *pos += 1;
+            let expr = parse_expr(chars, pos)?;
+            skip_whitespace(chars, pos);
+            if chars.get(*pos) != Some(&')') {
+                bail!("Expected ')' in template at position {}.", *pos);
+            }
+            *pos += 1;
+            Ok(expr)
+        }
+        Some(c) if c.is_ascii_digit() || *c == '-' => {
+            let start = *pos;
+            *pos += 1;
+            while chars.get(*pos).is_some_and(|c| c.is_ascii_digit()) {
+                *pos += 1;
+            }
+            let number: String = chars[start..*pos].iter().collect();
+            Ok(Expr::Integer(number.parse().with_context(|| {
+                format!("Invalid number {:?} in template.", number)
+            })?))
+        }
+        Some(c) if c.is_alphabetic() || *c == '_' => {
+            let name = parse_identifier(chars, pos);
+            if chars.get(*pos) != Some(&'(') {
+                return Ok(Expr::Keyword(name));
+            }
+            *pos += 1;
+            let mut args = Vec::new();
+            skip_whitespace(chars, pos);
+            if chars.get(*pos) == Some(&')') {
+                *pos += 1;
+                return Ok(Expr::Func(name, args));
+            }
+            loop {
+                args.push(parse_expr(chars, pos)?);
+                skip_whitespace(chars, pos);
+                match chars.get(*pos) {
+                    Some(',') => *pos += 1,
+                    Some(')') => {
+                        *pos += 1;
+                        return Ok(Expr::Func(name, args));
+                    }
+                    _ => bail!("Expected ',' or ')' in template at position {}.", *pos),
+                }
+            }
+        }
+        c => bail!("Unexpected {:?} in template at position {}.", c, *pos),
+    }
+}
+
+fn render_segments(segments: &[Segment], keywords: &Keywords) -> Result<String> {
+    let mut out = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Literal(text) => out.push_str(text),
+            Segment::Expr(expr) => out.push_str(&eval(expr, keywords)?.render()),
+        }
+    }
+    Ok(out)
+}
+
+fn eval(expr: &Expr, keywords: &Keywords) -> Result<Value> {
+    match expr {
+        Expr::Keyword(name) => keywords
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unknown template keyword {}.", name)),
+        Expr::Template(segments) => Ok(Value::Text(render_segments(segments, keywords)?)),
+        Expr::Integer(number) => Ok(Value::Text(number.to_string())),
+        Expr::Filter(expr, filter) => apply_filter(eval(expr, keywords)?, filter),
+        Expr::Func(name, args) => call(name, args, keywords),
+        Expr::Map(list, template) => {
+            let (name, items) = match eval(list, keywords)? {
+                Value::List { name, items } => (name, items),
+                value => bail!("Cannot apply '%' to {:?}.", value.render()),
+            };
+            let mut out = String::new();
+            for item in items {
+                let mut item_keywords = keywords.clone();
+                item_keywords.insert(name.clone(), item);
+                out.push_str(&eval(template, &item_keywords)?.render());
+            }
+            Ok(Value::Text(out))
+        }
+    }
+}
+
+fn apply_filter(value: Value, filter: &str) -> Result<Value> {
+    let date = |value: &Value| match value {
+        Value::Date(time) => Ok(*time),
+        _ => Err(anyhow!("Filter {} expects a date.", filter)),
+    };
+    let text = value.render();
+    Ok(Value::Text(match filter {
+        "short" => text.chars().take(12).collect(),
+        "firstline" => text.lines().next().unwrap_or_default().to_string(),
+        "lower" => text.to_lowercase(),
+        "upper" => text.to_uppercase(),
+        "strip" => text.trim().to_string(),
+        "stringify" => text,
+        "basename" => text.rsplit('/').next().unwrap_or_default().to_string(),
+        "email" => match (text.find('<'), text.rfind('>')) {
+            (Some(start), Some(end)) if start < end => text[start + 1..end].to_string(),
+            _ => text,
+        },
+        "person" => match text.find('<') {
+            Some(start) if start > 0 => text[..start].trim().trim_matches('"').to_string(),
+            _ => text.split('@').next().unwrap_or_default().to_string(),
+        },
+        "user" => {
+            let email = apply_filter(Value::Text(text), "email")?.render();
+            email.split('@').next().unwrap_or_default().to_string()
+        }
+        "count" => match &value {
+            Value::List { items, .. } => items.len().to_string(),
+            _ => text.chars().count().to_string(),
+        },
+        "isodate" => date(&value)?.format("%Y-%m-%d %H:%M %z").to_string(),
+        "isodatesec" => date(&value)?.format("%Y-%m-%d %H:%M:%S %z").to_string(),
+        "shortdate" => date(&value)?.format("%Y-%m-%d").to_string(),
+        "rfc822date" => date(&value)?.format("%a, %d %b %Y %H:%M:%S %z").to_string(),
+        "date" => date(&value)?.format("%a %b %d %H:%M:%S %Y %z").to_string(),
+        _ => bail!("Unknown template filter {}.", filter),
+    }))
+}
+
+fn call(name: &str, args: &[Expr], keywords: &Keywords) -> Result<Value> {
+    let arity = |min: usize, max: usize| -> Result<()> {
+        if args.len() < min || args.len() > max {
+            bail!("{}() takes {} to {} arguments.", name, min, max);
+        }
+        Ok(())
+    };
+    let arg = |index: usize| eval(&args[index], keywords);
+    let optional = |index: usize| -> Result<Value> {
+        match args.get(index) {
+            Some(expr) => eval(expr, keywords),
+            None => Ok(Value::Text(String::new())),
+        }
+    };
+    match name {
+        "if" => {
+            arity(2, 3)?;
+            if arg(0)?.is_truthy() {
+                arg(1)
+            } else {
+                optional(2)
+            }
+        }
+        "ifeq" => {
+            arity(3, 4)?;
+            if arg(0)?.render() == arg(1)?.render() {
+                arg(2)
+            } else {
+                optional(3)
+            }
+        }
+        "join" => {
+            arity(1, 2)?;
+            let separator = optional(1)?.render();
+            let separator = if args.len() == 1 {
+                " ".to_string()
+            } else {
+                separator
+            };
+            match arg(0)? {
+                Value::List { items, .. } => Ok(Value::Text(
+                    items
+                        .iter()
+                        .map(Value::render)
+                        .collect::<Vec<_>>()
+                        .join(&separator),
+                )),
+                value => Ok(value),
+            }
+        }
+        // Output is never colored, so labels only pass their text through.
+        "label" => {
+            arity(2, 2)?;
+            arg(1)
+        }
+        "pad" => {
+            arity(2, 4)?;
+            let text = arg(0)?.render();
+            let width: usize = arg(1)?.render().parse().context("pad() expects a width.")?;
+            let fill = optional(2)?.render().chars().next().unwrap_or(' ');
+            let left = optional(3)?.is_truthy();
+            let padding: String =
+                std::iter::repeat_n(fill, width.saturating_sub(text.chars().count())).collect();
+            Ok(Value::Text(if left {
+                padding + &text
+            } else {
+                text + &padding
+            }))
+        }
+        "date" => {
+            arity(1, 2)?;
+            match arg(0)? {
+                Value::Date(time) if args.len() == 2 => {
+                    Ok(Value::Text(time.format(&arg(1)?.render()).to_string()))
+                }
+                value => Ok(value),
+            }
+        }
+        _ => bail!("Unknown template function {}.", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn keywords() -> Keywords {
+        let mut keywords = Keywords::new();
+        keywords.insert("rev".to_string(), Value::text("3"));
+        keywords.insert("node".to_string(), Value::text("0123456789abcdef0123"));
+        keywords.insert(
+            "author".to_string(),
+            Value::text("Ming Li <ming@example.com>"),
+        );
+        keywords.insert("desc".to_string(), Value::text("Fix it\n\nDetails."));
+        keywords.insert("date".to_string(), Value::Date(Utc.timestamp(0, 0).into()));
+        keywords.insert("files".to_string(), Value::list("file", vec!["a", "b/c"]));
+        keywords.insert("tags".to_string(), Value::list::<String>("tag", vec![]));
+        keywords
+    }
+
+    fn render(template: &str) -> Result<String> {
+        Template::parse(template)?.render(&keywords())
+    }
+
+    #[test]
+    fn test_render() -> Result<()> {
+        assert_eq!(
+            render("{rev}:{node|short} {author|email} {desc|firstline}\\n")?,
+            "3:0123456789ab ming@example.com Fix it\n"
+        );
+        assert_eq!(
+            render("{date|isodate} {author|person} {author|user}")?,
+            "1970-01-01 00:00 +0000 Ming Li ming"
+        );
+        assert_eq!(
+            render("{files} {join(files, ', ')} {files|count}")?,
+            "a b/c a, b/c 2"
+        );
+        assert_eq!(render("{files % '[{file|basename}]'}")?, "[a][c]");
+        assert_eq!(render("{if(tags, 'tagged', 'plain {rev}')}")?, "plain 3");
+        assert_eq!(
+            render("{ifeq(rev, 3, 'three')}{label('log.rev', rev)}")?,
+            "three3"
+        );
+        assert_eq!(
+            render("{pad(rev, 3, '0', 'left')}|{pad(rev, 3)}|")?,
+            "003|3  |"
+        );
+        assert!(render("{nope}").is_err());
+        assert!(render("{rev|nope}").is_err());
+        assert!(render("{rev").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_spec() -> Result<()> {
+        let config = Config::parse(
+            r#"
+[templates]
+short = "{rev} {join(files, \",\")}\n"
+raw = {rev}\t
+"#,
+        )?;
+        assert_eq!(
+            Template::from_spec("short", &config)?.render(&keywords())?,
+            "3 a,b/c\n"
+        );
+        assert_eq!(
+            Template::from_spec("raw", &config)?.render(&keywords())?,
+            "3\t"
+        );
+        assert_eq!(
+            Template::from_spec("{rev}!", &config)?.render(&keywords())?,
+            "3!"
+        );
+        Ok(())
+    }
+}
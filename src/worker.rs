@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::Result;
+
+// The most items handed to a worker at a time. Each worker gets several
+// batches, so that workers stay busy when some items, like large files or
+// directories, take much longer than others.
+const MAX_BATCH_SIZE: usize = 64;
+
+/// Apply `f` to every item on up to `workers` threads, returning the results
+/// in the order of the items. The first error, in that order, is returned.
+pub fn map<T, R, F>(workers: usize, items: &[T], f: F) -> Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R> + Sync,
+{
+    if workers <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let batch_size = (items.len() / (workers * 4)).clamp(1, MAX_BATCH_SIZE);
+    let batches = items.len().div_ceil(batch_size);
+    let workers = workers.min(batches);
+    let next_batch = AtomicUsize::new(0);
+    let done = Mutex::new(Vec::with_capacity(batches));
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let batch = next_batch.fetch_add(1, Ordering::Relaxed);
+                if batch >= batches {
+                    break;
+                }
+                let start = batch * batch_size;
+                let end = items.len().min(start + batch_size);
+                let results: Vec<Result<R>> = items[start..end].iter().map(&f).collect();
+                done.lock().unwrap().push((batch, results));
+            });
+        }
+    });
+    let mut done = done.into_inner().unwrap();
+    done.sort_unstable_by_key(|(batch, _results)| *batch);
+    done.into_iter()
+        .flat_map(|(_batch, results)| results)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+
+    #[test]
+    fn test_map() -> Result<()> {
+        let items: Vec<usize> = (0..1000).collect();
+        let doubled = map(8, &items, |item| Ok(item * 2))?;
+        assert_eq!(
+            doubled,
+            items.iter().map(|item| item * 2).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            map(8, &[] as &[usize], |item| Ok(*item))?,
+            Vec::<usize>::new()
+        );
+        let error = map(8, &items, |item| match item {
+            300 | 700 => bail!("item {}", item),
+            _ => Ok(()),
+        });
+        assert_eq!(error.unwrap_err().to_string(), "item 300");
+        Ok(())
+    }
+}